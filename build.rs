@@ -1,5 +1,6 @@
-  
 extern crate embed_resource;
 fn main() {
-    embed_resource::compile("embed_resources.rc");
-}
\ No newline at end of file
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows") {
+        embed_resource::compile("embed_resources.rc");
+    }
+}
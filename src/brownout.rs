@@ -0,0 +1,419 @@
+use anyhow::{bail, Error, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::probe::Endpoint;
+use crate::Sample;
+
+const SECOND: u128 = 1_000_000_000;
+/// How slowly each target's usual latency follows changes: the time constant of its EWMA, in
+/// seconds. Long enough that an evening of congestion stands out against the afternoon.
+const BASELINE_SECONDS: f64 = 30.0 * 60.0;
+/// The time constant of the current latency and loss, in seconds.
+const CURRENT_SECONDS: f64 = 60.0;
+/// A target's baseline is only trusted once it has been probed for this long, and a target that
+/// has not been probed for this long, e.g. one no longer configured, is left out.
+const WARM_UP: u128 = 5 * 60 * SECOND;
+/// A brownout starts when latency reaches this multiple of the usual, or loss this share...
+const ENTER_RATIO: f64 = 2.0;
+const ENTER_LOSS: f64 = 0.03;
+/// ...and ends when both are back under these, which are lower so it does not flap.
+const LEAVE_RATIO: f64 = 1.5;
+const LEAVE_LOSS: f64 = 0.01;
+/// Either way, the change has to last this long.
+const HOLD: u128 = 60 * SECOND;
+
+/// How bad a brownout got at its worst.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Severity {
+    /// Up to 3 times the usual latency and 5% loss.
+    Mild,
+    /// Up to 4 times the usual latency and 10% loss.
+    Moderate,
+    Severe,
+}
+
+impl Severity {
+    fn of(ratio: f64, loss: f64) -> Severity {
+        if ratio >= 4.0 || loss >= 0.10 {
+            Severity::Severe
+        } else if ratio >= 3.0 || loss >= 0.05 {
+            Severity::Moderate
+        } else {
+            Severity::Mild
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Mild => "mild",
+            Severity::Moderate => "moderate",
+            Severity::Severe => "severe",
+        })
+    }
+}
+
+impl FromStr for Severity {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Ok(match text {
+            "mild" => Severity::Mild,
+            "moderate" => Severity::Moderate,
+            "severe" => Severity::Severe,
+            _ => bail!("'{}' is not a severity", text),
+        })
+    }
+}
+
+/// The worst of a brownout.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Peak {
+    /// The highest latency, as a multiple of the usual.
+    pub latency_ratio: f32,
+    /// The highest loss, from 0 to 1.
+    pub loss: f32,
+}
+
+impl Peak {
+    pub fn severity(&self) -> Severity {
+        Severity::of(self.latency_ratio as f64, self.loss as f64)
+    }
+
+    /// Reads the two fields `Display` writes, e.g. `latency 2.9x` and `loss 5.8%`.
+    pub fn parse(latency: &str, loss: &str) -> Result<Peak> {
+        let number = |text: Option<&str>| text.and_then(|number| number.parse::<f32>().ok());
+        let ratio = latency
+            .strip_prefix("latency ")
+            .and_then(|ratio| ratio.strip_suffix('x'));
+        let percent = loss
+            .strip_prefix("loss ")
+            .and_then(|percent| percent.strip_suffix('%'));
+        match (number(ratio), number(percent)) {
+            (Some(latency_ratio), Some(percent)) => Ok(Peak {
+                latency_ratio,
+                loss: percent / 100.0,
+            }),
+            _ => bail!(
+                "'{}, {}' is not a brownout's latency and loss",
+                latency,
+                loss
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Peak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency {:.1}x, loss {:.1}%",
+            self.latency_ratio,
+            self.loss * 100.0
+        )
+    }
+}
+
+/// A stretch of sustained high latency or loss that fell short of an outage. Times are sample
+/// timestamps in nanoseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Brownout {
+    pub start: u128,
+    pub end: u128,
+    pub peak: Peak,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Latency or loss has been high for `HOLD`.
+    Started { start: u128 },
+    /// They have been back to normal for `HOLD`.
+    Ended(Brownout),
+}
+
+/// Exponentially weighted averages for one target, in microseconds and as a share of probes.
+struct TargetState {
+    first: u128,
+    last: u128,
+    baseline: f64,
+    latency: f64,
+    loss: f64,
+}
+
+impl TargetState {
+    /// The weight a new sample gets in an average with a time constant of `seconds`, so that
+    /// targets probed at different intervals age at the same rate.
+    fn weight(&self, timestamp: u128, seconds: f64) -> f64 {
+        let elapsed = timestamp.saturating_sub(self.last) as f64 / SECOND as f64;
+        1.0 - (-elapsed / seconds).exp()
+    }
+}
+
+/// A brownout from when it started, which only counts once it has lasted `HOLD`.
+struct Episode {
+    start: u128,
+    confirmed: bool,
+    ratio: f64,
+    loss: f64,
+    /// When latency and loss went back under the thresholds to end it.
+    clearing: Option<u128>,
+}
+
+/// Finds brownouts among the samples of one IP version: the latency of its targets, against
+/// what is usual for each of them, or their loss, staying high without the connection going
+/// down. Each target's latency and loss are smoothed on their own and the median is taken
+/// across them, so one slow or rate-limiting provider does not count.
+///
+/// Samples taken during an outage should be left out, and the brownout under way ended with
+/// `stopped` when one starts. Gateway and interception samples should be left out too.
+#[derive(Default)]
+pub struct Detector {
+    targets: HashMap<Endpoint, TargetState>,
+    episode: Option<Episode>,
+}
+
+impl Detector {
+    pub fn observe(&mut self, sample: Sample) -> Vec<Event> {
+        let (endpoint, timestamp, result) = sample;
+        // the usual latency must not take in what the brownout is measured against
+        let steady = self.episode.is_none();
+        let rtt = result.ok().map(f64::from);
+        let lost = if result.is_err() { 1.0 } else { 0.0 };
+        let state = self.targets.entry(endpoint).or_insert(TargetState {
+            first: timestamp,
+            last: timestamp,
+            baseline: rtt.unwrap_or(0.0),
+            latency: rtt.unwrap_or(0.0),
+            loss: lost,
+        });
+        let current = state.weight(timestamp, CURRENT_SECONDS);
+        state.loss += (lost - state.loss) * current;
+        if let Some(rtt) = rtt {
+            if state.baseline == 0.0 {
+                state.baseline = rtt;
+                state.latency = rtt;
+            }
+            state.latency += (rtt - state.latency) * current;
+            if steady {
+                state.baseline +=
+                    (rtt - state.baseline) * state.weight(timestamp, BASELINE_SECONDS);
+            }
+        }
+        state.last = state.last.max(timestamp);
+        self.assess(timestamp)
+    }
+
+    /// The median latency ratio and loss across the current targets whose baselines are
+    /// trusted.
+    fn levels(&self, now: u128) -> Option<(f64, f64)> {
+        let ready: Vec<_> = self
+            .targets
+            .values()
+            .filter(|state| state.first + WARM_UP <= now && now <= state.last + WARM_UP)
+            .filter(|state| state.baseline > 0.0)
+            .collect();
+        let ratios = ready.iter().map(|state| state.latency / state.baseline);
+        let losses = ready.iter().map(|state| state.loss);
+        Some((median(ratios)?, median(losses)?))
+    }
+
+    fn assess(&mut self, now: u128) -> Vec<Event> {
+        let Some((ratio, loss)) = self.levels(now) else {
+            return Vec::new();
+        };
+        let high = ratio >= ENTER_RATIO || loss >= ENTER_LOSS;
+        let normal = ratio < LEAVE_RATIO && loss < LEAVE_LOSS;
+        let mut ended = None;
+        let mut events = Vec::new();
+        match self.episode.as_mut() {
+            None if high => {
+                self.episode = Some(Episode {
+                    start: now,
+                    confirmed: false,
+                    ratio,
+                    loss,
+                    clearing: None,
+                })
+            }
+            None => {}
+            Some(episode) if !episode.confirmed && !high => self.episode = None,
+            Some(episode) => {
+                episode.ratio = episode.ratio.max(ratio);
+                episode.loss = episode.loss.max(loss);
+                if !episode.confirmed {
+                    if now - episode.start >= HOLD {
+                        episode.confirmed = true;
+                        events.push(Event::Started {
+                            start: episode.start,
+                        });
+                    }
+                } else if !normal {
+                    episode.clearing = None;
+                } else if now - *episode.clearing.get_or_insert(now) >= HOLD {
+                    ended = episode.clearing;
+                }
+            }
+        }
+        if let Some(end) = ended {
+            events.extend(self.stopped(end));
+        }
+        events
+    }
+
+    /// Ends the brownout under way at `at`, because the connection went down or contrac is
+    /// stopping. One that had not lasted long enough to count is forgotten.
+    pub fn stopped(&mut self, at: u128) -> Option<Event> {
+        let episode = self.episode.take()?;
+        episode.confirmed.then(|| {
+            Event::Ended(Brownout {
+                start: episode.start,
+                end: at.max(episode.start),
+                peak: Peak {
+                    latency_ratio: episode.ratio as f32,
+                    loss: episode.loss as f32,
+                },
+            })
+        })
+    }
+
+    /// When the brownout under way started, once it counts.
+    pub fn start(&self) -> Option<u128> {
+        self.episode
+            .as_ref()
+            .filter(|episode| episode.confirmed)
+            .map(|episode| episode.start)
+    }
+}
+
+fn median(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let mut values: Vec<_> = values.collect();
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    match values.len() {
+        0 => None,
+        count if count % 2 == 0 => Some((values[middle - 1] + values[middle]) / 2.0),
+        _ => Some(values[middle]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Failure;
+    use std::net::IpAddr;
+
+    fn endpoint(last: u8) -> Endpoint {
+        IpAddr::from([10, 0, 0, last]).into()
+    }
+
+    /// Probes three targets once a second from `from` to `to` seconds, with `rtt` giving each
+    /// target's round trip time in microseconds, or `None` for a lost probe.
+    fn probe(
+        detector: &mut Detector,
+        from: u128,
+        to: u128,
+        rtt: impl Fn(u8, u128) -> Option<u32>,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        for second in from..to {
+            for target in 1..=3 {
+                let result = rtt(target, second).ok_or(Failure::Timeout);
+                events.extend(detector.observe((endpoint(target), second * SECOND, result)));
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn congestion_is_a_brownout_with_a_severity() {
+        let mut detector = Detector::default();
+        // the targets are usually 10, 20 and 30 ms away
+        let usual = |target: u8, _second| Some(target as u32 * 10_000);
+        assert_eq!(probe(&mut detector, 0, 3600, usual), vec![]);
+        // then latency triples for half an hour, with a probe in 20 lost
+        let congested = |target: u8, second: u128| match second % 20 {
+            0 => None,
+            _ => Some(target as u32 * 30_000),
+        };
+        let events = probe(&mut detector, 3600, 5400, congested);
+        assert_eq!(events.len(), 1);
+        let Event::Started { start } = events[0] else {
+            panic!("not a start: {:?}", events[0]);
+        };
+        // it counts from when latency first crossed the threshold, within the smoothing
+        assert!(
+            (3600..3660).contains(&(start / SECOND)),
+            "{}",
+            start / SECOND
+        );
+        assert_eq!(detector.start(), Some(start));
+        let events = probe(&mut detector, 5400, 6000, usual);
+        let [Event::Ended(brownout)] = events[..] else {
+            panic!("not one end: {:?}", events);
+        };
+        assert_eq!(brownout.start, start);
+        assert!((5400..5520).contains(&(brownout.end / SECOND)));
+        assert!((2.7..3.01).contains(&brownout.peak.latency_ratio));
+        assert!((0.03..0.08).contains(&brownout.peak.loss));
+        assert_eq!(brownout.peak.severity(), Severity::Moderate);
+        assert_eq!(detector.start(), None);
+    }
+
+    #[test]
+    fn short_spikes_and_one_bad_target_are_not_brownouts() {
+        let mut detector = Detector::default();
+        let usual = |_target, _second| Some(10_000);
+        probe(&mut detector, 0, 600, usual);
+        // ten seconds of very high latency
+        let spike = |_target, second: u128| Some(if second < 610 { 100_000 } else { 10_000 });
+        assert_eq!(probe(&mut detector, 600, 900, spike), vec![]);
+        // one target losing everything and the others fine
+        let one = |target, _second| (target != 1).then_some(10_000);
+        assert_eq!(probe(&mut detector, 900, 2000, one), vec![]);
+    }
+
+    #[test]
+    fn targets_no_longer_probed_drop_out() {
+        let mut detector = Detector::default();
+        probe(&mut detector, 0, 600, |_target, _second| Some(10_000));
+        // the third target is taken out of the configuration, and the first loses every other
+        // probe, which the third outvotes until it has been gone for `WARM_UP`
+        let mut events = Vec::new();
+        for second in 600..1200u128 {
+            let lossy = (second % 2 != 0).then_some(10_000);
+            let lossy = (endpoint(1), second * SECOND, lossy.ok_or(Failure::Timeout));
+            events.extend(detector.observe(lossy));
+            events.extend(detector.observe((endpoint(2), second * SECOND, Ok(10_000))));
+        }
+        let [Event::Started { start }] = events[..] else {
+            panic!("not one start: {:?}", events);
+        };
+        assert_eq!(start, 900 * SECOND);
+    }
+
+    #[test]
+    fn stopping_ends_only_a_brownout_that_counts() {
+        let mut detector = Detector::default();
+        probe(&mut detector, 0, 600, |_target, _second| Some(10_000));
+        probe(&mut detector, 600, 630, |_target, _second| Some(50_000));
+        assert_eq!(detector.stopped(630 * SECOND), None);
+        probe(&mut detector, 630, 800, |_target, _second| Some(50_000));
+        let Some(Event::Ended(brownout)) = detector.stopped(800 * SECOND) else {
+            panic!("no brownout");
+        };
+        assert_eq!(brownout.end, 800 * SECOND);
+        assert_eq!(brownout.peak.severity(), Severity::Severe);
+        assert_eq!("moderate".parse::<Severity>().unwrap(), Severity::Moderate);
+        let peak = Peak {
+            latency_ratio: 2.5,
+            loss: 0.042,
+        };
+        assert_eq!(peak.to_string(), "latency 2.5x, loss 4.2%");
+        let parsed = Peak::parse("latency 2.5x", "loss 4.2%").unwrap();
+        assert!((parsed.loss - 0.042).abs() < 1e-6);
+        assert!(Peak::parse("latency 2.5", "loss 4.2%").is_err());
+    }
+}
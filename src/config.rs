@@ -0,0 +1,630 @@
+use anyhow::{bail, Context, Error, Result};
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::outage::Quorum;
+use crate::probe::{self, Endpoint, Prober, RecordType};
+use crate::rolling::Window;
+use crate::uptime::Sla;
+
+pub const CONFIG_FILE_NAME: &str = "contrac.toml";
+/// Just over a second, so no provider is probed more than once per second.
+const DEFAULT_INTERVAL_MILLIS: u32 = 1010;
+const MIN_INTERVAL_MILLIS: u32 = 1000;
+/// Matches the Windows ICMP helper default.
+const DEFAULT_TIMEOUT_MILLIS: u32 = 2000;
+const MAX_TIMEOUT_MILLIS: u32 = 60_000;
+const DEFAULT_DNS_QUERY: &str = "www.example.com";
+/// Longest name and label a DNS query can carry.
+const MAX_DNS_NAME: usize = 253;
+const MAX_DNS_LABEL: usize = 63;
+/// Resolver address that stands for the resolvers the operating system is configured with.
+const SYSTEM_RESOLVER: &str = "system";
+/// Gateway settings to look the default gateway up, or to not probe a gateway at all.
+const GATEWAY_AUTO: &str = "auto";
+const GATEWAY_OFF: &str = "off";
+const GATEWAY_TARGET_NAME: &str = "Gateway";
+
+/// Used when there is no configuration file: CloudFlare, Google, Cisco OpenDNS and Quad9.
+const DEFAULT_TARGETS: &str = r#"
+[[target]]
+name = "Cloudflare"
+address = "1.1.1.2"
+
+[[target]]
+name = "Google"
+address = "8.8.8.8"
+
+[[target]]
+name = "Cisco OpenDNS"
+address = "208.67.222.222"
+
+[[target]]
+name = "Quad9"
+address = "9.9.9.9"
+"#;
+
+/// The same providers' IPv6 anycast addresses, added when the machine has an IPv6 route.
+const DEFAULT_IPV6_TARGETS: &str = r#"
+[[target]]
+name = "Cloudflare IPv6"
+address = "2606:4700:4700::1112"
+
+[[target]]
+name = "Google IPv6"
+address = "2001:4860:4860::8888"
+
+[[target]]
+name = "Cisco OpenDNS IPv6"
+address = "2620:119:35::35"
+
+[[target]]
+name = "Quad9 IPv6"
+address = "2620:fe::fe"
+"#;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    interval_ms: Option<u32>,
+    timeout_ms: Option<u32>,
+    gateway: Option<String>,
+    quorum: Option<usize>,
+    quorum_window_ms: Option<u32>,
+    database: Option<PathBuf>,
+    sla: Option<SlaEntry>,
+    status_window: Option<String>,
+    #[serde(default, rename = "target")]
+    targets: Vec<TargetEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetEntry {
+    name: String,
+    address: String,
+    #[serde(default)]
+    kind: ProbeKind,
+    interval_ms: Option<u32>,
+    timeout_ms: Option<u32>,
+    query: Option<String>,
+    record: Option<String>,
+}
+
+/// The `[sla]` table, with availabilities and credits in percent.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SlaEntry {
+    availability: f64,
+    #[serde(default)]
+    monthly_price: f64,
+    /// Pairs of an availability and the credit owed below it.
+    #[serde(default)]
+    credits: Vec<(f64, f64)>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    #[default]
+    Icmp,
+    Tcp,
+    Dns,
+    Http,
+}
+
+impl ProbeKind {
+    pub const ALL: [ProbeKind; 4] = [
+        ProbeKind::Icmp,
+        ProbeKind::Tcp,
+        ProbeKind::Dns,
+        ProbeKind::Http,
+    ];
+
+    /// The name the configuration file uses for the kind, which logs and exports use as well.
+    pub fn name(self) -> &'static str {
+        match self {
+            ProbeKind::Icmp => "icmp",
+            ProbeKind::Tcp => "tcp",
+            ProbeKind::Dns => "dns",
+            ProbeKind::Http => "http",
+        }
+    }
+}
+
+impl fmt::Display for ProbeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ProbeKind {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match ProbeKind::ALL.iter().find(|kind| kind.name() == text) {
+            Some(kind) => Ok(*kind),
+            None => bail!("'{}' is not a kind of probe", text),
+        }
+    }
+}
+
+/// The validated configuration file.
+#[derive(Debug)]
+pub struct Config {
+    /// The Internet targets, followed by the gateway if there is one.
+    pub targets: Vec<Target>,
+    /// Targets of one address family that must fail together; a majority of them if not set.
+    pub quorum: Option<usize>,
+    pub quorum_window: Duration,
+    /// The SQLite database samples and outages are stored in as well.
+    pub database: Option<PathBuf>,
+    /// The service level agreement the uptime report checks the line against.
+    pub sla: Option<Sla>,
+    /// How far back the latency in the status bar goes.
+    pub status_window: Window,
+}
+
+/// One validated entry from the target list.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub name: String,
+    pub kind: ProbeKind,
+    /// IP address or host name; `host:port` for TCP, a URL for HTTP.
+    pub address: String,
+    pub interval_millis: u32,
+    pub timeout: Duration,
+    /// Name looked up and record asked for by DNS targets.
+    pub query: String,
+    pub record: RecordType,
+    /// Set for the local gateway, whose samples tell where an outage was rather than if there was one.
+    pub gateway: bool,
+}
+
+impl Target {
+    /// Creates the prober for this target, resolving host names.
+    pub fn prober(&self) -> Result<Box<dyn Prober>> {
+        let prober = match self.kind {
+            ProbeKind::Icmp => probe::icmp(&self.address, self.timeout),
+            ProbeKind::Tcp => probe::tcp(&self.address, self.timeout),
+            ProbeKind::Dns => probe::dns(&self.address, &self.query, self.record, self.timeout),
+            ProbeKind::Http => probe::http(&self.address, self.timeout),
+        };
+        prober.context(format!("target '{}'", self.name))
+    }
+
+    /// The address probed, when the target gives one rather than a host name.
+    fn literal_address(&self) -> Option<IpAddr> {
+        let address = match self.kind {
+            ProbeKind::Http => {
+                let rest = self.address.split_once("://")?.1;
+                rest.split('/').next()?
+            }
+            _ => &self.address,
+        };
+        let bare = address.trim_start_matches('[').trim_end_matches(']');
+        bare.parse()
+            .ok()
+            .or_else(|| address.parse::<SocketAddr>().ok().map(|dst| dst.ip()))
+    }
+}
+
+/// Checks that no two targets send the same kind of probe to the same address, given each
+/// target's name and endpoint. Samples, and everything kept from them, go by endpoint, so two
+/// such targets would be taken for one. Different kinds of probe to one address are fine.
+pub fn distinct_endpoints<'a>(
+    targets: impl IntoIterator<Item = (&'a str, Endpoint)>,
+) -> Result<()> {
+    let mut seen = HashMap::new();
+    for (name, endpoint) in targets {
+        if let Some(first) = seen.insert(endpoint, name) {
+            bail!(
+                "targets '{}' and '{}' both send {} probes to {}, so they cannot be told apart",
+                first,
+                name,
+                endpoint.kind,
+                endpoint.address
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where the configuration file is looked for when none is given on the command line.
+pub fn default_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "Vivitap", "Contrac")
+        .map(|dirs| dirs.config_dir().join(CONFIG_FILE_NAME))
+}
+
+/// Loads and validates the target list from `path`, or from the default location. The built-in
+/// targets are used if no file was given and there is none at the default location.
+pub fn load(path: Option<&Path>) -> Result<Config> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None => return parse(&default_targets()),
+        },
+    };
+    let text = fs::read_to_string(&path).context(format!("unable to read '{}'", path.display()))?;
+    parse(&text).context(format!("invalid configuration in '{}'", path.display()))
+}
+
+/// The built-in targets, over IPv6 as well if this machine can reach the first of them that way.
+fn default_targets() -> String {
+    let cloudflare: IpAddr = "2606:4700:4700::1112".parse().unwrap();
+    if probe::has_route(cloudflare) {
+        return format!("{}{}", DEFAULT_TARGETS, DEFAULT_IPV6_TARGETS);
+    }
+    DEFAULT_TARGETS.to_string()
+}
+
+fn parse(text: &str) -> Result<Config> {
+    let file: ConfigFile = toml::from_str(text)?;
+    if file.targets.is_empty() {
+        bail!("no [[target]] entries");
+    }
+    let interval_millis = file.interval_ms.unwrap_or(DEFAULT_INTERVAL_MILLIS);
+    let timeout_millis = file.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MILLIS);
+    let mut names = HashSet::new();
+    let mut targets = Vec::new();
+    for entry in file.targets {
+        if !names.insert(entry.name.clone()) {
+            bail!("target name '{}' is used more than once", entry.name);
+        }
+        let name = entry.name.clone();
+        let expanded = validate(entry, interval_millis, timeout_millis)
+            .context(format!("target '{}'", name))?;
+        targets.extend(expanded);
+    }
+    // checked against all the targets here, and against each address family's once they are
+    // resolved
+    if let Some(count) = file.quorum {
+        if count == 0 || count > targets.len() {
+            bail!(
+                "quorum is {} but must be between 1 and the {} Internet targets",
+                count,
+                targets.len()
+            );
+        }
+    }
+    let window = match file.quorum_window_ms {
+        Some(millis) if millis < MIN_INTERVAL_MILLIS => bail!(
+            "quorum_window_ms is {} but must be at least {}",
+            millis,
+            MIN_INTERVAL_MILLIS
+        ),
+        Some(millis) => Duration::from_millis(millis as u64),
+        None => Quorum::default().window,
+    };
+    if file.database.is_some() && !cfg!(feature = "sqlite") {
+        bail!("database needs a build of contrac with the sqlite feature");
+    }
+    let sla = file.sla.map(sla).transpose().context("[sla]")?;
+    let status_window = match file.status_window {
+        Some(text) => text.parse().context("status_window")?,
+        None => Window::default(),
+    };
+    let gateway = match file.gateway.as_deref() {
+        None | Some(GATEWAY_AUTO) => probe::default_gateway().map(|ip| ip.to_string()),
+        Some(GATEWAY_OFF) => None,
+        Some(address) => Some(address.to_string()),
+    };
+    if let Some(address) = gateway {
+        targets.push(Target {
+            name: GATEWAY_TARGET_NAME.to_string(),
+            kind: ProbeKind::Icmp,
+            address,
+            interval_millis,
+            timeout: Duration::from_millis(timeout_millis as u64),
+            query: DEFAULT_DNS_QUERY.to_string(),
+            record: RecordType::A,
+            gateway: true,
+        });
+    }
+    // host names are checked once they are resolved
+    distinct_endpoints(targets.iter().filter_map(|target| {
+        let endpoint = Endpoint {
+            address: target.literal_address()?,
+            kind: target.kind,
+        };
+        Some((target.name.as_str(), endpoint))
+    }))?;
+    Ok(Config {
+        targets,
+        quorum: file.quorum,
+        quorum_window: window,
+        database: file.database,
+        sla,
+        status_window,
+    })
+}
+
+/// Checks the `[sla]` table, converting its percentages to fractions.
+fn sla(entry: SlaEntry) -> Result<Sla> {
+    let percent = |name: &str, value: f64| {
+        if value > 0.0 && value <= 100.0 {
+            Ok(value / 100.0)
+        } else {
+            bail!("{} is {} but must be a percentage above 0", name, value)
+        }
+    };
+    if !(entry.monthly_price >= 0.0 && entry.monthly_price.is_finite()) {
+        bail!(
+            "monthly_price is {} but must not be negative",
+            entry.monthly_price
+        );
+    }
+    let credits = entry
+        .credits
+        .into_iter()
+        .map(|(below, credit)| Ok((percent("availability", below)?, percent("credit", credit)?)))
+        .collect::<Result<_>>()
+        .context("credits")?;
+    Ok(Sla {
+        availability: percent("availability", entry.availability)?,
+        monthly_price: entry.monthly_price,
+        credits,
+    })
+}
+
+/// Checks that `name` fits in a DNS query: labels of 1 to 63 bytes, at most 253 in all, and a
+/// trailing dot at most.
+fn check_dns_name(name: &str) -> Result<()> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        bail!("is empty");
+    }
+    if name.len() > MAX_DNS_NAME {
+        bail!("is longer than {} bytes", MAX_DNS_NAME);
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            bail!("has an empty label");
+        }
+        if label.len() > MAX_DNS_LABEL {
+            bail!("has a label longer than {} bytes", MAX_DNS_LABEL);
+        }
+    }
+    Ok(())
+}
+
+/// Checks one entry. A DNS entry for the system resolver becomes one target per resolver.
+fn validate(entry: TargetEntry, interval_millis: u32, timeout_millis: u32) -> Result<Vec<Target>> {
+    if entry.name.trim().is_empty() {
+        bail!("name is empty");
+    }
+    if entry.address.trim().is_empty() {
+        bail!("address is empty");
+    }
+    let interval_millis = entry.interval_ms.unwrap_or(interval_millis);
+    if interval_millis < MIN_INTERVAL_MILLIS {
+        bail!(
+            "interval_ms is {} but must be at least {} so no provider is probed more than once per second",
+            interval_millis,
+            MIN_INTERVAL_MILLIS
+        );
+    }
+    let timeout_millis = entry.timeout_ms.unwrap_or(timeout_millis);
+    if timeout_millis == 0 || timeout_millis > MAX_TIMEOUT_MILLIS {
+        bail!(
+            "timeout_ms is {} but must be between 1 and {}",
+            timeout_millis,
+            MAX_TIMEOUT_MILLIS
+        );
+    }
+    if entry.kind != ProbeKind::Dns && (entry.query.is_some() || entry.record.is_some()) {
+        bail!("query and record only apply to dns targets");
+    }
+    if let Some(query) = &entry.query {
+        check_dns_name(query).context(format!("query '{}'", query))?;
+    }
+    let record = match entry.record.as_deref() {
+        None => RecordType::A,
+        Some(record) if record.eq_ignore_ascii_case("a") => RecordType::A,
+        Some(record) if record.eq_ignore_ascii_case("aaaa") => RecordType::Aaaa,
+        Some(record) => bail!("record is '{}' but must be A or AAAA", record),
+    };
+    match entry.kind {
+        ProbeKind::Tcp if !entry.address.contains(':') || entry.address.ends_with(']') => bail!(
+            "tcp address '{}' needs a port, e.g. 1.1.1.1:443",
+            entry.address
+        ),
+        ProbeKind::Http
+            if !entry.address.starts_with("http://") && !entry.address.starts_with("https://") =>
+        {
+            bail!(
+                "http address '{}' must be an http:// or https:// URL",
+                entry.address
+            )
+        }
+        _ => {}
+    }
+    let target = Target {
+        name: entry.name,
+        kind: entry.kind,
+        address: entry.address,
+        interval_millis,
+        timeout: Duration::from_millis(timeout_millis as u64),
+        query: entry.query.unwrap_or_else(|| DEFAULT_DNS_QUERY.to_string()),
+        record,
+        gateway: false,
+    };
+    if target.kind != ProbeKind::Dns || target.address != SYSTEM_RESOLVER {
+        return Ok(vec![target]);
+    }
+    let resolvers = probe::system_resolvers();
+    if resolvers.is_empty() {
+        bail!("the system has no DNS resolvers configured");
+    }
+    Ok(resolvers
+        .iter()
+        .map(|resolver| Target {
+            name: format!("{} ({})", target.name, resolver),
+            address: resolver.to_string(),
+            ..target.clone()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        format!("{:#}", parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn built_in_targets_are_valid() {
+        let text = format!(
+            "gateway = \"off\"\n{}{}",
+            DEFAULT_TARGETS, DEFAULT_IPV6_TARGETS
+        );
+        let config = parse(&text).unwrap();
+        assert_eq!(config.quorum, None);
+        let targets = config.targets;
+        assert_eq!(targets.len(), 8);
+        assert!(targets.iter().all(|target| target.kind == ProbeKind::Icmp));
+        assert_eq!(targets[0].interval_millis, DEFAULT_INTERVAL_MILLIS);
+    }
+
+    #[test]
+    fn entries_override_file_defaults() {
+        let targets = parse(
+            r#"
+            timeout_ms = 500
+            gateway = "192.168.1.1"
+            [[target]]
+            name = "VPN"
+            kind = "tcp"
+            address = "vpn.example.com:443"
+            interval_ms = 5000
+            [[target]]
+            name = "DNS"
+            kind = "dns"
+            address = "8.8.8.8"
+            record = "aaaa"
+            "#,
+        )
+        .unwrap()
+        .targets;
+        assert_eq!(targets[0].interval_millis, 5000);
+        assert_eq!(targets[0].timeout, Duration::from_millis(500));
+        assert_eq!(targets[1].record, RecordType::Aaaa);
+        assert_eq!(targets[1].query, DEFAULT_DNS_QUERY);
+        assert!(targets[2].gateway);
+        assert_eq!(targets[2].address, "192.168.1.1");
+        assert_eq!(targets[2].timeout, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn bad_entries_are_named() {
+        let message =
+            error("[[target]]\nname = \"Fast\"\naddress = \"1.1.1.1\"\ninterval_ms = 100\n");
+        assert!(message.starts_with("target 'Fast': interval_ms is 100"));
+        let message = error("[[target]]\nname = \"Web\"\nkind = \"tcp\"\naddress = \"1.1.1.1\"\n");
+        assert!(message.contains("needs a port"));
+        let message = error("[[target]]\nname = \"A\"\naddress = \"1.1.1.1\"\nquery = \"x\"\n");
+        assert!(message.contains("only apply to dns targets"));
+        let message = error("[[target]]\nname = \"A\"\naddress = \"1.1.1.1\"\nkind = \"udp\"\n");
+        assert!(message.contains("udp"));
+        let dns = "[[target]]\nname = \"DNS\"\nkind = \"dns\"\naddress = \"1.1.1.1\"\n";
+        let message = error(&format!("{}query = \"a..example.com\"\n", dns));
+        assert_eq!(
+            message,
+            "target 'DNS': query 'a..example.com': has an empty label"
+        );
+        let long = format!("{}query = \"{}.com\"\n", dns, "a".repeat(64));
+        assert!(error(&long).ends_with("has a label longer than 63 bytes"));
+        let root = format!("{}query = \".example.com\"\n", dns);
+        assert!(error(&root).ends_with("has an empty label"));
+        assert!(parse(&format!(
+            "gateway = \"off\"\n{}query = \"example.com.\"\n",
+            dns
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn target_list_is_checked() {
+        assert!(error("interval_ms = 1000\n").contains("no [[target]] entries"));
+        let twice = "[[target]]\nname = \"A\"\naddress = \"1.1.1.1\"\n".repeat(2);
+        assert!(error(&twice).contains("used more than once"));
+        let one = "quorum = 2\n[[target]]\nname = \"A\"\naddress = \"1.1.1.1\"\n";
+        assert!(error(one).contains("quorum is 2"));
+        let web = "gateway = \"off\"\n[[target]]\nname = \"A\"\nkind = \"http\"\n\
+            address = \"http://[::1]:8080/x\"\n[[target]]\nname = \"B\"\nkind = \"http\"\n\
+            address = \"http://[::1]/y\"\n";
+        assert_eq!(
+            error(web),
+            "targets 'A' and 'B' both send http probes to ::1, so they cannot be told apart"
+        );
+        let window = "status_window = \"2h\"\n[[target]]\nname = \"A\"\naddress = \"1.1.1.1\"\n";
+        assert!(error(window).starts_with("status_window: '2h' is not a window"));
+    }
+
+    #[test]
+    fn one_address_takes_different_kinds_of_probe() {
+        let shared = "gateway = \"off\"\n[[target]]\nname = \"Google\"\naddress = \"8.8.8.8\"\n\
+            [[target]]\nname = \"Google DNS\"\nkind = \"dns\"\naddress = \"8.8.8.8:53\"\n";
+        let targets = parse(shared).unwrap().targets;
+        assert_eq!(targets[0].literal_address(), targets[1].literal_address());
+        assert_eq!(
+            (targets[0].kind, targets[1].kind),
+            (ProbeKind::Icmp, ProbeKind::Dns)
+        );
+    }
+
+    #[test]
+    fn system_resolver_can_be_the_gateway() {
+        // home routers usually hand themselves out as the resolver
+        let resolver = match probe::system_resolvers().first() {
+            Some(resolver) => resolver.to_string(),
+            None => return, // nothing to test against on a machine without resolvers
+        };
+        let text = format!(
+            "gateway = \"{}\"\n[[target]]\nname = \"Resolver\"\nkind = \"dns\"\naddress = \"system\"\n",
+            resolver
+        );
+        let targets = parse(&text).unwrap().targets;
+        let gateway = targets.iter().find(|target| target.gateway).unwrap();
+        assert_eq!(gateway.address, resolver);
+        assert!(targets
+            .iter()
+            .any(|target| target.kind == ProbeKind::Dns && target.address == resolver));
+    }
+
+    #[test]
+    fn sla_is_in_percent() {
+        let targets = "gateway = \"off\"\n[[target]]\nname = \"A\"\naddress = \"1.1.1.1\"\n";
+        let text = format!(
+            "{}[sla]\navailability = 99.9\nmonthly_price = 120\ncredits = [[99.9, 10], [99, 25]]\n",
+            targets
+        );
+        let sla = parse(&text).unwrap().sla.unwrap();
+        assert!((sla.availability - 0.999).abs() < 1e-12);
+        assert_eq!(sla.monthly_price, 120.0);
+        assert_eq!(sla.credits.len(), 2);
+        assert_eq!(sla.credits[1], (0.99, 0.25));
+        let over = format!("{}[sla]\navailability = 999\n", targets);
+        assert!(error(&over).contains("availability is 999"));
+    }
+
+    #[test]
+    fn database_needs_sqlite_support() {
+        let text = "database = \"contrac.sqlite\"\ngateway = \"off\"\n[[target]]\nname = \"A\"\naddress = \"1.1.1.1\"\n";
+        let result = parse(text);
+        assert_eq!(result.is_ok(), cfg!(feature = "sqlite"));
+        if let Ok(config) = result {
+            assert_eq!(config.database, Some(PathBuf::from("contrac.sqlite")));
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use chrono::{Duration, Local};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::outage::Event;
+use crate::{utils, AppData, Sample};
+
+/// How often the status is written, the same as what the GUI shows in its status bar.
+const STATUS_MINS: i64 = 5;
+
+/// Runs without a window where the Windows GUI is not available. Connection changes are written
+/// to stdout, and to the timeouts log as they end, with the status every `STATUS_MINS`. Stops on
+/// Ctrl-C or a termination signal, noting what was still under way in the timeouts log.
+pub fn run(mut data: AppData) -> Result<()> {
+    let stopping = Arc::new(AtomicBool::new(false));
+    let handler = Arc::clone(&stopping);
+    ctrlc::set_handler(move || handler.store(true, Ordering::SeqCst))
+        .context("unable to handle Ctrl-C")?;
+    log_write("Running");
+    let watched = watch(&mut data, &stopping);
+    // whatever stopped it, what was still under way goes in the timeouts log
+    data.close_timeouts_log()?;
+    log_write("Stopped");
+    watched
+}
+
+/// Processes samples as they come in until `stopping` is set or the probers are gone.
+fn watch(data: &mut AppData, stopping: &AtomicBool) -> Result<()> {
+    let mut last_status = Local::now();
+    // samples come in every second or so, which is soon enough to notice stopping
+    while !stopping.load(Ordering::SeqCst) {
+        let sample = data
+            .samples_receiver
+            .recv()
+            .context("the probers stopped")?;
+        process_sample(data, sample);
+        data.collect_strays();
+        for message in data.collect_traces() {
+            log_write(&message);
+        }
+        if let Err(err) = data.append_timeouts_log() {
+            log_write(&format!("error saving timeouts log {:#}", err));
+        }
+
+        if (last_status + Duration::minutes(STATUS_MINS)) < Local::now() {
+            log_write(&data.status_message());
+            last_status = Local::now();
+        }
+    }
+    Ok(())
+}
+
+fn process_sample(data: &mut AppData, sample: Sample) {
+    data.record_observation(sample);
+    for message in data.save_sample(sample) {
+        log_write(&message);
+    }
+    if let Some(message) = data.track_interception(sample) {
+        log_write(&message);
+    }
+    for (family, event) in data.track_outages(sample) {
+        match event {
+            Event::Started { start } => {
+                log_write(&data.disconnected_message(family));
+                data.timeout_start
+                    .insert(family, utils::timestamp_to_datetime(start));
+                data.start_trace(sample.0.address);
+            }
+            Event::Ended { start, end } => {
+                log_write(&data.outage_message(family, start, end));
+                data.timeout_start.remove(&family);
+            }
+            Event::Degraded {
+                endpoint,
+                start,
+                end,
+            } => log_write(&format!(
+                "{} degraded for {} seconds",
+                data.target_name(endpoint),
+                (end - start) as f32 / 1_000_000_000.0
+            )),
+        }
+    }
+    for (family, event) in data.track_brownouts(sample) {
+        log_write(&data.brownout_message(family, event));
+    }
+}
+
+pub fn log_write(message: &str) {
+    println!("{}: {}", Local::now().format("%F %r"), message);
+}
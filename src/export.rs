@@ -0,0 +1,370 @@
+use anyhow::{bail, Error, Result};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::probe::{Endpoint, Trace};
+use crate::{utils, LoggedEvent, Sample};
+
+/// Written on every record. Bumped when a field is renamed, removed or changes meaning; new
+/// fields are only ever added at the end.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const SAMPLE_FIELDS: [&str; 10] = [
+    "schema",
+    "time",
+    "target",
+    "address",
+    "family",
+    "outcome",
+    "rtt_ms",
+    "icmp_type",
+    "icmp_code",
+    "probe",
+];
+
+const OUTAGE_FIELDS: [&str; 16] = [
+    "schema",
+    "kind",
+    "start",
+    "end",
+    "seconds",
+    "family",
+    "target",
+    "address",
+    "fault",
+    "failures",
+    "trace",
+    "severity",
+    "latency_ratio",
+    "loss_percent",
+    "evidence",
+    "probe",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// Comma-separated values with a header line.
+    Csv,
+    /// A JSON object per line.
+    JsonLines,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Ok(match text {
+            "csv" => Format::Csv,
+            "jsonl" => Format::JsonLines,
+            _ => bail!("'{}' is not an export format, use csv or jsonl", text),
+        })
+    }
+}
+
+/// A sample as exported. Times are RFC 3339 in UTC, so they read the same in any time zone.
+#[derive(Serialize)]
+struct SampleRecord<'a> {
+    schema: u32,
+    time: String,
+    target: &'a str,
+    address: IpAddr,
+    family: String,
+    /// `ok`, or the kind of failure, e.g. `timeout` or `no_route`.
+    outcome: &'static str,
+    rtt_ms: Option<f64>,
+    icmp_type: Option<u8>,
+    icmp_code: Option<u8>,
+    /// The kind of probe sent to the target: `icmp`, `tcp`, `dns` or `http`.
+    probe: &'static str,
+}
+
+/// An outage, brownout, degraded provider, interception or time contrac was not running, as
+/// exported.
+#[derive(Serialize)]
+struct OutageRecord<'a> {
+    schema: u32,
+    /// `outage`, `brownout`, `degraded`, `interception` or `not_running`.
+    kind: &'static str,
+    start: String,
+    end: String,
+    seconds: f64,
+    family: Option<String>,
+    target: Option<&'a str>,
+    address: Option<IpAddr>,
+    fault: Option<&'static str>,
+    /// The kinds of failure, separated by `;`.
+    failures: String,
+    trace: Option<String>,
+    /// How bad a brownout got: `mild`, `moderate` or `severe`, with its highest latency as a
+    /// multiple of the usual and its highest loss.
+    severity: Option<String>,
+    latency_ratio: Option<f32>,
+    loss_percent: Option<f32>,
+    /// How the gateway and each target answered during an outage, as in the timeouts log.
+    evidence: Option<String>,
+    probe: Option<&'static str>,
+}
+
+fn rfc3339<T: TimeZone>(time: &DateTime<T>) -> String {
+    time.with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn write<T: Serialize>(
+    mut writer: impl Write,
+    format: Format,
+    fields: &[&str],
+    records: impl IntoIterator<Item = T>,
+) -> Result<()> {
+    match format {
+        Format::Csv => {
+            // the header goes in even when there are no records
+            let mut csv = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(writer);
+            csv.write_record(fields)?;
+            for record in records {
+                csv.serialize(record)?;
+            }
+            csv.flush()?;
+        }
+        Format::JsonLines => {
+            for record in records {
+                serde_json::to_writer(&mut writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `samples`, naming their targets from `names`.
+pub fn write_samples(
+    writer: impl Write,
+    format: Format,
+    samples: &[Sample],
+    names: &HashMap<Endpoint, String>,
+) -> Result<()> {
+    let records = samples.iter().map(|&(endpoint, timestamp, result)| {
+        let icmp = result.err().and_then(|failure| failure.icmp());
+        SampleRecord {
+            schema: SCHEMA_VERSION,
+            time: rfc3339(&utils::timestamp_to_datetime(timestamp)),
+            target: names.get(&endpoint).map_or("", String::as_str),
+            address: endpoint.address,
+            family: endpoint.family().to_string(),
+            outcome: result.map_or_else(|failure| failure.kind(), |_rtt| "ok"),
+            rtt_ms: result.ok().map(utils::millis),
+            icmp_type: icmp.map(|icmp| icmp.kind),
+            icmp_code: icmp.map(|icmp| icmp.code),
+            probe: endpoint.kind.name(),
+        }
+    });
+    write(writer, format, &SAMPLE_FIELDS, records)
+}
+
+/// Writes `events`, each with the trace taken when it started if there is one, naming their
+/// targets from `names`.
+pub fn write_outages(
+    writer: impl Write,
+    format: Format,
+    events: &[(&LoggedEvent, Option<&Trace>)],
+    names: &HashMap<Endpoint, String>,
+) -> Result<()> {
+    let records = events.iter().map(|(event, trace)| {
+        let (start, end) = event.span();
+        let mut record = OutageRecord {
+            schema: SCHEMA_VERSION,
+            kind: match event {
+                LoggedEvent::Outage { .. } => "outage",
+                LoggedEvent::Brownout { .. } => "brownout",
+                LoggedEvent::Degraded { .. } => "degraded",
+                LoggedEvent::Interception { .. } => "interception",
+                LoggedEvent::NotRunning { .. } => "not_running",
+            },
+            start: rfc3339(&start),
+            end: rfc3339(&end),
+            seconds: (end - start).num_microseconds().unwrap_or(0) as f64 / 1_000_000.0,
+            family: None,
+            target: None,
+            address: None,
+            fault: None,
+            failures: String::new(),
+            trace: trace.map(Trace::to_string),
+            severity: None,
+            latency_ratio: None,
+            loss_percent: None,
+            evidence: None,
+            probe: None,
+        };
+        match event {
+            LoggedEvent::Outage {
+                family,
+                fault,
+                failures,
+                evidence,
+                ..
+            } => {
+                let failures: Vec<_> = failures.iter().map(|failure| failure.kind()).collect();
+                record.family = Some(family.to_string());
+                record.fault = Some(fault.kind());
+                record.failures = failures.join(";");
+                record.evidence = Some(evidence.to_string());
+            }
+            LoggedEvent::Brownout { family, peak, .. } => {
+                record.family = Some(family.to_string());
+                record.severity = Some(peak.severity().to_string());
+                record.latency_ratio = Some(peak.latency_ratio);
+                record.loss_percent = Some(peak.loss * 100.0);
+            }
+            LoggedEvent::Degraded { endpoint, .. } => {
+                record.family = Some(endpoint.family().to_string());
+                record.target = names.get(endpoint).map(String::as_str);
+                record.address = Some(endpoint.address);
+                record.probe = Some(endpoint.kind.name());
+            }
+            LoggedEvent::Interception {
+                endpoint, failure, ..
+            } => {
+                record.family = Some(endpoint.family().to_string());
+                record.target = names.get(endpoint).map(String::as_str);
+                record.address = Some(endpoint.address);
+                record.probe = Some(endpoint.kind.name());
+                record.failures = failure.kind().to_string();
+            }
+            LoggedEvent::NotRunning { .. } => {}
+        }
+        record
+    });
+    write(writer, format, &OUTAGE_FIELDS, records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brownout::Peak;
+    use crate::fault::{Evidence, Fault, Tally};
+    use crate::probe::{Failure, Family, Icmp};
+    use chrono::Local;
+
+    fn names() -> HashMap<Endpoint, String> {
+        let mut names = HashMap::new();
+        names.insert("8.8.8.8".parse().unwrap(), "Google".to_string());
+        names.insert("8.8.8.8 dns".parse().unwrap(), "Google DNS".to_string());
+        names
+    }
+
+    fn export(write: impl FnOnce(&mut Vec<u8>) -> Result<()>) -> String {
+        let mut buffer = Vec::new();
+        write(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn samples_export_as_csv_in_utc() {
+        let unreachable = Failure::Unreachable(Some(Icmp { kind: 3, code: 1 }));
+        let samples = [
+            ("8.8.8.8".parse().unwrap(), 1_500_000_000, Ok(12_345)),
+            ("9.9.9.9".parse().unwrap(), 2_000_000_000, Err(unreachable)),
+            (
+                "8.8.8.8 dns".parse().unwrap(),
+                2_500_000_000,
+                Err(Failure::Timeout),
+            ),
+        ];
+        let csv = export(|buffer| write_samples(buffer, Format::Csv, &samples, &names()));
+        assert_eq!(
+            csv,
+            "schema,time,target,address,family,outcome,rtt_ms,icmp_type,icmp_code,probe\n\
+             1,1970-01-01T00:00:01.500000Z,Google,8.8.8.8,IPv4,ok,12.345,,,icmp\n\
+             1,1970-01-01T00:00:02.000000Z,,9.9.9.9,IPv4,unreachable,,3,1,icmp\n\
+             1,1970-01-01T00:00:02.500000Z,Google DNS,8.8.8.8,IPv4,timeout,,,,dns\n"
+        );
+    }
+
+    #[test]
+    fn outages_export_as_json_lines() {
+        let start = Local.timestamp_opt(60, 0).unwrap();
+        let end = Local.timestamp_opt(90, 500_000_000).unwrap();
+        let outage = LoggedEvent::Outage {
+            family: Family::V4,
+            start,
+            end,
+            fault: Fault::UpstreamIsp,
+            failures: vec![Failure::Timeout, Failure::NoRoute],
+            evidence: Evidence {
+                gateway: Tally {
+                    answered: 2,
+                    sent: 2,
+                },
+                targets: Vec::new(),
+            },
+        };
+        let degraded = LoggedEvent::Degraded {
+            endpoint: "8.8.8.8".parse().unwrap(),
+            start,
+            end,
+        };
+        let brownout = LoggedEvent::Brownout {
+            family: Family::V6,
+            start,
+            end,
+            peak: Peak {
+                latency_ratio: 2.5,
+                loss: 0.0,
+            },
+        };
+        let events = [(&outage, None), (&degraded, None), (&brownout, None)];
+        let jsonl = export(|buffer| write_outages(buffer, Format::JsonLines, &events, &names()));
+        let lines: Vec<_> = jsonl.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"schema":1,"kind":"outage","start":"1970-01-01T00:01:00.000000Z","end":"1970-01-01T00:01:30.500000Z","seconds":30.5,"family":"IPv4","target":null,"address":null,"fault":"upstream_isp","failures":"timeout;no_route","trace":null,"severity":null,"latency_ratio":null,"loss_percent":null,"evidence":"gateway 2/2","probe":null}"#
+        );
+        assert!(lines[1].contains(r#""kind":"degraded""#));
+        assert!(lines[1].contains(r#""target":"Google","address":"8.8.8.8""#));
+        assert!(lines[1].ends_with(r#""probe":"icmp"}"#));
+        assert!(lines[2].ends_with(
+            r#""severity":"mild","latency_ratio":2.5,"loss_percent":0.0,"evidence":null,"probe":null}"#
+        ));
+    }
+
+    /// The keys of a JSON object, in the order they were written.
+    fn keys(json: &str) -> Vec<String> {
+        let object: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(json).unwrap();
+        let mut keys: Vec<_> = object.keys().cloned().collect();
+        keys.sort_by_key(|key| json.find(&format!("\"{}\":", key)));
+        keys
+    }
+
+    #[test]
+    fn headers_match_the_records() {
+        let sample = ("8.8.8.8".parse().unwrap(), 0, Ok(1));
+        let json = export(|buffer| write_samples(buffer, Format::JsonLines, &[sample], &names()));
+        assert_eq!(keys(&json), SAMPLE_FIELDS);
+        let event = LoggedEvent::NotRunning {
+            start: Local::now(),
+            end: Local::now(),
+        };
+        let json =
+            export(|buffer| write_outages(buffer, Format::JsonLines, &[(&event, None)], &names()));
+        assert_eq!(keys(&json), OUTAGE_FIELDS);
+        let csv = export(|buffer| write_outages(buffer, Format::Csv, &[], &names()));
+        assert_eq!(csv.trim_end(), OUTAGE_FIELDS.join(","));
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::{bail, Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::config::ProbeKind;
+use crate::probe::{Endpoint, Failure};
+
+/// Where along the path an outage was, judged by how the default gateway answered during it
+/// and which of the Internet targets still did.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Probes could not even be sent, so this machine's Wi-Fi or LAN link was down.
+    LocalNetwork,
+    /// The link was up but the gateway did not answer, so the router or modem failed.
+    GatewayModem,
+    /// Only DNS targets failed while other targets answered, so lookups broke but the
+    /// connection did not.
+    DnsOnly,
+    /// Only one provider's address failed while other targets answered.
+    SingleProvider,
+    /// The gateway answered while the Internet targets did not, so the fault is with the ISP.
+    UpstreamIsp,
+    /// No gateway samples cover the outage, e.g. because no gateway was found.
+    Unknown,
+}
+
+impl Fault {
+    /// Attributes an outage from how the Internet targets failed and the gateway probe results
+    /// taken while it lasted.
+    pub fn attribute<'a>(
+        gateway: impl IntoIterator<Item = &'a Result<u32, Failure>>,
+        targets: impl IntoIterator<Item = &'a Failure>,
+    ) -> Fault {
+        if targets.into_iter().any(|failure| failure.is_local()) {
+            return Fault::LocalNetwork;
+        }
+        let mut fault = Fault::Unknown;
+        for result in gateway {
+            fault = match (result, fault) {
+                (Ok(_), _) => return Fault::UpstreamIsp,
+                (Err(failure), _) if failure.is_local() => Fault::LocalNetwork,
+                (_, Fault::LocalNetwork) => Fault::LocalNetwork,
+                (Err(_), _) => Fault::GatewayModem,
+            };
+        }
+        fault
+    }
+
+    /// Narrows down a fault attributed from the gateway with how each target fared. Targets
+    /// that still answered show the path through the gateway was up, so the outage was only of
+    /// the ones that did not: the DNS targets, one provider, or the rest of the ISP's routes.
+    pub fn classify(self, evidence: &Evidence) -> Fault {
+        if self == Fault::LocalNetwork {
+            return self;
+        }
+        let failing: Vec<_> = evidence
+            .targets
+            .iter()
+            .filter(|target| target.tally.failing())
+            .collect();
+        if failing.is_empty()
+            || !evidence
+                .targets
+                .iter()
+                .any(|target| target.tally.answering())
+        {
+            return self;
+        }
+        if failing
+            .iter()
+            .all(|target| target.endpoint.kind == ProbeKind::Dns)
+        {
+            Fault::DnsOnly
+        } else if failing.len() == 1 {
+            Fault::SingleProvider
+        } else {
+            Fault::UpstreamIsp
+        }
+    }
+
+    /// A name for the fault that stays the same across versions, for exports.
+    pub fn kind(self) -> &'static str {
+        match self {
+            Fault::LocalNetwork => "local_network",
+            Fault::GatewayModem => "gateway_modem",
+            Fault::DnsOnly => "dns_only",
+            Fault::SingleProvider => "single_provider",
+            Fault::UpstreamIsp => "upstream_isp",
+            Fault::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Fault::LocalNetwork => "local network",
+            Fault::GatewayModem => "gateway/modem",
+            Fault::DnsOnly => "DNS only",
+            Fault::SingleProvider => "single provider",
+            Fault::UpstreamIsp => "upstream ISP",
+            Fault::Unknown => "unknown",
+        })
+    }
+}
+
+impl FromStr for Fault {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Ok(match text {
+            "local network" => Fault::LocalNetwork,
+            "gateway/modem" => Fault::GatewayModem,
+            "DNS only" => Fault::DnsOnly,
+            "single provider" => Fault::SingleProvider,
+            "upstream ISP" => Fault::UpstreamIsp,
+            "unknown" => Fault::Unknown,
+            _ => bail!("'{}' is not a fault", text),
+        })
+    }
+}
+
+/// How many probes were answered out of those sent.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Tally {
+    pub answered: usize,
+    pub sent: usize,
+}
+
+impl Tally {
+    pub fn record(&mut self, result: &Result<u32, Failure>) {
+        self.sent += 1;
+        if result.is_ok() {
+            self.answered += 1;
+        }
+    }
+
+    /// Fewer than half the probes were answered. Targets rarely fail at the same moment, so
+    /// one that failed a moment after the outage started still counts.
+    fn failing(self) -> bool {
+        self.answered * 2 < self.sent
+    }
+
+    fn answering(self) -> bool {
+        self.answered * 2 > self.sent
+    }
+}
+
+/// How an Internet target fared during an outage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetTally {
+    pub endpoint: Endpoint,
+    pub tally: Tally,
+}
+
+/// What an outage's fault was judged by: how the gateway and each Internet target answered
+/// while it lasted, e.g. `gateway 3/3; 8.8.8.8 5/5; 8.8.8.8 dns 0/5`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Evidence {
+    pub gateway: Tally,
+    pub targets: Vec<TargetTally>,
+}
+
+impl fmt::Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.gateway {
+            Tally { sent: 0, .. } => f.write_str("no gateway")?,
+            Tally { answered, sent } => write!(f, "gateway {}/{}", answered, sent)?,
+        }
+        for target in &self.targets {
+            let Tally { answered, sent } = target.tally;
+            write!(f, "; {} {}/{}", target.endpoint, answered, sent)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: [Failure; 1] = [Failure::Timeout];
+
+    #[test]
+    fn any_gateway_reply_puts_the_fault_upstream() {
+        let results = [Err(Failure::NoRoute), Err(Failure::Timeout), Ok(2)];
+        assert_eq!(Fault::attribute(&results, &TIMEOUT), Fault::UpstreamIsp);
+        let results = [Err(Failure::Timeout), Err(Failure::NoRoute)];
+        assert_eq!(Fault::attribute(&results, &TIMEOUT), Fault::LocalNetwork);
+        let results = [Err(Failure::Timeout), Err(Failure::Unreachable(None))];
+        assert_eq!(Fault::attribute(&results, &TIMEOUT), Fault::GatewayModem);
+        assert_eq!(Fault::attribute(&[], &TIMEOUT), Fault::Unknown);
+    }
+
+    fn target(endpoint: &str, answered: usize, sent: usize) -> TargetTally {
+        TargetTally {
+            endpoint: endpoint.parse().unwrap(),
+            tally: Tally { answered, sent },
+        }
+    }
+
+    #[test]
+    fn targets_that_still_answer_narrow_the_fault_down() {
+        let mut evidence = Evidence {
+            gateway: Tally {
+                answered: 3,
+                sent: 3,
+            },
+            targets: vec![
+                target("1.1.1.1", 5, 5),
+                target("8.8.8.8 dns", 0, 5),
+                target("9.9.9.9 dns", 1, 5),
+            ],
+        };
+        assert_eq!(Fault::UpstreamIsp.classify(&evidence), Fault::DnsOnly);
+        // answering targets show the gateway passed traffic, whatever its own probes did
+        assert_eq!(Fault::GatewayModem.classify(&evidence), Fault::DnsOnly);
+        assert_eq!(Fault::LocalNetwork.classify(&evidence), Fault::LocalNetwork);
+        evidence.targets[1].endpoint.kind = ProbeKind::Icmp;
+        assert_eq!(Fault::Unknown.classify(&evidence), Fault::UpstreamIsp);
+        evidence.targets[2].tally.answered = 4;
+        assert_eq!(
+            Fault::UpstreamIsp.classify(&evidence),
+            Fault::SingleProvider
+        );
+        // nothing answered, so there is nothing to narrow down
+        evidence.targets[0].tally.answered = 0;
+        evidence.targets[2].tally.answered = 0;
+        assert_eq!(Fault::GatewayModem.classify(&evidence), Fault::GatewayModem);
+        assert_eq!(
+            evidence.to_string(),
+            "gateway 3/3; 1.1.1.1 0/5; 8.8.8.8 0/5; 9.9.9.9 dns 0/5"
+        );
+        assert_eq!(Evidence::default().to_string(), "no gateway");
+    }
+
+    #[test]
+    fn a_resolver_that_pings_but_does_not_resolve_is_a_dns_fault() {
+        let evidence = Evidence {
+            gateway: Tally {
+                answered: 3,
+                sent: 3,
+            },
+            targets: vec![target("8.8.8.8", 5, 5), target("8.8.8.8 dns", 0, 5)],
+        };
+        assert_eq!(Fault::UpstreamIsp.classify(&evidence), Fault::DnsOnly);
+        assert_eq!(
+            evidence.to_string(),
+            "gateway 3/3; 8.8.8.8 5/5; 8.8.8.8 dns 0/5"
+        );
+    }
+
+    #[test]
+    fn local_failures_are_never_upstream() {
+        let failures = [Failure::Timeout, Failure::NoRoute];
+        assert_eq!(Fault::attribute(&[Ok(2)], &failures), Fault::LocalNetwork);
+        assert_eq!(Fault::attribute(&[], &failures), Fault::LocalNetwork);
+    }
+}
@@ -7,9 +7,9 @@ extern crate native_windows_gui as nwg;
 
 use nwd::NwgPartial;
 
+use super::history;
 use super::stats;
 use super::utils;
-use crate::Sample;
 
 const GRAPH_INTERVAL_MILLIS: i64 = 1000;
 const GRAPH_MAX_MILLIS: u16 = 1500;
@@ -24,7 +24,10 @@ pub struct GraphData {
     bar_count: u16,
     min: u16,
     max: u16,
-    bars: Vec<stats::Stats<u16>>,
+    /// Round trip times per bar in microseconds, so bars are drawn to sub-millisecond detail.
+    bars: Vec<stats::Stats<u64>>,
+    /// Bars for intervals when contrac was not running.
+    absent: Vec<bool>,
     // subject: Subject<'a>,
 }
 
@@ -35,6 +38,7 @@ impl Default for GraphData {
             min: u16::MAX,
             max: 0,
             bars: Vec::new(),
+            absent: Vec::new(),
             // subject: Subject::new(),
         }
     }
@@ -65,6 +69,8 @@ pub struct GraphUi {
     pub min_select: nwg::TextInput,
 
     bars: RefCell<Vec<nwg::ImageFrame>>,
+    /// Shaded the full height of the graph where contrac was not running.
+    gap_bars: RefCell<Vec<nwg::ImageFrame>>,
     // tooltips: nwg::Tooltip,
 }
 
@@ -86,6 +92,16 @@ impl GraphUi {
                     graph_bars.push(new_bar);
                 }
             }
+            let mut gap_bars = self.gap_bars.borrow_mut();
+            for _i in gap_bars.len() as u16..graph_bars_len {
+                let mut gap_bar = Default::default();
+                nwg::ImageFrame::builder()
+                    .parent(&self.frame)
+                    .background_color(Some([225, 225, 235]))
+                    .build(&mut gap_bar)
+                    .expect("Failed to build gap marker");
+                gap_bars.push(gap_bar);
+            }
             let mut data = self.data.borrow_mut();
             data.bar_count = graph_bars_len;
             data.min = min;
@@ -93,6 +109,7 @@ impl GraphUi {
             if data.bar_count != data.bars.len() as u16 {
                 data.bars
                     .resize_with(graph_bars_len as usize, Default::default);
+                data.absent.resize(graph_bars_len as usize, false);
             }
         }
         let (min, max) = (format!("{}", min), format!("{}", max));
@@ -146,49 +163,35 @@ impl GraphUi {
         }
     }
 
-    pub fn set_values(&self, samples: &VecDeque<Sample>) {
-        // Loop backward in time in 1 second intervals aligned to clock.
-        // so find now to the nearest forward 1 second aligned time in nanoseconds and then iterate backward in time.
-        // if there is no data in that interval it can be invisible
-
-        let now = Local::now();
+    /// Shows the buckets of the last `bar_count` intervals, one per bar, with the bar for the
+    /// interval in progress on the right. `buckets` are one interval wide, oldest first. The
+    /// intervals within `gaps`, when contrac was not running, are shaded instead.
+    pub fn set_values(&self, buckets: &[history::Bucket], gaps: &VecDeque<history::Gap>) {
         let interval = Duration::milliseconds(GRAPH_INTERVAL_MILLIS);
-        let mut end_of_interval = (now + interval)
+        let width = interval.num_nanoseconds().expect("graph interval fits") as u128;
+        let now = Local::now()
             .duration_trunc(interval)
             .expect("time trucation should always work");
-        let mut start_of_interval = end_of_interval - interval;
-
-        let mut probe_count_remaining = samples.len();
-
-        let bars = self.bars.borrow();
-        let mut bar_is_complete = false;
+        let end = utils::datetime_to_timestamp(&now) + width;
 
-        for i in (0..bars.len()).rev() {
-            let mut stats = <stats::Stats<u16> as Default>::default();
-            while !bar_is_complete {
-                if probe_count_remaining > 0 {
-                    // let Some(thing) = probes[probe_count_remaining] {
-                    let (_address, timestamp, _ping) = samples[probe_count_remaining - 1]; //**thing;
-                    let datetime = utils::timestamp_to_datetime(timestamp);
-                    assert!(datetime < end_of_interval, "confirming time");
-                    if datetime < start_of_interval {
-                        bar_is_complete = true;
-                    }
-                } else {
-                    bar_is_complete = true;
-                }
-                if bar_is_complete {
-                    let mut data = self.data.borrow_mut();
-                    data.bars[i] = stats;
-                    bar_is_complete = false;
-                    break;
-                }
-                let (_address, _timestamp, ping) = samples[probe_count_remaining - 1];
-                probe_count_remaining -= 1;
-                stats.update(ping);
-            }
-            end_of_interval = start_of_interval;
-            start_of_interval = end_of_interval - interval;
+        let mut data = self.data.borrow_mut();
+        let count = data.bars.len() as u128;
+        let start = end.saturating_sub(count * width);
+        for bar in data.bars.iter_mut() {
+            *bar = Default::default();
+        }
+        for bucket in buckets
+            .iter()
+            .filter(|bucket| (start..end).contains(&bucket.start))
+        {
+            let index = ((bucket.start - start) / width) as usize;
+            data.bars[index].merge(&bucket.stats);
+        }
+        for (index, absent) in data.absent.iter_mut().enumerate() {
+            let interval = start + index as u128 * width;
+            *absent = gaps
+                .iter()
+                .any(|(from, to)| interval < *to && interval + width > *from);
         }
     }
 
@@ -218,28 +221,30 @@ impl GraphUi {
                 let _pos = bar.average().unwrap();
                 let mut low = bar.min;
                 let mut high = bar.max;
+                // the scale is set in milliseconds
+                let (min, max) = (data.min as u64 * 1_000, data.max as u64 * 1_000);
 
                 // Clip
 
-                if low < data.min {
-                    low = data.min;
+                if low < min {
+                    low = min;
                 }
-                if low > data.max {
-                    low = data.max;
+                if low > max {
+                    low = max;
                 }
-                if high < data.min {
-                    high = data.min;
+                if high < min {
+                    high = min;
                 }
-                if high > data.max {
-                    high = data.max;
+                if high > max {
+                    high = max;
                 }
 
-                let bar_h_ratio = (high - low) as f32 / (data.max - data.min) as f32;
+                let bar_h_ratio = (high - low) as f32 / (max - min) as f32;
                 let mut bar_h = (h as f32 * bar_h_ratio) as u32;
                 if bar_h < 2 {
                     bar_h = 2;
                 }
-                let top_gap_ratio = (data.max - high) as f32 / (data.max - data.min) as f32;
+                let top_gap_ratio = (max - high) as f32 / (max - min) as f32;
                 let top_gap = (h as f32 * top_gap_ratio) as i32;
                 {
                     graph_bar.set_size(1 + (w / data_len as u32), bar_h);
@@ -254,6 +259,14 @@ impl GraphUi {
             } else {
                 graph_bar.set_visible(false);
             }
+            let gap_bar = &self.gap_bars.borrow()[i];
+            if data.absent[i] {
+                gap_bar.set_size(1 + (w / data_len as u32), h);
+                gap_bar.set_position(w as i32 * (i as i32) / data_len as i32, 0);
+                gap_bar.set_visible(true);
+            } else {
+                gap_bar.set_visible(false);
+            }
         }
     }
 }
@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::probe::{Endpoint, Family};
+use crate::stats::Stats;
+use crate::Sample;
+
+const SECOND: u128 = 1_000_000_000;
+const MINUTE: u128 = 60 * SECOND;
+const HOUR: u128 = 60 * MINUTE;
+/// Individual samples are kept this long, enough to attribute an outage and for the samples
+/// report; older ones only survive in the rollups and the sample journal.
+const RAW_WINDOW: u128 = HOUR;
+
+/// From the last sample before contrac stopped to when it started again, in nanoseconds.
+pub type Gap = (u128, u128);
+
+/// How finely the rollups divide time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Resolution {
+    Second,
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 3] = [Resolution::Second, Resolution::Minute, Resolution::Hour];
+
+    /// The length of each bucket in nanoseconds.
+    pub fn width(self) -> u128 {
+        match self {
+            Resolution::Second => SECOND,
+            Resolution::Minute => MINUTE,
+            Resolution::Hour => HOUR,
+        }
+    }
+
+    /// How far back buckets are kept, so each resolution holds a fixed number of them.
+    fn kept(self) -> u128 {
+        match self {
+            Resolution::Second => HOUR,
+            Resolution::Minute => 24 * HOUR,
+            Resolution::Hour => 31 * 24 * HOUR,
+        }
+    }
+}
+
+/// The round trip times in microseconds and failures of the samples taken from `start` for
+/// the width of the bucket's resolution.
+#[derive(Clone)]
+pub struct Bucket {
+    pub start: u128,
+    pub stats: Stats<u64>,
+}
+
+/// Adds a sample to the bucket it falls in, keeping the buckets in time order.
+pub fn add(buckets: &mut VecDeque<Bucket>, width: u128, timestamp: u128, rtt: Option<u64>) {
+    let start = timestamp - timestamp % width;
+    // samples arrive nearly in order, so the bucket is at or near the back
+    let index = match buckets.iter().rposition(|bucket| bucket.start <= start) {
+        Some(index) if buckets[index].start == start => index,
+        found => {
+            let index = found.map_or(0, |index| index + 1);
+            buckets.insert(
+                index,
+                Bucket {
+                    start,
+                    stats: Stats::default(),
+                },
+            );
+            index
+        }
+    };
+    buckets[index].stats.update(rtt);
+}
+
+/// The samples of the last `RAW_WINDOW` in timestamp order, and per target rollups of older
+/// ones at a second, minute and hour resolution, each kept for a bounded time so that memory
+/// use stays the same however long contrac runs.
+#[derive(Default)]
+pub struct History {
+    samples: VecDeque<Sample>,
+    rollups: HashMap<Endpoint, Vec<VecDeque<Bucket>>>,
+    /// When contrac was not running, as long as the rollups go back.
+    gaps: VecDeque<Gap>,
+    latest: u128,
+    /// The second buckets of one family's targets combined, as last handed out by `seconds_of`.
+    seconds: Option<(Family, VecDeque<Bucket>)>,
+    /// The earliest sample recorded since `seconds` was last brought up to date.
+    changed_since: Option<u128>,
+}
+
+impl History {
+    pub fn record(&mut self, sample: Sample) {
+        let (endpoint, timestamp, result) = sample;
+        let index = self
+            .samples
+            .iter()
+            .rposition(|(_endpoint, earlier, _result)| *earlier <= timestamp)
+            .map_or(0, |index| index + 1);
+        self.samples.insert(index, sample);
+        let rollups = self
+            .rollups
+            .entry(endpoint)
+            .or_insert_with(|| vec![VecDeque::new(); Resolution::ALL.len()]);
+        for (resolution, buckets) in Resolution::ALL.iter().zip(rollups.iter_mut()) {
+            add(
+                buckets,
+                resolution.width(),
+                timestamp,
+                result.ok().map(u64::from),
+            );
+        }
+        self.latest = self.latest.max(timestamp);
+        self.changed_since = Some(
+            self.changed_since
+                .map_or(timestamp, |since| since.min(timestamp)),
+        );
+        self.prune();
+    }
+
+    /// Notes that contrac was not running from `start` to `end`.
+    pub fn record_gap(&mut self, start: u128, end: u128) {
+        self.gaps.push_back((start, end));
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        let kept = self.latest.saturating_sub(Resolution::Hour.kept());
+        while self.gaps.front().is_some_and(|(_start, end)| *end < kept) {
+            self.gaps.pop_front();
+        }
+        let oldest = self.latest.saturating_sub(RAW_WINDOW);
+        while self
+            .samples
+            .front()
+            .is_some_and(|(_endpoint, timestamp, _result)| *timestamp < oldest)
+        {
+            self.samples.pop_front();
+        }
+        for rollups in self.rollups.values_mut() {
+            for (resolution, buckets) in Resolution::ALL.iter().zip(rollups.iter_mut()) {
+                let oldest = self.latest.saturating_sub(resolution.kept());
+                while buckets
+                    .front()
+                    .is_some_and(|bucket| bucket.start + resolution.width() <= oldest)
+                {
+                    buckets.pop_front();
+                }
+            }
+        }
+        if let Some((_family, buckets)) = &mut self.seconds {
+            let oldest = self.latest.saturating_sub(Resolution::Second.kept());
+            while buckets
+                .front()
+                .is_some_and(|bucket| bucket.start + SECOND <= oldest)
+            {
+                buckets.pop_front();
+            }
+        }
+    }
+
+    /// The samples still kept individually, oldest first.
+    pub fn samples(&self) -> &VecDeque<Sample> {
+        &self.samples
+    }
+
+    /// The stretches when contrac was not running, oldest first.
+    #[cfg_attr(not(windows), allow(dead_code))] // the graph
+    pub fn gaps(&self) -> &VecDeque<Gap> {
+        &self.gaps
+    }
+
+    /// The buckets at `resolution` of the targets `include` picks, combined into one bucket for
+    /// each stretch of time, oldest first.
+    #[cfg(test)] // the graph keeps its combined seconds up to date with `seconds_of`
+    pub fn rollups(
+        &self,
+        resolution: Resolution,
+        include: impl Fn(Endpoint) -> bool,
+    ) -> Vec<Bucket> {
+        let index = Resolution::ALL
+            .iter()
+            .position(|kept| *kept == resolution)
+            .unwrap();
+        let mut combined: BTreeMap<u128, Stats<u64>> = BTreeMap::new();
+        for (endpoint, rollups) in &self.rollups {
+            if !include(*endpoint) {
+                continue;
+            }
+            for bucket in &rollups[index] {
+                combined
+                    .entry(bucket.start)
+                    .or_default()
+                    .merge(&bucket.stats);
+            }
+        }
+        combined
+            .into_iter()
+            .map(|(start, stats)| Bucket { start, stats })
+            .collect()
+    }
+
+    /// The second buckets of the targets of `family`, combined into one bucket for each second,
+    /// oldest first, as `rollups` gives them. The combined buckets are kept, and only the
+    /// seconds with samples recorded since the last call are combined again, so the graph can
+    /// refresh several times a second without merging an hour of histograms each time.
+    #[cfg_attr(not(windows), allow(dead_code))] // the graph
+    pub fn seconds_of(&mut self, family: Family) -> &[Bucket] {
+        let from = match &self.seconds {
+            Some((kept, _buckets)) if *kept == family => {
+                self.changed_since.map(|since| since - since % SECOND)
+            }
+            _ => {
+                self.seconds = Some((family, VecDeque::new()));
+                Some(0)
+            }
+        };
+        let (_family, combined) = self.seconds.as_mut().expect("set above");
+        if let Some(from) = from {
+            combined.truncate(combined.partition_point(|bucket| bucket.start < from));
+            let mut changed: BTreeMap<u128, Stats<u64>> = BTreeMap::new();
+            for (endpoint, rollups) in &self.rollups {
+                if endpoint.family() != family {
+                    continue;
+                }
+                let seconds = &rollups[0];
+                let first = seconds.partition_point(|bucket| bucket.start < from);
+                for bucket in seconds.range(first..) {
+                    changed
+                        .entry(bucket.start)
+                        .or_default()
+                        .merge(&bucket.stats);
+                }
+            }
+            combined.extend(
+                changed
+                    .into_iter()
+                    .map(|(start, stats)| Bucket { start, stats }),
+            );
+        }
+        self.changed_since = None;
+        combined.make_contiguous()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Failure;
+    use std::net::IpAddr;
+
+    fn endpoint(last: u8) -> Endpoint {
+        IpAddr::from([10, 0, 0, last]).into()
+    }
+
+    #[test]
+    fn samples_are_kept_in_order_within_the_window() {
+        let mut history = History::default();
+        history.record((endpoint(1), 2 * SECOND, Ok(20)));
+        history.record((endpoint(2), SECOND, Ok(20)));
+        history.record((endpoint(1), 3 * SECOND, Ok(20)));
+        let timestamps: Vec<_> = history.samples().iter().map(|sample| sample.1).collect();
+        assert_eq!(timestamps, vec![SECOND, 2 * SECOND, 3 * SECOND]);
+        history.record((endpoint(1), RAW_WINDOW + 2 * SECOND, Ok(20)));
+        assert_eq!(history.samples().front().unwrap().1, 2 * SECOND);
+    }
+
+    #[test]
+    fn rollups_combine_targets() {
+        let mut history = History::default();
+        history.record((endpoint(1), 0, Ok(10)));
+        history.record((endpoint(2), SECOND / 2, Ok(30)));
+        history.record((endpoint(1), SECOND, Err(Failure::Timeout)));
+        let seconds = history.rollups(Resolution::Second, |_endpoint| true);
+        assert_eq!(
+            seconds
+                .iter()
+                .map(|bucket| (bucket.start, bucket.stats.count, bucket.stats.failures))
+                .collect::<Vec<_>>(),
+            vec![(0, 2, 0), (SECOND, 0, 1)]
+        );
+        assert_eq!(seconds[0].stats.distribution.percentile(100.0), Some(30));
+        let minutes = history.rollups(Resolution::Minute, |target| target == endpoint(1));
+        assert_eq!(minutes.len(), 1);
+        let stats = &minutes[0].stats;
+        assert_eq!(
+            (stats.min, stats.max, stats.count, stats.failures),
+            (10, 10, 1, 1)
+        );
+    }
+
+    #[test]
+    fn seconds_are_combined_again_only_where_samples_came_in() {
+        let v6: Endpoint = IpAddr::from([0xfd00, 0, 0, 0, 0, 0, 0, 1]).into();
+        let mut history = History::default();
+        let summary = |buckets: &[Bucket]| -> Vec<_> {
+            buckets
+                .iter()
+                .map(|bucket| (bucket.start, bucket.stats.count, bucket.stats.failures))
+                .collect()
+        };
+        for second in 0..10 {
+            history.record((endpoint(1), second * SECOND, Ok(10)));
+            history.record((endpoint(2), second * SECOND, Err(Failure::Timeout)));
+            history.record((v6, second * SECOND, Ok(30)));
+        }
+        let v4 = |history: &History| {
+            summary(&history.rollups(Resolution::Second, |endpoint| endpoint.address.is_ipv4()))
+        };
+        let expected = v4(&history);
+        assert_eq!(summary(history.seconds_of(Family::V4)), expected);
+        // a late reply lands in an earlier second, and a new second starts
+        history.record((endpoint(2), 7 * SECOND + 1, Ok(12)));
+        history.record((endpoint(1), 10 * SECOND, Ok(10)));
+        let expected = v4(&history);
+        assert_eq!(expected[7], (7 * SECOND, 2, 1));
+        assert_eq!(summary(history.seconds_of(Family::V4)), expected);
+        assert_eq!(history.seconds_of(Family::V6).len(), 10);
+    }
+
+    #[test]
+    fn memory_stays_bounded_over_a_week() {
+        let mut history = History::default();
+        let week = 7 * 24 * HOUR;
+        let mut timestamp = 0;
+        while timestamp < week {
+            history.record((endpoint(1), timestamp, Ok(20)));
+            timestamp += 10 * SECOND;
+        }
+        let count = |resolution| history.rollups(resolution, |_endpoint| true).len() as u128;
+        assert_eq!(
+            history.samples().len() as u128,
+            RAW_WINDOW / (10 * SECOND) + 1
+        );
+        assert_eq!(count(Resolution::Second), HOUR / (10 * SECOND) + 1);
+        assert_eq!(count(Resolution::Minute), 24 * 60 + 1);
+        assert_eq!(count(Resolution::Hour), 7 * 24);
+    }
+}
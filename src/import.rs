@@ -0,0 +1,497 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, FixedOffset};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::brownout::{Peak, Severity};
+use crate::fault::Fault;
+use crate::probe::{Endpoint, Failure, Family};
+use crate::{utils, Sample};
+
+/// How chrono displays a time, which is how both logs write them.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f %:z";
+/// How log file names give the time the run started, after the host name.
+const IDENTIFIER_FORMAT: &str = "%Y-%m-%d %H-%M-%S-%3f %z";
+const SAMPLES_SUFFIX: &str = " samples.log";
+const TIMEOUTS_SUFFIX: &str = " timeouts.log";
+
+/// A line of a samples log. Logs from before loss and jitter were tracked only have the time,
+/// the result and the target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleLine {
+    pub time: DateTime<FixedOffset>,
+    /// The round trip time in microseconds, or why there was none.
+    pub result: Result<u32, Failure>,
+    pub endpoint: Endpoint,
+    pub loss_percent: Option<f32>,
+    pub jitter: Option<f32>,
+    /// The late and duplicate replies to the probe.
+    pub notes: String,
+}
+
+impl SampleLine {
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))] // for storing imported samples
+    pub fn sample(&self) -> Sample {
+        (
+            self.endpoint,
+            utils::datetime_to_timestamp(&self.time),
+            self.result,
+        )
+    }
+}
+
+/// A line of a timeouts log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimeoutLine {
+    /// Logs from older versions leave out some or all of the fault, IP version, failures,
+    /// evidence and trace.
+    Outage {
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+        fault: Option<Fault>,
+        family: Option<Family>,
+        failures: Vec<Failure>,
+        /// How the gateway and each target answered, without the `evidence: ` label.
+        evidence: Option<String>,
+        trace: Option<String>,
+    },
+    Degraded {
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+        endpoint: Endpoint,
+    },
+    Brownout {
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+        family: Family,
+        peak: Peak,
+    },
+    /// Logs from older versions leave out the target.
+    Interception {
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+        failure: Failure,
+        endpoint: Option<Endpoint>,
+    },
+    NotRunning {
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+    },
+}
+
+impl TimeoutLine {
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))] // for storing imported outages
+    pub fn span(&self) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+        match *self {
+            TimeoutLine::Outage { start, end, .. }
+            | TimeoutLine::Degraded { start, end, .. }
+            | TimeoutLine::Brownout { start, end, .. }
+            | TimeoutLine::Interception { start, end, .. }
+            | TimeoutLine::NotRunning { start, end } => (start, end),
+        }
+    }
+}
+
+/// A line that could not be read, numbered from 1, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Malformed {
+    pub number: usize,
+    pub text: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: '{}'", self.number, self.reason, self.text)
+    }
+}
+
+/// The lines of a log that were read, in order, and those that were not.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parsed<T> {
+    pub records: Vec<T>,
+    pub malformed: Vec<Malformed>,
+}
+
+/// The contents of a samples or timeouts log.
+pub enum Log {
+    Samples(Parsed<SampleLine>),
+    Timeouts(Parsed<TimeoutLine>),
+}
+
+/// Reads a samples or timeouts log, telling which it is from the file name.
+pub fn read(path: &Path) -> Result<Log> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let bytes = fs::read(path).context(format!("unable to read '{}'", path.display()))?;
+    // a damaged byte should cost one line, not the whole file
+    let text = String::from_utf8_lossy(&bytes);
+    if name.ends_with(SAMPLES_SUFFIX) {
+        Ok(Log::Samples(parse_samples_log(&text)))
+    } else if name.ends_with(TIMEOUTS_SUFFIX) {
+        Ok(Log::Timeouts(parse_timeouts_log(&text)))
+    } else {
+        bail!(
+            "'{}' is neither a samples log nor a timeouts log",
+            path.display()
+        )
+    }
+}
+
+/// The host and start time of the run that wrote a log, from its file name, e.g.
+/// `contrac desk 2024-01-31 09-00-00-000 +0100 timeouts.log`.
+pub fn identify(path: &Path) -> Option<(String, DateTime<FixedOffset>)> {
+    let name = path.file_name()?.to_str()?;
+    let name = name
+        .strip_suffix(SAMPLES_SUFFIX)
+        .or_else(|| name.strip_suffix(TIMEOUTS_SUFFIX))?;
+    // samples logs used to be written without the prefix
+    let name = name.strip_prefix("contrac ").unwrap_or(name);
+    let mut words = name.rsplitn(4, ' ');
+    let (zone, time, date, host) = (words.next()?, words.next()?, words.next()?, words.next()?);
+    let started = format!("{} {} {}", date, time, zone);
+    let started = DateTime::parse_from_str(&started, IDENTIFIER_FORMAT).ok()?;
+    Some((host.to_string(), started))
+}
+
+pub fn parse_samples_log(text: &str) -> Parsed<SampleLine> {
+    parse_lines(text, parse_sample_line)
+}
+
+pub fn parse_timeouts_log(text: &str) -> Parsed<TimeoutLine> {
+    parse_lines(text, parse_timeout_line)
+}
+
+fn parse_lines<T>(text: &str, parse: impl Fn(&str) -> Result<T>) -> Parsed<T> {
+    let mut parsed = Parsed {
+        records: Vec::new(),
+        malformed: Vec::new(),
+    };
+    for (index, line) in text.lines().enumerate() {
+        // a last line cut short can lose its '\n' but keep the '\r'
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse(line) {
+            Ok(record) => parsed.records.push(record),
+            Err(err) => parsed.malformed.push(Malformed {
+                number: index + 1,
+                text: line.to_string(),
+                reason: format!("{:#}", err),
+            }),
+        }
+    }
+    parsed
+}
+
+fn parse_time(text: &str) -> Result<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(text, TIME_FORMAT).context(format!("'{}' is not a time", text))
+}
+
+/// A round trip time in milliseconds, whole in older logs, or how the probe failed.
+fn parse_result(text: &str) -> Result<Result<u32, Failure>> {
+    match text.parse::<f64>() {
+        Ok(millis) if millis.is_finite() && millis >= 0.0 => {
+            Ok(Ok((millis * 1_000.0).round().min(u32::MAX as f64) as u32))
+        }
+        Ok(_) => bail!("'{}' is not a round trip time", text),
+        Err(_) => Ok(Err(text.parse()?)),
+    }
+}
+
+fn parse_sample_line(line: &str) -> Result<SampleLine> {
+    let fields: Vec<_> = line.split(", ").collect();
+    let (time, result, endpoint, quality) = match fields[..] {
+        [time, result, endpoint] => (time, result, endpoint, None),
+        [time, result, endpoint, loss, jitter, notes] => {
+            (time, result, endpoint, Some((loss, jitter, notes)))
+        }
+        _ => bail!("expected 3 or 6 fields, found {}", fields.len()),
+    };
+    let mut line = SampleLine {
+        time: parse_time(time)?,
+        result: parse_result(result)?,
+        endpoint: endpoint.parse()?,
+        loss_percent: None,
+        jitter: None,
+        notes: String::new(),
+    };
+    if let Some((loss, jitter, notes)) = quality {
+        line.loss_percent = Some(loss.parse().context("bad loss")?);
+        line.jitter = Some(jitter.parse().context("bad jitter")?);
+        line.notes = notes.to_string();
+    }
+    Ok(line)
+}
+
+fn parse_timeout_line(line: &str) -> Result<TimeoutLine> {
+    let fields: Vec<_> = line.split(", ").collect();
+    if fields.len() < 3 {
+        bail!("expected at least 3 fields, found {}", fields.len());
+    }
+    let start = parse_time(fields[0])?;
+    let end = parse_time(fields[1])?;
+    fields[2]
+        .parse::<f32>()
+        .context(format!("'{}' is not a number of seconds", fields[2]))?;
+    // what was under way when contrac stopped reads as it stood then
+    let rest = match &fields[3..] {
+        [rest @ .., "ongoing"] => rest,
+        rest => rest,
+    };
+    Ok(match rest {
+        [] => TimeoutLine::Outage {
+            start,
+            end,
+            fault: None,
+            family: None,
+            failures: Vec::new(),
+            evidence: None,
+            trace: None,
+        },
+        ["provider degraded", endpoint] => TimeoutLine::Degraded {
+            start,
+            end,
+            endpoint: endpoint.parse()?,
+        },
+        ["brownout", family, severity, latency, loss] => {
+            // the severity follows from the rest
+            severity.parse::<Severity>()?;
+            TimeoutLine::Brownout {
+                start,
+                end,
+                family: family.parse()?,
+                peak: Peak::parse(latency, loss)?,
+            }
+        }
+        ["not running"] => TimeoutLine::NotRunning { start, end },
+        [first, details @ ..] => match first.parse::<Fault>() {
+            Ok(fault) => {
+                let details = parse_outage_details(details)?;
+                TimeoutLine::Outage {
+                    start,
+                    end,
+                    fault: Some(fault),
+                    family: details.family,
+                    failures: details.failures,
+                    evidence: details.evidence,
+                    trace: details.trace,
+                }
+            }
+            Err(_) if details.len() <= 1 => TimeoutLine::Interception {
+                start,
+                end,
+                failure: first
+                    .parse()
+                    .context("neither an outage's fault nor an interception")?,
+                endpoint: details
+                    .first()
+                    .map(|endpoint| endpoint.parse())
+                    .transpose()?,
+            },
+            Err(err) => return Err(err),
+        },
+    })
+}
+
+/// What follows an outage's fault in the timeouts log.
+struct OutageDetails {
+    family: Option<Family>,
+    failures: Vec<Failure>,
+    evidence: Option<String>,
+    trace: Option<String>,
+}
+
+/// The IP version, failures, evidence and trace that follow an outage's fault, each of which
+/// older logs leave out.
+fn parse_outage_details(details: &[&str]) -> Result<OutageDetails> {
+    let mut family = None;
+    let mut failures = None;
+    let mut evidence = None;
+    let mut trace = None;
+    for detail in details {
+        if detail.starts_with("trace to ") && trace.is_none() {
+            trace = Some(detail.to_string());
+        } else if let Some(found) = detail.strip_prefix("evidence: ") {
+            if evidence.is_some() || trace.is_some() {
+                bail!("unexpected '{}'", detail);
+            }
+            evidence = Some(found.to_string());
+        } else if family.is_none() && failures.is_none() && detail.parse::<Family>().is_ok() {
+            family = detail.parse().ok();
+        } else if failures.is_none() && evidence.is_none() && trace.is_none() {
+            let list = detail
+                .split("; ")
+                .filter(|failure| !failure.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<Failure>>>()?;
+            failures = Some(list);
+        } else {
+            bail!("unexpected '{}'", detail);
+        }
+    }
+    Ok(OutageDetails {
+        family,
+        failures: failures.unwrap_or_default(),
+        evidence,
+        trace,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Icmp;
+
+    fn time(text: &str) -> DateTime<FixedOffset> {
+        parse_time(text).unwrap()
+    }
+
+    #[test]
+    fn samples_logs_of_every_version_are_read() {
+        let parsed = parse_samples_log(include_str!("../tests/fixtures/samples.log"));
+        assert_eq!(parsed.records.len(), 5);
+        assert_eq!(
+            parsed.records[0],
+            SampleLine {
+                time: time("2021-03-01 10:00:00.123456789 +01:00"),
+                result: Ok(23_000),
+                endpoint: "8.8.8.8".parse().unwrap(),
+                loss_percent: None,
+                jitter: None,
+                notes: String::new(),
+            }
+        );
+        assert_eq!(parsed.records[1].result, Err(Failure::Timeout));
+        let latest = &parsed.records[3];
+        assert_eq!(latest.result, Ok(1_234));
+        assert_eq!(
+            (latest.loss_percent, latest.jitter, latest.notes.as_str()),
+            (Some(33.3), Some(0.42), "late 2.500")
+        );
+        assert_eq!(
+            parsed.records[4].result,
+            Err(Failure::Unreachable(Some(Icmp { kind: 1, code: 3 })))
+        );
+        let numbers: Vec<_> = parsed.malformed.iter().map(|line| line.number).collect();
+        assert_eq!(numbers, vec![3, 7]);
+        assert!(parsed.malformed[0].reason.contains("not a probe failure"));
+    }
+
+    #[test]
+    fn timeouts_logs_of_every_version_are_read() {
+        let parsed = parse_timeouts_log(include_str!("../tests/fixtures/timeouts.log"));
+        assert_eq!(parsed.malformed.len(), 1);
+        assert_eq!(parsed.malformed[0].number, 8);
+        let records = parsed.records;
+        assert_eq!(records.len(), 12);
+        assert!(matches!(
+            records[0],
+            TimeoutLine::Outage {
+                fault: None,
+                family: None,
+                ..
+            }
+        ));
+        assert_eq!(
+            records[0].span(),
+            (
+                time("2021-03-01 10:00:00.5 +01:00"),
+                time("2021-03-01 10:00:12.5 +01:00")
+            )
+        );
+        assert!(matches!(
+            &records[2],
+            TimeoutLine::Outage {
+                fault: Some(Fault::UpstreamIsp),
+                family: None,
+                trace: Some(_),
+                ..
+            }
+        ));
+        match &records[4] {
+            TimeoutLine::Outage {
+                fault,
+                family,
+                failures,
+                trace,
+                ..
+            } => {
+                assert_eq!(*fault, Some(Fault::LocalNetwork));
+                assert_eq!(*family, Some(Family::V6));
+                assert_eq!(*failures, vec![Failure::Timeout, Failure::NoRoute]);
+                assert!(trace
+                    .as_deref()
+                    .unwrap()
+                    .starts_with("trace to 2001:db8::1:"));
+            }
+            other => panic!("not an outage: {:?}", other),
+        }
+        assert!(matches!(records[5], TimeoutLine::Degraded { .. }));
+        assert!(matches!(
+            records[6],
+            TimeoutLine::Interception {
+                failure: Failure::CaptivePortal,
+                endpoint: None,
+                ..
+            }
+        ));
+        assert!(matches!(records[7], TimeoutLine::NotRunning { .. }));
+        match &records[8] {
+            TimeoutLine::Outage {
+                family, failures, ..
+            } => assert_eq!((*family, failures.len()), (Some(Family::V4), 1)),
+            other => panic!("not an outage: {:?}", other),
+        }
+        match &records[9] {
+            TimeoutLine::Brownout { family, peak, .. } => {
+                assert_eq!(*family, Family::V4);
+                assert_eq!(peak.severity(), Severity::Moderate);
+            }
+            other => panic!("not a brownout: {:?}", other),
+        }
+        match &records[10] {
+            TimeoutLine::Outage {
+                fault,
+                failures,
+                evidence,
+                trace,
+                ..
+            } => {
+                assert_eq!(*fault, Some(Fault::DnsOnly));
+                assert_eq!(*failures, vec![Failure::ServFail]);
+                assert_eq!(
+                    evidence.as_deref(),
+                    Some("gateway 3/3; 1.1.1.1 4/4; 8.8.8.8 dns 0/4")
+                );
+                assert!(trace.is_some());
+            }
+            other => panic!("not an outage: {:?}", other),
+        }
+        assert_eq!(
+            records[11],
+            TimeoutLine::Interception {
+                start: time("2024-06-02 09:00:00 +02:00"),
+                end: time("2024-06-02 09:00:06 +02:00"),
+                failure: Failure::Intercepted,
+                endpoint: Some("93.184.216.34 http".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn file_names_tell_the_run() {
+        let path = Path::new("contrac desk 2024-01-31 09-00-00-250 +0100 timeouts.log");
+        assert_eq!(
+            identify(path),
+            Some(("desk".to_string(), time("2024-01-31 09:00:00.250 +01:00")))
+        );
+        let older = Path::new("desk 2024-01-31 09-00-00-250 +0100 samples.log");
+        assert_eq!(identify(older).unwrap().0, "desk");
+        assert_eq!(identify(Path::new("notes.txt")), None);
+    }
+}
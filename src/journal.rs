@@ -0,0 +1,373 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::ProbeKind;
+use crate::history::Gap;
+use crate::probe::Endpoint;
+use crate::{utils, Sample};
+
+/// Written at the top of each journal file so a later format can be told apart.
+const HEADER: &str = "# contrac sample journal 1\n";
+const EXTENSION: &str = "journal";
+/// Starts the line written each time contrac starts, followed by the timestamp.
+const STARTED: &str = "# started ";
+/// A day of samples from a handful of targets is a few of these.
+const MAX_FILE_BYTES: u64 = 8 * 1024 * 1024;
+/// Appends reach the operating system straight away, which is enough to survive contrac
+/// crashing; they are synced to the disk this often to survive the machine going down.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An append-only record of every sample as it comes in, one file per day and at most
+/// `MAX_FILE_BYTES` each.
+///
+/// Each sample is a line of its timestamp, target and round trip time in microseconds or
+/// failure, preceded by a CRC-32 of the rest of the line, so a record cut short by a crash or
+/// damaged on disk is found and skipped rather than misread. A comment line marks each start of
+/// contrac, so the times it was not running can be told from the samples stopping.
+pub struct Journal {
+    directory: PathBuf,
+    file: File,
+    date: NaiveDate,
+    sequence: u32,
+    len: u64,
+    last_synced: Instant,
+}
+
+impl Journal {
+    /// Opens the latest journal file in `directory`, creating the directory if needed, and
+    /// marks contrac starting. An incomplete record at the end of the file, left by a crash in
+    /// the middle of an append, is cut off; the number of bytes removed is returned with the
+    /// journal.
+    pub fn open(directory: &Path) -> Result<(Journal, u64)> {
+        fs::create_dir_all(directory)
+            .context(format!("unable to create '{}'", directory.display()))?;
+        let (date, sequence, torn) = match latest_file(directory)? {
+            Some((date, sequence)) => {
+                let torn = recover(&file_path(directory, date, sequence))?;
+                (date, sequence, torn)
+            }
+            None => (utils::timestamp_to_datetime(now()).date_naive(), 0, 0),
+        };
+        let (mut file, mut len) = open_file(directory, date, sequence)?;
+        let started = format!("{}{}\n", STARTED, now());
+        file.write_all(started.as_bytes())
+            .context("unable to append to the sample journal")?;
+        len += started.len() as u64;
+        let journal = Journal {
+            directory: directory.to_path_buf(),
+            file,
+            date,
+            sequence,
+            len,
+            last_synced: Instant::now(),
+        };
+        Ok((journal, torn))
+    }
+
+    pub fn append(&mut self, sample: Sample) -> Result<()> {
+        let date = utils::timestamp_to_datetime(sample.1).date_naive();
+        // samples from different probers can arrive slightly out of order around midnight, so
+        // only ever move on to a later day
+        if date > self.date {
+            self.rotate(date, 0)?;
+        } else if self.len >= MAX_FILE_BYTES {
+            self.rotate(self.date, self.sequence + 1)?;
+        }
+        let record = format_record(sample);
+        // a single write, so a crash leaves at most one partial record at the end
+        self.file
+            .write_all(record.as_bytes())
+            .context("unable to append to the sample journal")?;
+        self.len += record.len() as u64;
+        if self.last_synced.elapsed() >= SYNC_INTERVAL {
+            self.file
+                .sync_data()
+                .context("unable to sync the sample journal")?;
+            self.last_synced = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self, date: NaiveDate, sequence: u32) -> Result<()> {
+        self.file
+            .sync_data()
+            .context("unable to sync the sample journal")?;
+        let (file, len) = open_file(&self.directory, date, sequence)?;
+        self.file = file;
+        self.date = date;
+        self.sequence = sequence;
+        self.len = len;
+        Ok(())
+    }
+}
+
+/// The samples from `since` on, in timestamp order, and the stretches when contrac was not
+/// running, from the last sample before each start to the start.
+pub fn read_since(directory: &Path, since: u128) -> Result<(Vec<Sample>, Vec<Gap>)> {
+    let first_day = utils::timestamp_to_datetime(since).date_naive();
+    let entries =
+        fs::read_dir(directory).context(format!("unable to read '{}'", directory.display()))?;
+    // late samples go in the next day's file, so no earlier file has any from `since` on
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| parse_file_name(&entry.ok()?.path()))
+        .filter(|(date, _sequence)| *date >= first_day)
+        .collect();
+    files.sort();
+    let mut samples = Vec::new();
+    let mut gaps = Vec::new();
+    let mut last = None;
+    for (date, sequence) in files {
+        let path = file_path(directory, date, sequence);
+        let contents =
+            fs::read_to_string(&path).context(format!("unable to read '{}'", path.display()))?;
+        for line in contents.lines() {
+            if let Some(started) = line.strip_prefix(STARTED) {
+                match (last, started.parse::<u128>()) {
+                    (Some(last), Ok(started)) if started >= since && started > last => {
+                        gaps.push((last, started))
+                    }
+                    _ => {}
+                }
+            } else if let Some(sample) = parse_record(line) {
+                last = Some(last.map_or(sample.1, |last: u128| last.max(sample.1)));
+                if sample.1 >= since {
+                    samples.push(sample);
+                }
+            }
+        }
+    }
+    samples.sort_by_key(|(_endpoint, timestamp, _result)| *timestamp);
+    Ok((samples, gaps))
+}
+
+fn now() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+}
+
+fn file_path(directory: &Path, date: NaiveDate, sequence: u32) -> PathBuf {
+    directory.join(format!(
+        "{} {:03}.{}",
+        date.format("%Y-%m-%d"),
+        sequence,
+        EXTENSION
+    ))
+}
+
+/// The date and sequence number from a journal file name, or nothing for any other file.
+fn parse_file_name(path: &Path) -> Option<(NaiveDate, u32)> {
+    if path.extension()? != EXTENSION {
+        return None;
+    }
+    let (date, sequence) = path.file_stem()?.to_str()?.split_once(' ')?;
+    Some((
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?,
+        sequence.parse().ok()?,
+    ))
+}
+
+fn latest_file(directory: &Path) -> Result<Option<(NaiveDate, u32)>> {
+    let entries =
+        fs::read_dir(directory).context(format!("unable to read '{}'", directory.display()))?;
+    Ok(entries
+        .filter_map(|entry| parse_file_name(&entry.ok()?.path()))
+        .max())
+}
+
+fn open_file(directory: &Path, date: NaiveDate, sequence: u32) -> Result<(File, u64)> {
+    let path = file_path(directory, date, sequence);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context(format!("unable to open '{}'", path.display()))?;
+    let mut len = file.metadata()?.len();
+    if len == 0 {
+        file.write_all(HEADER.as_bytes())
+            .context(format!("unable to write to '{}'", path.display()))?;
+        len = HEADER.len() as u64;
+    }
+    Ok((file, len))
+}
+
+/// Cuts the file back to the end of its last intact record, returning how many bytes went.
+fn recover(path: &Path) -> Result<u64> {
+    let contents = fs::read(path).context(format!("unable to read '{}'", path.display()))?;
+    let mut intact = 0;
+    let mut offset = 0;
+    for line in contents.split_inclusive(|byte| *byte == b'\n') {
+        offset += line.len();
+        let complete = line.ends_with(b"\n")
+            && std::str::from_utf8(line)
+                .ok()
+                .is_some_and(|line| line.starts_with('#') || parse_record(line).is_some());
+        if complete {
+            intact = offset;
+        }
+    }
+    let torn = (contents.len() - intact) as u64;
+    if torn > 0 {
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(intact as u64))
+            .context(format!("unable to truncate '{}'", path.display()))?;
+    }
+    Ok(torn)
+}
+
+fn format_record(sample: Sample) -> String {
+    let (endpoint, timestamp, result) = sample;
+    let body = match result {
+        Ok(rtt) => format!("{} {} {}", timestamp, endpoint, rtt),
+        Err(failure) => format!("{} {} {}", timestamp, endpoint, failure),
+    };
+    format!("{:08x} {}\n", crc32fast::hash(body.as_bytes()), body)
+}
+
+/// The sample in a record, if its checksum matches.
+fn parse_record(line: &str) -> Option<Sample> {
+    let (checksum, body) = line.trim_end_matches('\n').split_once(' ')?;
+    if u32::from_str_radix(checksum, 16).ok()? != crc32fast::hash(body.as_bytes()) {
+        return None;
+    }
+    let mut fields = body.splitn(3, ' ');
+    let timestamp = fields.next()?.parse().ok()?;
+    let address = fields.next()?.parse().ok()?;
+    let rest = fields.next()?;
+    // ICMP targets name no kind, as every record did before there were other kinds
+    let (kind, result) = match rest
+        .split_once(' ')
+        .map(|(kind, result)| (kind.parse(), result))
+    {
+        Some((Ok(kind), result)) => (kind, result),
+        _ => (ProbeKind::Icmp, rest),
+    };
+    let result = match result.parse() {
+        Ok(rtt) => Ok(rtt),
+        Err(_) => Err(result.parse().ok()?),
+    };
+    Some((Endpoint { address, kind }, timestamp, result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::{Failure, Icmp};
+    use std::io::Read;
+
+    const DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+
+    /// An empty directory of its own for each test.
+    fn directory(test: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("contrac-journal-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn records(path: &Path) -> Vec<Sample> {
+        let mut contents = String::new();
+        File::open(path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents.lines().filter_map(parse_record).collect()
+    }
+
+    fn endpoint(last: u8) -> Endpoint {
+        std::net::IpAddr::from([10, 0, 0, last]).into()
+    }
+
+    #[test]
+    fn records_read_back() {
+        let icmp = Some(Icmp { kind: 3, code: 1 });
+        for sample in [
+            (endpoint(1), 5, Ok(1234)),
+            (
+                "fd00::1".parse().unwrap(),
+                6,
+                Err(Failure::Unreachable(icmp)),
+            ),
+            ("10.0.0.1 dns".parse().unwrap(), 7, Err(Failure::NoRoute)),
+            ("10.0.0.1 http".parse().unwrap(), 8, Ok(5678)),
+        ] {
+            let record = format_record(sample);
+            assert_eq!(parse_record(&record), Some(sample));
+            let damaged = record.replacen(&format!(" {} ", sample.1), " 9 ", 1);
+            assert_eq!(parse_record(&damaged), None);
+        }
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let directory = directory("torn");
+        let sample = (endpoint(1), now(), Ok(1234));
+        let path = {
+            let (mut journal, torn) = Journal::open(&directory).unwrap();
+            assert_eq!(torn, 0);
+            journal.append(sample).unwrap();
+            journal.append(sample).unwrap();
+            file_path(&directory, journal.date, journal.sequence)
+        };
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+        let (mut journal, torn) = Journal::open(&directory).unwrap();
+        assert_eq!(torn, (format_record(sample).len() - 5) as u64);
+        journal.append(sample).unwrap();
+        assert_eq!(records(&path), vec![sample, sample]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn files_rotate_by_day_and_size() {
+        let directory = directory("rotate");
+        let (mut journal, _torn) = Journal::open(&directory).unwrap();
+        let start = journal.date;
+        let tomorrow = now() + DAY;
+        journal.append((endpoint(1), tomorrow, Ok(1))).unwrap();
+        // a sample from before midnight that arrives late stays with the new day
+        journal.append((endpoint(2), now(), Ok(2))).unwrap();
+        assert_eq!(
+            (journal.date, journal.sequence),
+            (start.succ_opt().unwrap(), 0)
+        );
+        journal.len = MAX_FILE_BYTES;
+        journal.append((endpoint(3), tomorrow, Ok(3))).unwrap();
+        assert_eq!(journal.sequence, 1);
+        assert_eq!(records(&file_path(&directory, journal.date, 0)).len(), 2);
+        assert_eq!(latest_file(&directory).unwrap(), Some((journal.date, 1)));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn restarts_leave_gaps() {
+        let directory = directory("gaps");
+        let start = now();
+        let (mut journal, _torn) = Journal::open(&directory).unwrap();
+        journal.append((endpoint(1), start, Ok(1))).unwrap();
+        journal.append((endpoint(1), start + 1_000, Ok(2))).unwrap();
+        drop(journal);
+        let (mut journal, _torn) = Journal::open(&directory).unwrap();
+        let restart = now();
+        journal.append((endpoint(1), restart, Ok(3))).unwrap();
+        let (samples, gaps) = read_since(&directory, start + 1).unwrap();
+        let rtts: Vec<_> = samples.iter().map(|sample| sample.2).collect();
+        assert_eq!(rtts, vec![Ok(2), Ok(3)]);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].0, start + 1_000);
+        assert!((start + 1_000..=restart).contains(&gaps[0].1));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,129 +1,1041 @@
 #![windows_subsystem = "windows"]
 
-use anyhow::{bail, Context, Result};
-use chrono::{DateTime, Duration, Local};
+use anyhow::bail;
+use anyhow::{Context, Result};
+use chrono::Duration;
+use chrono::{DateTime, Datelike, Local};
 use directories::UserDirs;
-use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender};
+
+#[cfg(windows)]
+use std::cell::RefCell;
 use std::thread; // ::{spawn, JoinHandle};
-use std::time::{SystemTime, UNIX_EPOCH};
-use winping::{Buffer, Pinger};
+#[cfg(windows)]
 use winreg::enums::*;
+#[cfg(windows)]
 use winreg::RegKey;
 
+#[cfg(windows)]
 extern crate native_windows_derive as nwd;
+#[cfg(windows)]
 extern crate native_windows_gui as nwg;
+#[cfg(windows)]
 use nwd::NwgUi;
+#[cfg(windows)]
 use nwg::stretch::{
     geometry::{Rect, Size},
     style::{AlignItems, Dimension as D, FlexDirection, JustifyContent},
 };
+#[cfg(windows)]
 use nwg::NativeUi;
 
+mod brownout;
+mod config;
+#[cfg(not(windows))]
+mod console;
+mod export;
+mod fault;
+#[cfg(windows)]
 mod graph;
+mod history;
+mod import;
+mod journal;
+mod outage;
+mod probe;
+mod quality;
+mod rolling;
 mod stats;
+#[cfg(feature = "sqlite")]
+mod store;
+mod uptime;
 mod utils;
 
+use crate::fault::{Evidence, Fault, Tally, TargetTally};
+#[cfg(windows)]
 use crate::graph::*;
+use crate::history::History;
+use crate::outage::Quorum;
+use crate::probe::{Endpoint, Failure, Family, Prober, Stray, Trace};
+use crate::quality::{Quality, StrayKind};
+use crate::rolling::Rolling;
 use crate::utils::GetHostName;
 
-const MIN_PING_TIME_MILLIS: u32 = 1010;
+#[cfg(windows)]
 const GRAPH_REFRESH_MILLIS: i64 = 250;
+#[cfg(windows)]
 const MIN_TIMEOUT_INTERVAL_MILLIS: i64 = 1000;
-const AUTO_SAVE_MINS: i64 = 5;
+/// How long to wait before trying the timeouts log again after an append to it failed.
+const TIMEOUTS_RETRY_MINS: i64 = 5;
+/// Where the sample journal goes, inside the log directory.
+const JOURNAL_DIRECTORY: &str = "contrac journal";
+/// How much of the journal is replayed at startup, in nanoseconds.
+const RELOAD_WINDOW: u128 = 24 * 60 * 60 * 1_000_000_000;
+#[cfg(windows)]
 const GRAPH_BAR_COUNT: u16 = 40;
 
-pub type Sample = (IpAddr, u128, Option<u16>);
+/// The target, the timestamp in nanoseconds since the epoch and the round trip time in
+/// microseconds or why there was none.
+pub type Sample = (Endpoint, u128, Result<u32, Failure>);
 
+#[cfg_attr(not(windows), allow(dead_code))] // graph and registry state is only used by the GUI
 pub struct AppData {
-    count: u32,
-    total: u32,
-    min: u16,
-    max: u16,
-    samples: VecDeque<Sample>,
+    /// Round trip times in microseconds over the last minute, 15 minutes, hour and day.
+    latency: BTreeMap<Family, Rolling>,
+    /// Which of those the status bar shows.
+    status_window: rolling::Window,
+    last_rtt: HashMap<Family, u32>,
+    last_failure: HashMap<Family, Failure>,
+    history: History,
+    gateway: Option<Endpoint>,
+    gateway_history: History,
     registry_loaded: bool,
     graph_min: u16,
     graph_max: u16,
     last_full_update: DateTime<Local>,
-    last_sample_display_timeout_notification: bool,
-    timeout_start: Option<DateTime<Local>>,
+    timeout_notified: HashSet<Family>,
+    timeout_start: HashMap<Family, DateTime<Local>>,
+    outages: outage::DualStack,
+    brownouts: BTreeMap<Family, brownout::Detector>,
+    traces: Vec<Trace>,
+    tracing: bool,
+    traces_receiver: Receiver<Result<Trace>>,
+    traces_sender: Sender<Result<Trace>>,
+    intercepted: HashMap<Endpoint, (DateTime<Local>, Failure)>,
+    /// What the timeouts log records, kept as it happens since the samples behind it are not.
+    logged: Vec<LoggedEvent>,
+    quality: Quality,
+    strays: Vec<(Stray, StrayKind)>,
+    strays_receiver: Receiver<Stray>,
+    strays_sender: Sender<Stray>,
+    target_names: HashMap<Endpoint, String>,
     samples_receiver: Receiver<Sample>,
     samples_sender: Sender<Sample>,
     _app_start: DateTime<Local>,
     log_identifier: String,
-    last_saved: DateTime<Local>,
+    /// How many of the `logged` events have gone to the timeouts log.
+    written_events: usize,
+    /// When appending to the timeouts log last failed.
+    timeouts_failed: Option<DateTime<Local>>,
+    journal: Option<journal::Journal>,
+    #[cfg(feature = "sqlite")]
+    store: Option<store::Session>,
+    /// How many of the `logged` events have gone to the database.
+    #[cfg(feature = "sqlite")]
+    stored_events: usize,
 }
 
 impl Default for AppData {
     fn default() -> Self {
         let (s, r) = channel::<Sample>();
+        let (traces_sender, traces_receiver) = channel();
+        let (strays_sender, strays_receiver) = channel();
         let now = Local::now();
         let hostname = GetHostName().into_string().expect("Not a string");
         Self {
-            count: 0,
-            total: 0,
-            min: u16::MAX,
-            max: 0,
-            samples: VecDeque::new(),
+            latency: BTreeMap::new(),
+            status_window: rolling::Window::default(),
+            last_rtt: HashMap::new(),
+            last_failure: HashMap::new(),
+            history: History::default(),
+            gateway: None,
+            gateway_history: History::default(),
             registry_loaded: false,
             graph_min: 0,
             graph_max: 100,
             last_full_update: Local::now(),
-            last_sample_display_timeout_notification: false,
-            timeout_start: None,
+            timeout_notified: HashSet::new(),
+            timeout_start: HashMap::new(),
+            outages: outage::DualStack::new([
+                (Family::V4, Quorum::default()),
+                (Family::V6, Quorum::default()),
+            ]),
+            brownouts: BTreeMap::new(),
+            traces: Vec::new(),
+            tracing: false,
+            traces_receiver,
+            traces_sender,
+            intercepted: HashMap::new(),
+            logged: Vec::new(),
+            quality: Quality::default(),
+            strays: Vec::new(),
+            strays_receiver,
+            strays_sender,
+            target_names: HashMap::new(),
             samples_receiver: r,
             samples_sender: s,
             _app_start: now,
             log_identifier: format!("{} {}", hostname, now.format("%Y-%m-%d %H-%M-%S-%3f %z")),
-            last_saved: Local::now(),
+            written_events: 0,
+            timeouts_failed: None,
+            journal: None,
+            #[cfg(feature = "sqlite")]
+            store: None,
+            #[cfg(feature = "sqlite")]
+            stored_events: 0,
         }
     }
 }
 
 impl AppData {
-    fn average(&self) -> f32 {
-        return self.total as f32 / self.count as f32;
+    /// Remembers the targets' names for messages and exports, and which of them is the gateway.
+    fn know_targets(&mut self, probers: &TargetProbers) {
+        for (target, prober) in probers {
+            self.target_names
+                .insert(prober.endpoint(), target.name.clone());
+            if target.gateway {
+                self.gateway = Some(prober.endpoint());
+            }
+        }
+    }
+
+    /// Starts probing the targets on a shared schedule.
+    fn spawn_probers(&mut self, probers: TargetProbers) -> Vec<thread::JoinHandle<()>> {
+        self.know_targets(&probers);
+        let schedule: Vec<_> = probers
+            .iter()
+            .map(|(target, prober)| {
+                let interval = std::time::Duration::from_millis(target.interval_millis.into());
+                (prober.address(), interval)
+            })
+            .collect();
+        probers
+            .into_iter()
+            .zip(probe::Slot::stagger(&schedule))
+            .map(|((_target, prober), slot)| {
+                probe::spawn(
+                    prober,
+                    self.samples_sender.clone(),
+                    self.strays_sender.clone(),
+                    slot,
+                )
+            })
+            .collect()
+    }
+
+    fn target_name(&self, endpoint: Endpoint) -> String {
+        match self.target_names.get(&endpoint) {
+            Some(name) => format!("{} ({})", name, endpoint),
+            None => endpoint.to_string(),
+        }
+    }
+
+    /// Watches the families `outages` covers for brownouts as well.
+    fn set_outages(&mut self, outages: outage::DualStack) {
+        self.brownouts = outages
+            .families()
+            .map(|family| (family, brownout::Detector::default()))
+            .collect();
+        self.outages = outages;
+    }
+
+    /// Names the family in messages when both are monitored, so they can be told apart.
+    fn family_label(&self, family: Family) -> String {
+        match self.outages.families().count() {
+            1 => String::new(),
+            _ => format!("{} ", family),
+        }
+    }
+
+    /// Says that `family` is disconnected, and how its latest probe failed.
+    fn disconnected_message(&self, family: Family) -> String {
+        let message = match self.outages.families().count() {
+            1 => "Disconnected".to_string(),
+            _ => format!("{} disconnected", family),
+        };
+        match self.last_failure.get(&family) {
+            Some(failure) => format!("{}: {}", message, failure),
+            None => message,
+        }
+    }
+
+    /// Says that a brownout of `family` started, or how long it lasted and how bad it got.
+    fn brownout_message(&self, family: Family, event: brownout::Event) -> String {
+        let label = self.family_label(family);
+        match event {
+            brownout::Event::Started { .. } => {
+                format!("{}brownout: latency or loss well above usual", label)
+            }
+            brownout::Event::Ended(brownout) => format!(
+                "{}brownout for {} seconds, {}: {}",
+                label,
+                (brownout.end - brownout.start) as f32 / 1_000_000_000.0,
+                brownout.peak.severity(),
+                brownout.peak
+            ),
+        }
+    }
+
+    /// Describes a finished outage of `family` for the log, with the family's availability.
+    fn outage_message(&self, family: Family, start: u128, end: u128) -> String {
+        let start = utils::timestamp_to_datetime(start);
+        let end = utils::timestamp_to_datetime(end);
+        let failures: Vec<_> = self
+            .failures_between(family, start, end)
+            .iter()
+            .map(Failure::to_string)
+            .collect();
+        let mut message = format!(
+            "{}was disconnected for {} seconds ({}: {})",
+            self.family_label(family),
+            (end - start).num_milliseconds() as f32 / 1_000.0,
+            self.diagnose(family, start, end).0,
+            failures.join("; ")
+        );
+        if let Some(availability) = self.outages.availability(family) {
+            message.push_str(&format!(", {:.2}% available", availability * 100.0));
+        }
+        message
+    }
+
+    /// The latest latency, with the minimum, maximum, average, median and 99th percentile over
+    /// the chosen window, or that it is disconnected, for each family, followed by loss and
+    /// jitter.
+    fn status_message(&self) -> String {
+        let mut parts = Vec::new();
+        for family in self.outages.families() {
+            let label = self.family_label(family);
+            if self.outages.is_down(family) {
+                parts.push(self.disconnected_message(family));
+                continue;
+            }
+            let stats = self
+                .latency
+                .get(&family)
+                .map(|latency| latency.stats(self.status_window));
+            if let (Some(rtt), Some(stats)) = (self.last_rtt.get(&family), stats) {
+                let Some(average) = stats.average() else {
+                    continue; // nothing answered within the window
+                };
+                let millis = |rtt: u64| utils::millis(rtt.min(u32::MAX as u64) as u32);
+                let percentile =
+                    |percent| millis(stats.distribution.percentile(percent).unwrap_or(0));
+                parts.push(format!(
+                    "{}{:.2} ms, {} ({:.2}:{:.2}) {:.2} p50 {:.2} p99 {:.2}",
+                    label,
+                    utils::millis(*rtt),
+                    self.status_window,
+                    millis(stats.min),
+                    millis(stats.max),
+                    millis(average),
+                    percentile(50.0),
+                    percentile(99.0)
+                ));
+            }
+            let browned_out = self
+                .brownouts
+                .get(&family)
+                .and_then(|detector| detector.start());
+            if browned_out.is_some() {
+                parts.push(format!("{}brownout", label));
+            }
+        }
+        parts.push(self.quality_message());
+        parts.join(" ")
+    }
+
+    /// Starts writing every sample to the journal in the log directory. Returns a message when
+    /// the end of the journal had to be repaired after contrac last stopped.
+    fn open_journal(&mut self) -> Result<Option<String>> {
+        let directory = Self::log_directory().join(JOURNAL_DIRECTORY);
+        let (journal, torn) = journal::Journal::open(&directory)?;
+        self.journal = Some(journal);
+        Ok((torn > 0).then(|| {
+            format!(
+                "dropped an incomplete record ({} bytes) from the end of the sample journal",
+                torn
+            )
+        }))
+    }
+
+    /// Opens the journal, and the database if one is configured, returning messages for the
+    /// log about any problems. Either failing to open does not stop contrac.
+    fn open_storage(&mut self, database: Option<&Path>, probers: &TargetProbers) -> Vec<String> {
+        let mut messages = Vec::new();
+        match self.open_journal() {
+            Ok(message) => {
+                messages.extend(message);
+                let now = utils::datetime_to_timestamp(&self._app_start);
+                match self.reload(probers, now.saturating_sub(RELOAD_WINDOW), |_sample| {}) {
+                    Ok(reloaded) => messages.extend(reloaded),
+                    Err(err) => messages.push(format!("unable to reload the journal: {:#}", err)),
+                }
+                // the runs that saw the replayed events logged them; only the time since the
+                // last run stopped, which ends the replay, is new
+                self.written_events = self.logged.len();
+                if let Some(LoggedEvent::NotRunning { .. }) = self.logged.last() {
+                    self.written_events -= 1;
+                }
+            }
+            Err(err) => messages.push(format!("unable to start the sample journal: {:#}", err)),
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = database {
+            if let Err(err) = self.open_store(path, probers) {
+                messages.push(format!("unable to open the database: {:#}", err));
+            }
+        }
+        #[cfg(not(feature = "sqlite"))]
+        let _ = database; // the configuration is refused without SQLite support
+        messages
+    }
+
+    /// Replays the journal from `since`, so the stats, outages and graph carry on from earlier
+    /// runs, and marks the times contrac was not running. Returns messages for the log.
+    /// Each sample is also passed to `each`.
+    fn reload(
+        &mut self,
+        probers: &TargetProbers,
+        since: u128,
+        mut each: impl FnMut(Sample),
+    ) -> Result<Vec<String>> {
+        // the gateway's samples are kept apart, as they are when they come in
+        self.know_targets(probers);
+        let directory = Self::log_directory().join(JOURNAL_DIRECTORY);
+        let (samples, gaps) = journal::read_since(&directory, since)?;
+        let mut gaps = gaps.into_iter().peekable();
+        let mut messages = Vec::new();
+        if !samples.is_empty() {
+            messages.push(format!(
+                "replayed {} samples from the journal",
+                samples.len()
+            ));
+        }
+        for &sample in &samples {
+            while let Some((start, end)) = gaps.next_if(|(_start, end)| *end <= sample.1) {
+                messages.push(self.not_running(start, end));
+            }
+            self.record_observation(sample);
+            self.track_interception(sample);
+            self.track_outages(sample);
+            self.track_brownouts(sample);
+            each(sample);
+        }
+        for (start, end) in gaps {
+            messages.push(self.not_running(start, end));
+        }
+        Ok(messages)
+    }
+
+    /// Ends the outages and interceptions under way when contrac stopped at `start`, and marks
+    /// it not running until it started again at `end`. Returns a message for the log.
+    fn not_running(&mut self, start: u128, end: u128) -> String {
+        let events = self.outages.stopped(start);
+        self.log_events(&events);
+        self.stop_brownouts(start);
+        let stopped = utils::timestamp_to_datetime(start);
+        self.end_interceptions(stopped);
+        self.history.record_gap(start, end);
+        let started = utils::timestamp_to_datetime(end);
+        self.logged.push(LoggedEvent::NotRunning {
+            start: stopped,
+            end: started,
+        });
+        format!(
+            "not running from {} for {} seconds",
+            stopped,
+            (started - stopped).num_milliseconds() as f32 / 1_000.0
+        )
+    }
+
+    /// Logs the interceptions under way as ending at `end`.
+    fn end_interceptions(&mut self, end: DateTime<Local>) {
+        for (endpoint, (start, failure)) in self.intercepted.drain() {
+            self.logged.push(LoggedEvent::Interception {
+                endpoint,
+                start,
+                end,
+                failure,
+            });
+        }
+    }
+
+    /// Stores every sample in the database as well, along with the targets they are from.
+    #[cfg(feature = "sqlite")]
+    fn open_store(&mut self, path: &Path, probers: &TargetProbers) -> Result<()> {
+        let targets: Vec<_> = probers
+            .iter()
+            .map(|(target, prober)| (target.name.clone(), prober.endpoint(), target.gateway))
+            .collect();
+        let host = GetHostName().to_string_lossy().into_owned();
+        let started = utils::datetime_to_timestamp(&self._app_start);
+        let session = store::Store::open(path)?.start_session(&host, started, &targets)?;
+        self.store = Some(session);
+        // the events replayed from the journal were stored by the runs that saw them
+        self.stored_events = self.logged.len();
+        Ok(())
+    }
+
+    /// Appends the sample to the journal and the database, along with any outages that ended
+    /// since the last sample. Whichever of them fails is closed, and the messages for the log
+    /// say why.
+    fn save_sample(&mut self, sample: Sample) -> Vec<String> {
+        let mut messages = Vec::new();
+        if let Some(journal) = self.journal.as_mut() {
+            if let Err(err) = journal.append(sample) {
+                self.journal = None;
+                messages.push(format!("stopped writing the sample journal: {:#}", err));
+            }
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(store) = self.store.as_mut() {
+            let stored = self.logged[self.stored_events..]
+                .iter()
+                .try_for_each(|event| store.record_event(event))
+                .and_then(|_| store.record_sample(sample));
+            self.stored_events = self.logged.len();
+            if let Err(err) = stored {
+                self.store = None;
+                messages.push(format!("stopped writing the database: {:#}", err));
+            }
+        }
+        messages
+    }
+
+    fn is_gateway(&self, endpoint: Endpoint) -> bool {
+        self.gateway == Some(endpoint)
     }
 
     fn record_observation(&mut self, sample: Sample) {
-        let (address, timestamp_in_nano, response_time_in_milli) = sample;
-        if let Some(ping) = response_time_in_milli {
-            self.count += 1;
-            if ping < self.min {
-                self.min = ping;
-            };
-            if ping > self.max {
-                self.max = ping;
+        let (endpoint, timestamp_in_nano, response_time_in_micro) = sample;
+        if self.is_gateway(endpoint) {
+            // kept apart so the gateway's short round trips stay out of the latency stats
+            self.gateway_history.record(sample);
+            return;
+        }
+        self.quality.record(sample);
+        let family = endpoint.family();
+        if let Err(failure) = response_time_in_micro {
+            self.last_failure.insert(family, failure);
+        }
+        self.latency.entry(family).or_default().record(
+            timestamp_in_nano,
+            response_time_in_micro.ok().map(u64::from),
+        );
+        if let Ok(ping) = response_time_in_micro {
+            self.last_rtt.insert(family, ping);
+        }
+        self.history
+            .record((endpoint, timestamp_in_nano, response_time_in_micro));
+    }
+
+    /// Follows captive portal and interception results per target, returning a message for
+    /// the log when a target starts or stops being intercepted.
+    fn track_interception(&mut self, sample: Sample) -> Option<String> {
+        let (endpoint, timestamp, response) = sample;
+        let datetime = utils::timestamp_to_datetime(timestamp);
+        match response {
+            Err(failure) if failure.is_interception() => {
+                if self.intercepted.contains_key(&endpoint) {
+                    return None;
+                }
+                self.intercepted.insert(endpoint, (datetime, failure));
+                Some(format!("{} at {}", failure, self.target_name(endpoint)))
+            }
+            _ => self.intercepted.remove(&endpoint).map(|(start, failure)| {
+                self.logged.push(LoggedEvent::Interception {
+                    endpoint,
+                    start,
+                    end: datetime,
+                    failure,
+                });
+                format!(
+                    "{} no longer intercepted after {} seconds",
+                    self.target_name(endpoint),
+                    (datetime - start).num_milliseconds() as f32 / 1_000.0
+                )
+            }),
+        }
+    }
+
+    /// Feeds the outage detector with samples that tell whether the Internet is reachable,
+    /// keeping the outages and degraded providers that end for the timeouts log.
+    fn track_outages(&mut self, sample: Sample) -> Vec<(Family, outage::Event)> {
+        let (endpoint, _timestamp, response) = sample;
+        let events = match response {
+            _ if self.is_gateway(endpoint) => Vec::new(),
+            Err(failure) if failure.is_interception() => Vec::new(),
+            _ => self.outages.observe(sample),
+        };
+        self.log_events(&events);
+        events
+    }
+
+    /// Keeps the outages and degraded providers that ended for the timeouts log.
+    fn log_events(&mut self, events: &[(Family, outage::Event)]) {
+        for (family, event) in events {
+            match *event {
+                outage::Event::Started { .. } => {}
+                outage::Event::Ended { start, end } => {
+                    let start = utils::timestamp_to_datetime(start);
+                    let end = utils::timestamp_to_datetime(end);
+                    let (fault, evidence) = self.diagnose(*family, start, end);
+                    self.logged.push(LoggedEvent::Outage {
+                        family: *family,
+                        start,
+                        end,
+                        fault,
+                        failures: self.failures_between(*family, start, end),
+                        evidence,
+                    });
+                }
+                outage::Event::Degraded {
+                    endpoint,
+                    start,
+                    end,
+                } => self.logged.push(LoggedEvent::Degraded {
+                    endpoint,
+                    start: utils::timestamp_to_datetime(start),
+                    end: utils::timestamp_to_datetime(end),
+                }),
+            }
+        }
+    }
+
+    /// Feeds the brownout detectors the samples the outage detector gets, while their family is
+    /// up, keeping the brownouts that end for the timeouts log. An outage ends the brownout
+    /// under way when it starts.
+    fn track_brownouts(&mut self, sample: Sample) -> Vec<(Family, brownout::Event)> {
+        let (endpoint, _timestamp, response) = sample;
+        match response {
+            _ if self.is_gateway(endpoint) => return Vec::new(),
+            Err(failure) if failure.is_interception() => return Vec::new(),
+            _ => {}
+        }
+        let family = endpoint.family();
+        let Some(detector) = self.brownouts.get_mut(&family) else {
+            return Vec::new(); // no targets of that family are configured
+        };
+        let events = match self.outages.outage_start(family) {
+            Some(start) => detector.stopped(start).into_iter().collect(),
+            None => detector.observe(sample),
+        };
+        let events: Vec<_> = events.into_iter().map(|event| (family, event)).collect();
+        self.log_brownouts(&events);
+        events
+    }
+
+    /// Ends the brownouts under way at `at`, as contrac stops.
+    fn stop_brownouts(&mut self, at: u128) {
+        let events: Vec<_> = self
+            .brownouts
+            .iter_mut()
+            .filter_map(|(family, detector)| Some((*family, detector.stopped(at)?)))
+            .collect();
+        self.log_brownouts(&events);
+    }
+
+    /// Keeps the brownouts that ended for the timeouts log.
+    fn log_brownouts(&mut self, events: &[(Family, brownout::Event)]) {
+        for (family, event) in events {
+            if let brownout::Event::Ended(brownout) = event {
+                self.logged.push(LoggedEvent::Brownout {
+                    family: *family,
+                    start: utils::timestamp_to_datetime(brownout.start),
+                    end: utils::timestamp_to_datetime(brownout.end),
+                    peak: brownout.peak,
+                });
+            }
+        }
+    }
+
+    /// Traces the route to `target` in the background, unless a trace is already running.
+    fn start_trace(&mut self, target: IpAddr) {
+        if self.tracing {
+            return;
+        }
+        self.tracing = true;
+        probe::spawn_trace(target, self.traces_sender.clone());
+    }
+
+    /// Keeps the traces that have finished and returns a message for the log about each.
+    fn collect_traces(&mut self) -> Vec<String> {
+        let mut messages = Vec::new();
+        while let Ok(result) = self.traces_receiver.try_recv() {
+            self.tracing = false;
+            match result {
+                Ok(trace) => {
+                    messages.push(trace.to_string());
+                    self.traces.push(trace);
+                }
+                Err(err) => messages.push(format!("unable to trace route: {:#}", err)),
+            }
+        }
+        messages
+    }
+
+    /// Matches late and duplicate replies up with the samples they belong to.
+    fn collect_strays(&mut self) {
+        while let Ok(stray) = self.strays_receiver.try_recv() {
+            if let Some(kind) = self.quality.record_stray(stray) {
+                self.strays.push((stray, kind));
+            }
+        }
+        // only the samples report uses them, for the samples still kept
+        if let Some((_endpoint, oldest, _result)) = self.history.samples().front() {
+            self.strays
+                .retain(|(stray, _kind)| stray.timestamp >= *oldest);
+        }
+    }
+
+    /// Loss and jitter across all targets over the quality window.
+    fn quality_message(&self) -> String {
+        let quality = self.quality.overall();
+        format!(
+            "loss {:.1}% jitter {:.1} ms",
+            quality.loss_percent(),
+            quality.jitter
+        )
+    }
+
+    /// The trace taken when the outage from `start` to `end` began, if it finished.
+    fn trace_between(&self, start: DateTime<Local>, end: DateTime<Local>) -> Option<&Trace> {
+        self.traces
+            .iter()
+            .find(|trace| (start..=end).contains(&utils::timestamp_to_datetime(trace.taken)))
+    }
+
+    /// The different ways the targets of `family` failed from `start` to `end`, as far as the
+    /// samples still kept go back.
+    fn failures_between(
+        &self,
+        family: Family,
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+    ) -> Vec<Failure> {
+        let mut failures = Vec::new();
+        for (endpoint, timestamp, result) in self.history.samples() {
+            let during = (start..=end).contains(&utils::timestamp_to_datetime(*timestamp));
+            if let Err(failure) = result {
+                if during && endpoint.family() == family && !failures.contains(failure) {
+                    failures.push(*failure);
+                }
+            }
+        }
+        failures
+    }
+
+    /// Attributes the outage of `family` from `start` to `end` using how its targets failed and
+    /// the gateway samples taken during it, then narrows it down by which targets still
+    /// answered, returning the fault with the evidence for it.
+    fn diagnose(
+        &self,
+        family: Family,
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+    ) -> (Fault, Evidence) {
+        let failures = self.failures_between(family, start, end);
+        let time =
+            |(_endpoint, timestamp, _ping): &&Sample| utils::timestamp_to_datetime(*timestamp);
+        let mut gateway: Vec<_> = self
+            .gateway_history
+            .samples()
+            .iter()
+            .filter(|sample| (start..=end).contains(&time(sample)))
+            .map(|(_endpoint, _timestamp, ping)| ping)
+            .collect();
+        if gateway.is_empty() {
+            // outages shorter than the gateway interval: go by the last gateway sample before
+            // the end
+            gateway.extend(
+                self.gateway_history
+                    .samples()
+                    .iter()
+                    .rev()
+                    .find(|sample| time(sample) <= end)
+                    .map(|(_endpoint, _timestamp, ping)| ping),
+            );
+        }
+        let mut evidence = Evidence::default();
+        for result in &gateway {
+            evidence.gateway.record(result);
+        }
+        // the sample that ended the outage answered after it was over
+        for (endpoint, _timestamp, result) in self
+            .history
+            .samples()
+            .iter()
+            .filter(|sample| (start..end).contains(&time(sample)))
+        {
+            if endpoint.family() != family
+                || matches!(result, Err(failure) if failure.is_interception())
+            {
+                continue;
+            }
+            let index = match evidence
+                .targets
+                .iter()
+                .position(|target| target.endpoint == *endpoint)
+            {
+                Some(index) => index,
+                None => {
+                    evidence.targets.push(TargetTally {
+                        endpoint: *endpoint,
+                        tally: Tally::default(),
+                    });
+                    evidence.targets.len() - 1
+                }
             };
-            self.total += ping as u32;
+            evidence.targets[index].tally.record(result);
+        }
+        let fault = Fault::attribute(gateway, &failures).classify(&evidence);
+        (fault, evidence)
+    }
+
+    fn log_directory() -> PathBuf {
+        // headless Linux machines often have no XDG documents folder
+        UserDirs::new()
+            .and_then(|dirs| dirs.document_dir().map(|dir| dir.to_path_buf()))
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    /// Writes the samples still kept and the events so far in `format` next to the logs.
+    /// Returns the files written.
+    #[cfg_attr(not(windows), allow(dead_code))] // exported from the tray menu
+    fn export(&self, format: export::Format) -> Result<Vec<PathBuf>> {
+        let path = |what| {
+            Self::log_directory().join(format!(
+                "contrac {} {}.{}",
+                &self.log_identifier,
+                what,
+                format.extension()
+            ))
+        };
+        let create = |path: &Path| {
+            File::create(path)
+                .map(std::io::BufWriter::new)
+                .context(format!("unable to open '{}'", path.display()))
+        };
+        let mut samples: Vec<Sample> = self
+            .history
+            .samples()
+            .iter()
+            .chain(self.gateway_history.samples())
+            .copied()
+            .collect();
+        samples.sort_by_key(|(_endpoint, timestamp, _result)| *timestamp);
+        let samples_path = path("samples");
+        export::write_samples(create(&samples_path)?, format, &samples, &self.target_names)?;
+        let events: Vec<_> = self
+            .logged
+            .iter()
+            .map(|event| (event, self.trace_of(event)))
+            .collect();
+        let outages_path = path("outages");
+        export::write_outages(create(&outages_path)?, format, &events, &self.target_names)?;
+        Ok(vec![samples_path, outages_path])
+    }
+
+    /// The trace taken when an outage began, if it is an outage and one finished.
+    fn trace_of(&self, event: &LoggedEvent) -> Option<&Trace> {
+        match event {
+            LoggedEvent::Outage { start, end, .. } => self.trace_between(*start, *end),
+            _ => None,
+        }
+    }
+
+    fn timeouts_log_path(&self) -> PathBuf {
+        Self::log_directory().join(format!("contrac {} timeouts.log", &self.log_identifier))
+    }
+
+    /// Appends the events logged since the last append to the timeouts log. An outage waits
+    /// for the trace taken when it began, which may finish after the outage did. After a
+    /// failure, nothing is tried for `TIMEOUTS_RETRY_MINS`, and the events wait until then.
+    fn append_timeouts_log(&mut self) -> Result<()> {
+        if let Some(failed) = self.timeouts_failed {
+            if Local::now() < failed + Duration::minutes(TIMEOUTS_RETRY_MINS) {
+                return Ok(());
+            }
         }
-        self.samples
-            .push_back((address, timestamp_in_nano, response_time_in_milli));
+        let ready: Vec<_> = self.logged[self.written_events..]
+            .iter()
+            .take_while(|event| !(self.tracing && matches!(event, LoggedEvent::Outage { .. })))
+            .collect();
+        let lines: String = ready
+            .iter()
+            .map(|event| format!("{}\r\n", self.timeouts_line(event)))
+            .collect();
+        let count = ready.len();
+        match append_lines(&self.timeouts_log_path(), &lines) {
+            Ok(()) => {
+                self.written_events += count;
+                self.timeouts_failed = None;
+                Ok(())
+            }
+            Err(err) => {
+                self.timeouts_failed = Some(Local::now());
+                Err(err)
+            }
+        }
+    }
+
+    /// Appends what is left to the timeouts log as contrac stops, followed by the outages,
+    /// brownouts, degraded providers and interceptions still under way, marked as ongoing
+    /// since they may well have gone on afterwards.
+    fn close_timeouts_log(&mut self) -> Result<()> {
+        // no waiting for traces any more
+        self.tracing = false;
+        self.timeouts_failed = None;
+        self.append_timeouts_log()?;
+        let now = Local::now();
+        let timestamp = utils::datetime_to_timestamp(&now);
+        let events = self.outages.stopped(timestamp);
+        self.log_events(&events);
+        self.stop_brownouts(timestamp);
+        self.end_interceptions(now);
+        let lines: String = self.logged[self.written_events..]
+            .iter()
+            .map(|event| format!("{}, ongoing\r\n", self.timeouts_line(event)))
+            .collect();
+        append_lines(&self.timeouts_log_path(), &lines)?;
+        self.written_events = self.logged.len();
+        Ok(())
     }
 
-    fn sort_samples(&mut self) {
-        self.samples
-            .make_contiguous()
-            .sort_by(|(_aa, at, _ap), (_ba, bt, _bp)| at.cmp(bt));
+    /// The line for `event` in the timeouts log, without the line ending.
+    fn timeouts_line(&self, event: &LoggedEvent) -> String {
+        match event {
+            // interception spans are logged on their own; the connection itself was up
+            LoggedEvent::Interception {
+                endpoint,
+                start,
+                end,
+                failure,
+            } => {
+                let duration = (*end - *start).num_milliseconds() as f32 / 1_000.0;
+                format!(
+                    "{}, {}, {}, {}, {}",
+                    start, end, duration, failure, endpoint
+                )
+            }
+            LoggedEvent::Outage {
+                family,
+                start,
+                end,
+                fault,
+                failures,
+                evidence,
+            } => {
+                let offline_duration = (*end - *start).num_milliseconds() as f32 / 1_000.0;
+                // if offline_duration < 1.0 { continue; }  // uncomment to ignore small duration timeouts
+                let failures: Vec<_> = failures.iter().map(Failure::to_string).collect();
+                let failures = failures.join("; ");
+                // the trace may have finished after the outage did
+                match self.trace_between(*start, *end) {
+                    Some(trace) => format!(
+                        "{}, {}, {}, {}, {}, {}, evidence: {}, {}",
+                        start, end, offline_duration, fault, family, failures, evidence, trace
+                    ),
+                    None => format!(
+                        "{}, {}, {}, {}, {}, {}, evidence: {}",
+                        start, end, offline_duration, fault, family, failures, evidence
+                    ),
+                }
+            }
+            LoggedEvent::Degraded {
+                endpoint,
+                start,
+                end,
+            } => {
+                let duration = (*end - *start).num_milliseconds() as f32 / 1_000.0;
+                format!(
+                    "{}, {}, {}, provider degraded, {}",
+                    start, end, duration, endpoint
+                )
+            }
+            LoggedEvent::Brownout {
+                family,
+                start,
+                end,
+                peak,
+            } => {
+                let duration = (*end - *start).num_milliseconds() as f32 / 1_000.0;
+                format!(
+                    "{}, {}, {}, brownout, {}, {}, {}",
+                    start,
+                    end,
+                    duration,
+                    family,
+                    peak.severity(),
+                    peak
+                )
+            }
+            LoggedEvent::NotRunning { start, end } => {
+                let duration = (*end - *start).num_milliseconds() as f32 / 1_000.0;
+                format!("{}, {}, {}, not running", start, end, duration)
+            }
+        }
     }
 }
 
+/// A finished outage, brownout, degraded provider or interception, or a time contrac was not
+/// running, as it goes in the timeouts log.
+enum LoggedEvent {
+    Outage {
+        family: Family,
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+        fault: Fault,
+        failures: Vec<Failure>,
+        /// How the gateway and each target answered, which the fault was judged by.
+        evidence: Evidence,
+    },
+    Degraded {
+        endpoint: Endpoint,
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+    },
+    /// Latency or loss well above usual for the family, short of an outage.
+    Brownout {
+        family: Family,
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+        peak: brownout::Peak,
+    },
+    Interception {
+        endpoint: Endpoint,
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+        failure: Failure,
+    },
+    NotRunning {
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+    },
+}
+
+impl LoggedEvent {
+    /// When it started and ended.
+    fn span(&self) -> (DateTime<Local>, DateTime<Local>) {
+        match self {
+            LoggedEvent::Outage { start, end, .. }
+            | LoggedEvent::Degraded { start, end, .. }
+            | LoggedEvent::Brownout { start, end, .. }
+            | LoggedEvent::Interception { start, end, .. }
+            | LoggedEvent::NotRunning { start, end } => (*start, *end),
+        }
+    }
+}
+
+#[cfg(windows)]
 const _PAD_5: Rect<D> = Rect {
     start: D::Points(10.0),
     end: D::Points(10.0),
     top: D::Points(10.0),
     bottom: D::Points(10.0),
 };
+#[cfg(windows)]
 const PAD_2: Rect<D> = Rect {
     start: D::Points(2.0),
     end: D::Points(2.0),
     top: D::Points(2.0),
     bottom: D::Points(2.0),
 };
+#[cfg(windows)]
 const PAD_SHRINK_1: Rect<D> = Rect {
     start: D::Points(-1.0),
     end: D::Points(-1.0),
@@ -131,6 +1043,7 @@ const PAD_SHRINK_1: Rect<D> = Rect {
     bottom: D::Points(-1.0),
 };
 
+#[cfg(windows)]
 const PAD_SHRINK_LEFT: Rect<D> = Rect {
     start: D::Points(-1.0),
     end: D::Points(0.0),
@@ -139,6 +1052,7 @@ const PAD_SHRINK_LEFT: Rect<D> = Rect {
 };
 
 
+#[cfg(windows)]
 #[derive(Default, NwgUi)]
 pub struct App {
     data: RefCell<AppData>,
@@ -168,6 +1082,14 @@ pub struct App {
     #[nwg_events(OnMenuItemSelected: [App::on_save_report_menu_item_selected])]
     tray_item1: nwg::MenuItem,
 
+    #[nwg_control(parent: tray_menu, text: "Export CSV")]
+    #[nwg_events(OnMenuItemSelected: [App::on_export_csv_menu_item_selected])]
+    tray_item2: nwg::MenuItem,
+
+    #[nwg_control(parent: tray_menu, text: "Export JSON Lines")]
+    #[nwg_events(OnMenuItemSelected: [App::on_export_json_lines_menu_item_selected])]
+    tray_item3: nwg::MenuItem,
+
     // Main UX
     #[nwg_layout(parent: window, auto_spacing: None, flex_direction: FlexDirection::Column, justify_content: JustifyContent::Center)]
     main_layout: nwg::FlexboxLayout,
@@ -204,10 +1126,14 @@ pub struct App {
     #[nwg_layout(parent: status_frame, auto_spacing: None, flex_direction: FlexDirection::Row, align_items: AlignItems::Center, justify_content: JustifyContent::FlexEnd)]
     status_layout: nwg::FlexboxLayout,
 
-    #[nwg_control(parent: status_frame, text: "Reset Stats")]
+    #[nwg_control(parent: status_frame, text: "Stats over")]
+    #[nwg_layout_item(layout: status_layout, margin: PAD_2, size: Size { width: D::Points(80.0), height: D::Points(25.0) },)]
+    window_label: nwg::Label,
+
+    #[nwg_control(parent: status_frame, collection: rolling::Window::ALL.to_vec())]
     #[nwg_layout_item(layout: status_layout,  margin: PAD_2, min_size: Size { width: D::Points(150.0), height: D::Points(40.0) },)]
-    #[nwg_events( OnButtonClick: [App::on_reset_click] )]
-    reset_button: nwg::Button,
+    #[nwg_events( OnComboxBoxSelection: [App::on_window_select] )]
+    window_select: nwg::ComboBox<rolling::Window>,
 
     #[nwg_control(parent: status_frame, focus: true, text: "Close")]
     #[nwg_layout_item(layout: status_layout, margin: PAD_2, size: Size { width: D::Points(150.0), height: D::Points(40.0) },)]
@@ -222,6 +1148,7 @@ pub struct App {
     message: nwg::StatusBar,
 }
 
+#[cfg(windows)]
 impl App {
     fn on_window_init(&self) {
         self.log.set_text("Starting");
@@ -230,10 +1157,12 @@ impl App {
             self.app_log_write(&format!("Registry loading issue: {}", e));
         }
         self.data.borrow_mut().registry_loaded = true;
-        let (min, max) = {
+        let (min, max, window) = {
             let data = self.data.borrow();
-            (data.graph_min, data.graph_max)
+            (data.graph_min, data.graph_max, data.status_window)
         };
+        self.window_select
+            .set_selection(rolling::Window::ALL.iter().position(|each| *each == window));
         self.message.set_min_height(25); // not settable above
         self.log.set_readonly(true); // not settable above
         self.graph.init(GRAPH_BAR_COUNT, min, max);
@@ -259,12 +1188,17 @@ impl App {
     }
 
 
-    fn on_reset_click(&self) {
-        let mut data = self.data.borrow_mut();
-        data.count = 0;
-        data.total = 0;
-        data.min = u16::MAX;
-        data.max = 0;
+    fn on_window_select(&self) {
+        let selected = self
+            .window_select
+            .selection()
+            .map(|index| rolling::Window::ALL[index]);
+        if let Some(window) = selected {
+            self.data.borrow_mut().status_window = window;
+            if let Err(e) = self.registry_settings_save() {
+                self.app_log_write(&format!("Registry saving issue: {:?}", e))
+            };
+        }
     }
 
     fn on_close_button(&self) {
@@ -273,7 +1207,10 @@ impl App {
 
 
     fn on_window_close(&self) {
-        self.write_timeouts_log().unwrap();
+        let closed = self.data.borrow_mut().close_timeouts_log();
+        if let Err(err) = closed {
+            self.app_log_write(&format!("error saving timeouts log {:#}", err));
+        }
         nwg::stop_thread_dispatch();
     }
 
@@ -296,23 +1233,29 @@ impl App {
                 done = true;
             }
         }
+        self.data.borrow_mut().collect_strays();
+        let traces = self.data.borrow_mut().collect_traces();
+        for message in traces {
+            self.app_log_write(&message);
+        }
+
+        self.write_timeouts_log();
 
         let datetime = Local::now();
         {
             let mut data = self.data.borrow_mut();
             if datetime > (data.last_full_update + Duration::milliseconds(GRAPH_REFRESH_MILLIS)) {
-                data.sort_samples();
-                self.graph.set_values(&data.samples);
+                // the graph follows one family; the status bar has both
+                let family = data.outages.families().next();
+                if let Some(family) = family {
+                    let gaps = data.history.gaps().clone();
+                    let seconds = data.history.seconds_of(family);
+                    self.graph.set_values(seconds, &gaps);
+                }
                 self.graph.on_resize();
                 data.last_full_update = datetime;
             }
         }
-
-        if (self.data.borrow().last_saved + Duration::minutes(AUTO_SAVE_MINS)) < datetime {
-            if let Err(err) = self.write_timeouts_log() {
-                self.app_log_write(&format!("{}", err));
-            }
-        }
     }
 
     fn on_save_report_menu_item_selected(&self) {
@@ -320,6 +1263,27 @@ impl App {
         self.display_notification("Report saved");
     }
 
+    fn on_export_csv_menu_item_selected(&self) {
+        self.export(export::Format::Csv);
+    }
+
+    fn on_export_json_lines_menu_item_selected(&self) {
+        self.export(export::Format::JsonLines);
+    }
+
+    fn export(&self, format: export::Format) {
+        let result = self.data.borrow().export(format);
+        match result {
+            Ok(paths) => {
+                for path in paths {
+                    self.app_log_write(&format!("Exported {}", path.display()));
+                }
+                self.display_notification("Export saved");
+            }
+            Err(err) => self.app_log_write(&format!("Export failed: {:#}", err)),
+        }
+    }
+
     fn on_tray_mouse_press_left_up(&self) {
         self.window.set_visible(true);
         self.window.restore();
@@ -334,47 +1298,58 @@ impl App {
         {
             let mut data = self.data.borrow_mut();
             data.record_observation(sample);
-            let (_dst, timestamp, ping_response) = sample;
-            if let Some(rtt) = ping_response {
-                if data.last_sample_display_timeout_notification {
-                    self.app_log_write(&format!(
-                        "was disconnected for {} seconds",
-                        (utils::timestamp_to_datetime(timestamp as u128)
-                            - data.timeout_start.unwrap())
-                        .num_milliseconds() as f32
-                            / 1_000 as f32
-                    ));
+            for message in data.save_sample(sample) {
+                self.app_log_write(&message);
+            }
+            if let Some(message) = data.track_interception(sample) {
+                self.app_log_write(&message);
+            }
+            for (family, event) in data.track_outages(sample) {
+                match event {
+                    outage::Event::Started { start } => {
+                        data.timeout_start
+                            .insert(family, utils::timestamp_to_datetime(start));
+                        data.start_trace(sample.0.address);
+                    }
+                    outage::Event::Ended { start, end } => {
+                        if data.timeout_notified.remove(&family) {
+                            self.app_log_write(&data.outage_message(family, start, end));
+                        }
+                        data.timeout_start.remove(&family);
+                    }
+                    outage::Event::Degraded {
+                        endpoint,
+                        start,
+                        end,
+                    } => self.app_log_write(&format!(
+                        "{} degraded for {} seconds",
+                        data.target_name(endpoint),
+                        (end - start) as f32 / 1_000_000_000 as f32
+                    )),
                 }
-                data.last_sample_display_timeout_notification = false;
-                data.timeout_start = None;
-                let message = format!(
-                    "{} ms ({}:{}) {:.1}",
-                    rtt,
-                    data.min,
-                    data.max,
-                    data.average(),
-                );
-                self.message.set_text(0, &message);
-            } else {
-                self.message.set_text(0, "Disconnected");
-                let datetime = utils::timestamp_to_datetime(timestamp as u128);
-                if data.last_sample_display_timeout_notification == false
-                    && data.timeout_start.is_some()
+            }
+            for (family, event) in data.track_brownouts(sample) {
+                self.app_log_write(&data.brownout_message(family, event));
+            }
+            let (dst, timestamp, _ping_response) = sample;
+            if data.is_gateway(dst) {
+                return;
+            }
+            self.message.set_text(0, &data.status_message());
+            let datetime = utils::timestamp_to_datetime(timestamp as u128);
+            let families: Vec<_> = data.timeout_start.clone().into_iter().collect();
+            for (family, timeout_start) in families {
+                if !data.timeout_notified.contains(&family)
                     && datetime
-                        > (data.timeout_start.unwrap()
-                            + Duration::milliseconds(MIN_TIMEOUT_INTERVAL_MILLIS))
+                        > (timeout_start + Duration::milliseconds(MIN_TIMEOUT_INTERVAL_MILLIS))
                 {
-                    self.display_notification("Disconnected");
-                    data.last_sample_display_timeout_notification = true;
-                }
-                if let None = data.timeout_start {
-                    data.timeout_start = Some(datetime);
+                    self.display_notification(&data.disconnected_message(family));
+                    data.timeout_notified.insert(family);
                 }
             }
         }
     }
 
-
     fn app_log_write(&self, message: &str) {
         let mut text = self.log.text();
         text.push_str(&format!(
@@ -395,6 +1370,10 @@ impl App {
         let mut data = self.data.borrow_mut();
         data.graph_min = min as u16;
         data.graph_max = max as u16;
+        // chosen here, it takes over from the configuration file
+        if let Ok(window) = subkey.get_value::<String, _>("StatusWindow") {
+            data.status_window = window.parse()?;
+        }
         Ok(())
     }
 
@@ -411,6 +1390,7 @@ impl App {
             let data = self.data.borrow();
             if let Err(e) = subkey.set_value("GraphMin", &(data.graph_min as u32)) { bail!("write error {}", e)};
             if let Err(e) = subkey.set_value("GraphMax", &(data.graph_max as u32)) { bail! ("write error {}", e)};
+            if let Err(e) = subkey.set_value("StatusWindow", &data.status_window.to_string()) { bail!("write error {}", e)};
         }
         Ok(())
     }
@@ -419,76 +1399,53 @@ impl App {
 
     fn write_samples_log(&self) {
         self.app_log_write("Saving samples");
-        {
-            let mut data = self.data.borrow_mut();
-            data.sort_samples();
-        }
-
         let data = self.data.borrow();
         let mut file = File::create(format!("{} samples.log", &data.log_identifier))
             .expect("file create failed");
 
-        for (address, time, rtt) in &data.samples {
-            let date_time = utils::timestamp_to_datetime(*time);
-            let result = if let Some(rtt) = rtt {
-                rtt.to_string()
-            } else {
-                String::from("timeout")
+        // replay the samples so each line has the loss and jitter as they stood at the time
+        let mut quality = Quality::default();
+        for &(endpoint, time, rtt) in data.history.samples() {
+            quality.record((endpoint, time, rtt));
+            let mut notes = Vec::new();
+            for (stray, kind) in data
+                .strays
+                .iter()
+                .filter(|(stray, _kind)| stray.endpoint == endpoint && stray.timestamp == time)
+            {
+                quality.record_stray(*stray);
+                notes.push(match kind {
+                    StrayKind::Late => format!("late {:.3}", utils::millis(stray.rtt)),
+                    StrayKind::Duplicate => "duplicate".to_string(),
+                });
+            }
+            let summary = quality.summary(endpoint);
+            let date_time = utils::timestamp_to_datetime(time);
+            // milliseconds as before, now with microsecond decimals
+            let result = match rtt {
+                Ok(rtt) => format!("{:.3}", utils::millis(rtt)),
+                Err(failure) => failure.to_string(),
             };
-            let message = format!("{:0}, {}, {}\r\n", date_time, result, address);
+            let message = format!(
+                "{:0}, {}, {}, {:.1}, {:.2}, {}\r\n",
+                date_time,
+                result,
+                endpoint,
+                summary.loss_percent(),
+                summary.jitter,
+                notes.join(" ")
+            );
             let message = message.as_bytes();
             file.write_all(message).unwrap();
         }
         file.sync_all().expect("file sync failed");
     }
 
-    fn write_timeouts_log(&self) -> Result<()> {
-        match || -> Result<()> {
-            // use closure to capture errors and enable '?' syntax
-            enum TimeoutTracker {
-                Active { start: DateTime<Local> },
-                Nominal,
-            }
-            let data = self.data.borrow();
-            let path = UserDirs::new()
-                .unwrap()
-                .document_dir()
-                .unwrap()
-                .join(format!("contrac {} timeouts.log", &data.log_identifier));
-            let full_path = String::from(path.to_str().unwrap());
-            // self.app_log_write(&format!("writing to {}", full_path));
-            let mut file = File::create(&path) // was format!("{} timeouts.log", documents.join(path: P), &data.log_identifier))
-                .context(format!("unable to open '{:?}'", &full_path))?;
-            let mut timeout_status = TimeoutTracker::Nominal;
-            for (_address, time, rtt) in &data.samples {
-                if rtt.is_some() {
-                    if let TimeoutTracker::Active { start } = timeout_status {
-                        let end = utils::timestamp_to_datetime(*time);
-                        let offline_duration = (end - start).num_milliseconds() as f32 / 1_000.0;
-                        timeout_status = TimeoutTracker::Nominal;
-                        // if offline_duration < 1.0 { continue; }  // uncomment to ignore small duration timeouts
-                        let message = format!("{}, {}, {}\r\n", start, end, offline_duration);
-                        file.write_all(message.as_bytes())
-                            .context(format!("write failed"))?;
-                    } else {
-                        continue;
-                    }
-                } else {
-                    if let TimeoutTracker::Active { start: _ } = timeout_status {
-                        continue;
-                    } else {
-                        timeout_status = TimeoutTracker::Active {
-                            start: utils::timestamp_to_datetime(*time),
-                        }
-                    }
-                }
-            }
-            Ok(())
-        }() {
-            Ok(_) => self.data.borrow_mut().last_saved = Local::now(),
-            Err(err) => self.app_log_write(&format!("error saving timesout log {:#?}", err)),
-        };
-        Ok(())
+    fn write_timeouts_log(&self) {
+        let result = self.data.borrow_mut().append_timeouts_log();
+        if let Err(err) = result {
+            self.app_log_write(&format!("error saving timeouts log {:#}", err));
+        }
     }
 
  
@@ -499,49 +1456,446 @@ impl App {
             .show("Status", Some(message), Some(flags), Some(&self.icon));
     }
 
-    pub fn spawn_pinger(&self, address: &str, delay_millis: u32) -> Result<thread::JoinHandle<()>> {
-        let sender = self.data.borrow().samples_sender.clone();
-        let dst = String::from(address)
-            .parse::<IpAddr>()
-            .context("Could not parse IP Address")?;
-        let handle = thread::spawn(move || {
-            let pinger = Pinger::new().unwrap();
-            let mut buffer = Buffer::new();
-            loop {
-                let ping_response;
-                let timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos();
-                match pinger.send(dst, &mut buffer) {
-                    Ok(rtt) => {
-                        ping_response = Some(rtt as u16);
-                    }
-                    Err(_err) => {
-                        ping_response = None;
-                    }
-                };
-                if let Err(_err) = sender.send((dst, timestamp, ping_response)) {
-                    break; // stop the loop if there is an error.
-                }
-                #[allow(deprecated)]
-                thread::sleep_ms(delay_millis);
+    pub fn spawn_pingers(&self, probers: TargetProbers) -> Vec<thread::JoinHandle<()>> {
+        self.data.borrow_mut().spawn_probers(probers)
+    }
+}
+
+type TargetProbers = Vec<(config::Target, Box<dyn Prober>)>;
+
+/// Appends `lines` to the file at `path` in a single write, so that an interruption leaves
+/// whole lines, and makes sure they reach the disk.
+fn append_lines(path: &Path, lines: &str) -> Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("unable to open '{}'", path.display()))?;
+    file.write_all(lines.as_bytes())
+        .and_then(|_| file.sync_data())
+        .context(format!("unable to append to '{}'", path.display()))
+}
+
+/// The value following `name` on the command line, if `name` is there.
+fn argument(name: &str) -> Result<Option<String>> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().position(|arg| arg == name) {
+        Some(index) => match args.get(index + 1) {
+            Some(value) => Ok(Some(value.clone())),
+            None => bail!("{} needs a value", name),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Options that run contrac from the command line instead of opening its window.
+#[cfg(windows)]
+const COMMAND_LINE_MODES: [&str; 4] = ["--query", "--import", "--export", "--report"];
+
+/// Takes over the console of the command prompt contrac was started from when it is run from
+/// the command line, since a GUI program starts without one and what it prints would be lost.
+#[cfg(windows)]
+fn attach_console() {
+    use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+    if std::env::args().any(|arg| COMMAND_LINE_MODES.contains(&arg.as_str())) {
+        // fails when there is no console to take over, e.g. when started from Explorer; output
+        // redirected to a file goes there either way
+        unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+    }
+}
+
+/// The values following `name` on the command line, up to the next option, if `name` is there.
+fn arguments(name: &str) -> Option<Vec<String>> {
+    let args: Vec<String> = std::env::args().collect();
+    let index = args.iter().position(|arg| arg == name)?;
+    let values = args[index + 1..]
+        .iter()
+        .take_while(|arg| !arg.starts_with("--"))
+        .cloned()
+        .collect();
+    Some(values)
+}
+
+/// The date following `name` on the command line, if `name` is there.
+fn date_argument(name: &str) -> Result<Option<chrono::NaiveDate>> {
+    argument(name)?
+        .map(|text| chrono::NaiveDate::parse_from_str(&text, "%Y-%m-%d"))
+        .transpose()
+        .context(format!("{} needs a date like 2024-01-31", name))
+}
+
+/// Loads the configuration, from `--config <path>` if given, and creates a prober for each target.
+/// Also returns where the database goes, if there is one, and the status bar's window.
+fn load_probers() -> Result<(
+    outage::DualStack,
+    TargetProbers,
+    Option<PathBuf>,
+    rolling::Window,
+)> {
+    let path = argument("--config")?.map(PathBuf::from);
+    let config = config::load(path.as_deref())?;
+    // a relative path is taken to be in the log directory
+    let database = config
+        .database
+        .map(|path| AppData::log_directory().join(path));
+    let probers = config
+        .targets
+        .into_iter()
+        .map(|target| {
+            let prober = target.prober()?;
+            Ok((target, prober))
+        })
+        .collect::<Result<TargetProbers>>()?;
+    config::distinct_endpoints(
+        probers
+            .iter()
+            .map(|(target, prober)| (target.name.as_str(), prober.endpoint())),
+    )?;
+    let endpoints = probers
+        .iter()
+        .filter(|(target, _prober)| !target.gateway)
+        .map(|(_target, prober)| prober.endpoint());
+    let outages = outage::DualStack::for_targets(config.quorum, config.quorum_window, endpoints)
+        .context("invalid configuration")?;
+    Ok((outages, probers, database, config.status_window))
+}
+
+/// Prints what the database holds for `--query outages` or `--query samples`, narrowed down by
+/// `--from` and `--to` dates, `--host`, `--target`, `--outcome` and `--longer-than` seconds.
+#[cfg(feature = "sqlite")]
+fn query(what: &str) -> Result<()> {
+    let path =
+        database_path()?.context("no database is configured, so give one with --database")?;
+    if !path.exists() {
+        bail!("there is no database at '{}'", path.display());
+    }
+    let longer_than = argument("--longer-than")?
+        .map(|text| text.parse().map(std::time::Duration::from_secs_f64))
+        .transpose()
+        .context("--longer-than needs a number of seconds")?;
+    let query = store::Query {
+        from: date_argument("--from")?.map(utils::start_of_day),
+        // the whole of the last day
+        to: date_argument("--to")?
+            .and_then(|date| date.succ_opt())
+            .map(utils::start_of_day),
+        host: argument("--host")?,
+        target: argument("--target")?,
+        outcome: argument("--outcome")?
+            .map(|text| text.parse())
+            .transpose()
+            .context("--outcome needs ok, failed or a failure such as timeout")?,
+        longer_than,
+    };
+    let store = store::Store::open(&path)?;
+    match what {
+        "outages" => store
+            .outages(&query)?
+            .iter()
+            .for_each(|outage| println!("{}", outage)),
+        "samples" => store
+            .samples(&query)?
+            .iter()
+            .for_each(|sample| println!("{}", sample)),
+        _ => bail!("--query takes outages or samples, not '{}'", what),
+    }
+    Ok(())
+}
+
+/// The database given with `--database`, or else the configured one.
+#[cfg(feature = "sqlite")]
+fn database_path() -> Result<Option<PathBuf>> {
+    if let Some(path) = argument("--database")? {
+        return Ok(Some(PathBuf::from(path)));
+    }
+    let config = argument("--config")?.map(PathBuf::from);
+    let database = config::load(config.as_deref())?.database;
+    Ok(database.map(|path| AppData::log_directory().join(path)))
+}
+
+/// Reads the samples and timeouts logs given to `--import`, listing the lines that could not
+/// be read, and stores what was read in the database if there is one.
+fn run_import() -> Result<bool> {
+    let paths = match arguments("--import") {
+        Some(paths) if paths.is_empty() => bail!("--import needs the logs to read"),
+        Some(paths) => paths,
+        None => return Ok(false),
+    };
+    #[cfg(feature = "sqlite")]
+    let mut store = match database_path()? {
+        Some(path) => Some(store::Store::open(&path)?),
+        None => None,
+    };
+    let mut unread = 0;
+    for path in paths.iter().map(Path::new) {
+        let log = match import::read(path) {
+            Ok(log) => log,
+            Err(err) => {
+                eprintln!("{:#}", err);
+                unread += 1;
+                continue;
             }
-        });
-        Ok(handle)
+        };
+        let (read, malformed) = match &log {
+            import::Log::Samples(parsed) => (parsed.records.len(), &parsed.malformed),
+            import::Log::Timeouts(parsed) => (parsed.records.len(), &parsed.malformed),
+        };
+        let run = match import::identify(path) {
+            Some((host, started)) => format!(" from the run on {} at {}", host, started),
+            None => String::new(),
+        };
+        println!(
+            "{}: {} lines read{}, {} unreadable",
+            path.display(),
+            read,
+            run,
+            malformed.len()
+        );
+        for line in malformed {
+            println!("  {}", line);
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(opened) = store.take() {
+            let (opened, message) = store_log(opened, path, &log)?;
+            println!("  {}", message);
+            store = Some(opened);
+        }
+    }
+    if unread > 0 {
+        bail!("{} of the {} logs could not be read", unread, paths.len());
+    }
+    Ok(true)
+}
+
+/// Stores a log as part of the session of the run that wrote it, unless it is already stored.
+/// Returns the store for the next log, and a message saying what happened.
+#[cfg(feature = "sqlite")]
+fn store_log(
+    store: store::Store,
+    path: &Path,
+    log: &import::Log,
+) -> Result<(store::Store, String)> {
+    let first = match log {
+        import::Log::Samples(parsed) => parsed.records.first().map(|line| line.time),
+        import::Log::Timeouts(parsed) => parsed.records.first().map(|line| line.span().0),
+    };
+    // runs are named after the host and when they started, which older files can lack
+    let (host, started) = match (import::identify(path), first) {
+        (Some(run), _) => run,
+        (None, Some(first)) => ("unknown".to_string(), first),
+        (None, None) => return Ok((store, "nothing to store".to_string())),
+    };
+    let mut session = store.import_session(&host, utils::datetime_to_timestamp(&started))?;
+    let stored = match log {
+        import::Log::Samples(parsed) if !session.has_samples()? => session.batch(|session| {
+            parsed
+                .records
+                .iter()
+                .try_for_each(|line| session.record_sample(line.sample()))
+        }),
+        import::Log::Timeouts(parsed) if !session.has_outages()? => session.batch(|session| {
+            parsed
+                .records
+                .iter()
+                .try_for_each(|line| session.record_line(line))
+        }),
+        _ => {
+            let message = format!("already stored for {} from {}", host, started);
+            return Ok((session.close(), message));
+        }
+    };
+    stored.context(format!("unable to store '{}'", path.display()))?;
+    let message = format!("stored for {} from {}", host, started);
+    Ok((session.close(), message))
+}
+
+/// Writes the samples or outages in the journal for `--export samples` or `--export outages`,
+/// as `--format csv` (the default) or `jsonl`, to `--output <file>` or the standard output.
+/// They cover the last day, or from the `--from` date to the end of the `--to` date.
+fn run_export() -> Result<bool> {
+    let what = match argument("--export")? {
+        Some(what) => what,
+        None => return Ok(false),
+    };
+    let format = argument("--format")?
+        .map(|text| text.parse())
+        .transpose()?
+        .unwrap_or(export::Format::Csv);
+    let now = utils::datetime_to_timestamp(&Local::now());
+    let from = date_argument("--from")?
+        .map(utils::start_of_day)
+        .unwrap_or_else(|| now.saturating_sub(RELOAD_WINDOW));
+    let to = date_argument("--to")?
+        .and_then(|date| date.succ_opt())
+        .map_or(u128::MAX, utils::start_of_day);
+    let path = argument("--output")?;
+    // only created once the rest of the command line has been checked
+    let output = || -> Result<Box<dyn Write>> {
+        Ok(match &path {
+            Some(path) => Box::new(std::io::BufWriter::new(
+                File::create(path).context(format!("unable to open '{}'", path))?,
+            )),
+            None => Box::new(std::io::stdout()),
+        })
+    };
+    let (outages, probers, _database, _window) = load_probers()?;
+    let mut data = AppData::default();
+    data.know_targets(&probers);
+    match what.as_str() {
+        "samples" => {
+            let directory = AppData::log_directory().join(JOURNAL_DIRECTORY);
+            let (mut samples, _gaps) = journal::read_since(&directory, from)?;
+            samples.retain(|(_endpoint, timestamp, _result)| *timestamp < to);
+            export::write_samples(output()?, format, &samples, &data.target_names)?;
+        }
+        "outages" => {
+            // the outages are found again from the samples, as when contrac starts
+            data.set_outages(outages);
+            data.reload(&probers, from, |_sample| {})?;
+            let events: Vec<_> = data
+                .logged
+                .iter()
+                .filter(|event| utils::datetime_to_timestamp(&event.span().0) < to)
+                .map(|event| (event, None))
+                .collect();
+            export::write_outages(output()?, format, &events, &data.target_names)?;
+        }
+        _ => bail!("--export takes samples or outages, not '{}'", what),
     }
+    Ok(true)
 }
 
+/// Prints the uptime report for `--report`, over the journal from the `--from` date (by
+/// default the start of last month) to the end of the `--to` date (by default today), counting
+/// the outages of `--family IPv4` or `IPv6` or else of either. The line is judged against the
+/// `[sla]` in the configuration, if there is one.
+fn run_report() -> Result<bool> {
+    if !std::env::args().any(|arg| arg == "--report") {
+        return Ok(false);
+    }
+    let today = Local::now().date_naive();
+    let last_month = today
+        .with_day(1)
+        .and_then(|first| first.pred_opt())
+        .unwrap_or(today);
+    let from = date_argument("--from")?.unwrap_or_else(|| last_month.with_day(1).unwrap());
+    let to = date_argument("--to")?.unwrap_or(today);
+    if to < from {
+        bail!("--to {} is before --from {}", to, from);
+    }
+    let family: Option<Family> = argument("--family")?.map(|text| text.parse()).transpose()?;
+    let config = argument("--config")?.map(PathBuf::from);
+    let sla = config::load(config.as_deref())?.sla;
+    let (outages, probers, _database, _window) = load_probers()?;
+    let mut data = AppData::default();
+    data.set_outages(outages);
+    let mut latency = uptime::Latency::default();
+    data.know_targets(&probers);
+    let gateway = data.gateway;
+    data.reload(&probers, utils::start_of_day(from), |sample| {
+        if Some(sample.0) != gateway {
+            latency.record(sample)
+        }
+    })?;
+    let covered = match data.outages.coverage() {
+        Some(covered) => covered,
+        None => bail!("the journal has no samples from {} on", from),
+    };
+    // an outage still under way at the end of the journal counts up to there
+    let ongoing = data.outages.stopped(covered.1);
+    data.log_events(&ongoing);
+    let timestamps = |event: &LoggedEvent| {
+        let (start, end) = event.span();
+        (
+            utils::datetime_to_timestamp(&start),
+            utils::datetime_to_timestamp(&end),
+        )
+    };
+    let outages = data.logged.iter().filter_map(|event| match event {
+        LoggedEvent::Outage { family: of, .. } if family.is_none() || family == Some(*of) => {
+            Some(timestamps(event))
+        }
+        _ => None,
+    });
+    let absent = data
+        .logged
+        .iter()
+        .filter(|event| matches!(event, LoggedEvent::NotRunning { .. }))
+        .map(timestamps);
+    let record = uptime::Record::new(outages, absent, covered);
+    let mut report = String::new();
+    uptime::write_report(&mut report, &record, from, to, sla.as_ref())?;
+    uptime::write_latency(&mut report, &latency, &data.target_names, from, to)?;
+    print!("{}", report);
+    Ok(true)
+}
+
+/// Runs a `--query` instead of tracking, if one was asked for.
+fn run_query() -> Result<bool> {
+    let what = match argument("--query")? {
+        Some(what) => what,
+        None => return Ok(false),
+    };
+    #[cfg(not(feature = "sqlite"))]
+    bail!(
+        "--query {} needs a build of contrac with the sqlite feature",
+        what
+    );
+    #[cfg(feature = "sqlite")]
+    {
+        query(&what)?;
+        Ok(true)
+    }
+}
+
+#[cfg(windows)]
 fn main() -> Result<()> {
+    attach_console();
+    if run_query()? || run_import()? || run_export()? || run_report()? {
+        return Ok(());
+    }
     nwg::init().context("Failed to init app")?;
     nwg::Font::set_global_family("Segoe UI").context("Failed to set default font")?;
-    let app = App::build_ui(Default::default()).context("Failed to build UI")?;
-    let _pingers = vec![
-        app.spawn_pinger("1.1.1.2", MIN_PING_TIME_MILLIS)?, // CloudFlare
-        app.spawn_pinger("8.8.8.8", MIN_PING_TIME_MILLIS)?, // Google
-        app.spawn_pinger("208.67.222.222", MIN_PING_TIME_MILLIS)?, // Cisco OpenDNS
-        app.spawn_pinger("9.9.9.9", MIN_PING_TIME_MILLIS)?, // Quad9
-    ];
+    let (outages, probers, database, window) = match load_probers() {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            // there is no console to report to
+            nwg::error_message("Connection Tracker", &format!("{:#}", err));
+            return Err(err);
+        }
+    };
+    let initial = App::default();
+    // before the window opens, so a choice saved in the registry wins
+    initial.data.borrow_mut().status_window = window;
+    let app = App::build_ui(initial).context("Failed to build UI")?;
+    app.data.borrow_mut().set_outages(outages);
+    let messages = app
+        .data
+        .borrow_mut()
+        .open_storage(database.as_deref(), &probers);
+    for message in messages {
+        app.app_log_write(&message);
+    }
+    let _pingers = app.spawn_pingers(probers);
     nwg::dispatch_thread_events();
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn main() -> Result<()> {
+    if run_query()? || run_import()? || run_export()? || run_report()? {
+        return Ok(());
+    }
+    let (outages, probers, database, window) = load_probers()?;
+    let mut data = AppData::default();
+    data.set_outages(outages);
+    data.status_window = window;
+    for message in data.open_storage(database.as_deref(), &probers) {
+        console::log_write(&message);
+    }
+    let _pingers = data.spawn_probers(probers);
+    console::run(data)
+}
@@ -0,0 +1,482 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use crate::probe::{Endpoint, Family};
+use crate::Sample;
+
+/// Old enough that every target has probed again, even right after a timeout.
+const DEFAULT_WINDOW_MILLIS: u64 = 5000;
+
+/// How many targets have to be failing, with failures no further apart than `window`, before
+/// the connection counts as down.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quorum {
+    pub count: usize,
+    pub window: Duration,
+}
+
+impl Default for Quorum {
+    /// Any failure is an outage, which is how contrac behaved before quorums.
+    fn default() -> Self {
+        Self {
+            count: 1,
+            window: Duration::from_millis(DEFAULT_WINDOW_MILLIS),
+        }
+    }
+}
+
+/// A change found by the `Detector`. Times are sample timestamps in nanoseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A quorum of targets is failing.
+    Started { start: u128 },
+    /// Fewer than a quorum of targets is failing again.
+    Ended { start: u128, end: u128 },
+    /// One target failed while the others kept answering.
+    Degraded {
+        endpoint: Endpoint,
+        start: u128,
+        end: u128,
+    },
+}
+
+struct TargetState {
+    last: u128,
+    failed: bool,
+    /// Start of the current run of failures, unless that run is part of an outage.
+    failing_since: Option<u128>,
+}
+
+/// Tells an outage, where a quorum of targets fails together, from a single provider
+/// rate-limiting or dropping probes, which is reported as that provider being degraded.
+///
+/// Samples should be fed in timestamp order per target; gateway and interception samples say
+/// nothing about whether the Internet is reachable and should be left out.
+pub struct Detector {
+    quorum: Quorum,
+    targets: HashMap<Endpoint, TargetState>,
+    outage_start: Option<u128>,
+    latest: u128,
+}
+
+impl Detector {
+    pub fn new(quorum: Quorum) -> Self {
+        Self {
+            quorum,
+            targets: HashMap::new(),
+            outage_start: None,
+            latest: 0,
+        }
+    }
+
+    /// When the current outage started, if there is one.
+    pub fn outage_start(&self) -> Option<u128> {
+        self.outage_start
+    }
+
+    pub fn observe(&mut self, sample: Sample) -> Vec<Event> {
+        let (endpoint, timestamp, result) = sample;
+        self.latest = self.latest.max(timestamp);
+        let mut events = Vec::new();
+        let in_outage = self.outage_start.is_some();
+        let state = self.targets.entry(endpoint).or_insert(TargetState {
+            last: timestamp,
+            failed: false,
+            failing_since: None,
+        });
+        state.last = timestamp;
+        state.failed = result.is_err();
+        match (result, state.failing_since) {
+            (Ok(_), Some(start)) => {
+                state.failing_since = None;
+                events.push(Event::Degraded {
+                    endpoint,
+                    start,
+                    end: timestamp,
+                });
+            }
+            (Err(_), None) if !in_outage => state.failing_since = Some(timestamp),
+            _ => {}
+        }
+
+        let failing = self.failing();
+        match self.outage_start {
+            None if failing >= self.quorum.count => {
+                let window_start = self.latest.saturating_sub(self.quorum.window.as_nanos());
+                let start = self
+                    .targets
+                    .values()
+                    .filter_map(|state| state.failing_since)
+                    .filter(|since| *since >= window_start)
+                    .min()
+                    .unwrap_or(timestamp);
+                // failures leading up to the outage are part of it; a provider that was already
+                // failing before then was degraded until the outage started
+                for (endpoint, state) in self.targets.iter_mut() {
+                    match state.failing_since.take() {
+                        Some(since) if since < start => events.push(Event::Degraded {
+                            endpoint: *endpoint,
+                            start: since,
+                            end: start,
+                        }),
+                        _ => {}
+                    }
+                }
+                self.outage_start = Some(start);
+                events.push(Event::Started { start });
+            }
+            // only a reply ends an outage; failures going stale do not
+            Some(start) if result.is_ok() && failing < self.quorum.count => {
+                self.outage_start = None;
+                events.push(Event::Ended {
+                    start,
+                    end: timestamp,
+                });
+            }
+            _ => {}
+        }
+        events
+    }
+
+    /// Ends the outage and degraded runs under way at `at`, when contrac stopped running, and
+    /// forgets how the targets stood, since there is no telling what happened meanwhile.
+    fn stop(&mut self, at: u128) -> Vec<Event> {
+        let mut events: Vec<_> = self
+            .targets
+            .drain()
+            .filter_map(|(endpoint, state)| {
+                let start = state.failing_since?;
+                Some(Event::Degraded {
+                    endpoint,
+                    start,
+                    end: at,
+                })
+            })
+            .collect();
+        if let Some(start) = self.outage_start.take() {
+            events.push(Event::Ended { start, end: at });
+        }
+        events
+    }
+
+    /// Targets whose latest sample, taken within the window, failed.
+    fn failing(&self) -> usize {
+        let window = self.quorum.window.as_nanos();
+        self.targets
+            .values()
+            .filter(|state| state.failed && state.last + window >= self.latest)
+            .count()
+    }
+}
+
+/// Runs a `Detector` for each address family, so an IPv6 outage is found even while IPv4 keeps
+/// working, and keeps a tally of each family's downtime.
+pub struct DualStack {
+    detectors: BTreeMap<Family, Detector>,
+    /// Timestamps of each family's first and latest sample.
+    seen: HashMap<Family, (u128, u128)>,
+    /// Total length of each family's finished outages.
+    downtime: HashMap<Family, u128>,
+    /// Total time each family went without samples while contrac was not running.
+    absent: HashMap<Family, u128>,
+    /// Families whose next sample is the first since contrac started again.
+    resuming: HashSet<Family>,
+}
+
+impl DualStack {
+    pub fn new(quorums: impl IntoIterator<Item = (Family, Quorum)>) -> Self {
+        Self {
+            detectors: quorums
+                .into_iter()
+                .map(|(family, quorum)| (family, Detector::new(quorum)))
+                .collect(),
+            seen: HashMap::new(),
+            downtime: HashMap::new(),
+            absent: HashMap::new(),
+            resuming: HashSet::new(),
+        }
+    }
+
+    /// A detector for each family `endpoints` cover. `count` applies to each family on its
+    /// own, and must not be more than the targets of any of them; it defaults to a majority of
+    /// the family's targets, so one provider dropping probes is not an outage.
+    pub fn for_targets(
+        count: Option<usize>,
+        window: Duration,
+        endpoints: impl IntoIterator<Item = Endpoint>,
+    ) -> Result<Self> {
+        let mut targets: BTreeMap<Family, usize> = BTreeMap::new();
+        for endpoint in endpoints {
+            *targets.entry(endpoint.family()).or_default() += 1;
+        }
+        if let Some(count) = count {
+            if let Some((family, fewer)) =
+                targets.iter().find(|(_family, targets)| **targets < count)
+            {
+                bail!(
+                    "quorum is {} but there are only {} {} targets; it applies to each IP version on its own",
+                    count,
+                    fewer,
+                    family
+                );
+            }
+        }
+        Ok(Self::new(targets.into_iter().map(|(family, targets)| {
+            let count = count.unwrap_or(targets / 2 + 1);
+            (family, Quorum { count, window })
+        })))
+    }
+
+    pub fn families(&self) -> impl Iterator<Item = Family> + '_ {
+        self.detectors.keys().copied()
+    }
+
+    /// Passes the sample to its family's detector. Samples of a family with no targets are ignored.
+    pub fn observe(&mut self, sample: Sample) -> Vec<(Family, Event)> {
+        let (endpoint, timestamp, _result) = sample;
+        let family = endpoint.family();
+        let detector = match self.detectors.get_mut(&family) {
+            Some(detector) => detector,
+            None => return Vec::new(),
+        };
+        let seen = self.seen.entry(family).or_insert((timestamp, timestamp));
+        if self.resuming.remove(&family) {
+            *self.absent.entry(family).or_default() += timestamp.saturating_sub(seen.1);
+        }
+        seen.1 = seen.1.max(timestamp);
+        let events = detector.observe(sample);
+        for event in &events {
+            if let Event::Ended { start, end } = event {
+                *self.downtime.entry(family).or_default() += end - start;
+            }
+        }
+        events.into_iter().map(|event| (family, event)).collect()
+    }
+
+    /// Contrac stopped running after the samples up to `at`: whatever was under way ends there,
+    /// and the time until the next sample does not count towards the availability.
+    pub fn stopped(&mut self, at: u128) -> Vec<(Family, Event)> {
+        let mut events = Vec::new();
+        for (family, detector) in self.detectors.iter_mut() {
+            for event in detector.stop(at) {
+                if let Event::Ended { start, end } = event {
+                    *self.downtime.entry(*family).or_default() += end - start;
+                }
+                events.push((*family, event));
+            }
+            if let Some(seen) = self.seen.get_mut(family) {
+                seen.1 = seen.1.max(at);
+            }
+            self.resuming.insert(*family);
+        }
+        events
+    }
+
+    pub fn is_down(&self, family: Family) -> bool {
+        self.outage_start(family).is_some()
+    }
+
+    /// When the family's current outage started, if it is down.
+    pub fn outage_start(&self, family: Family) -> Option<u128> {
+        self.detectors.get(&family)?.outage_start()
+    }
+
+    /// The timestamps of the first and latest samples of any family.
+    pub fn coverage(&self) -> Option<(u128, u128)> {
+        let first = self.seen.values().map(|seen| seen.0).min()?;
+        let latest = self.seen.values().map(|seen| seen.1).max()?;
+        Some((first, latest))
+    }
+
+    /// The share of the time since the family's first sample, while contrac was running, that
+    /// it was not in an outage.
+    pub fn availability(&self, family: Family) -> Option<f32> {
+        let (first, latest) = *self.seen.get(&family)?;
+        if latest == first {
+            return None;
+        }
+        let ongoing = match self.detectors[&family].outage_start() {
+            Some(start) => latest.saturating_sub(start),
+            None => 0,
+        };
+        let down = self.downtime.get(&family).copied().unwrap_or(0) + ongoing;
+        let absent = self.absent.get(&family).copied().unwrap_or(0);
+        let running = (latest - first).saturating_sub(absent);
+        if running == 0 {
+            return None;
+        }
+        Some(1.0 - down as f32 / running as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Failure;
+    use std::net::IpAddr;
+
+    const SECOND: u128 = 1_000_000_000;
+
+    fn detector(count: usize) -> Detector {
+        Detector::new(Quorum {
+            count,
+            window: Duration::from_secs(3),
+        })
+    }
+
+    fn endpoint(last: u8) -> Endpoint {
+        IpAddr::from([10, 0, 0, last]).into()
+    }
+
+    fn up(last: u8, second: u128) -> Sample {
+        (endpoint(last), second * SECOND, Ok(20))
+    }
+
+    fn down(last: u8, second: u128) -> Sample {
+        (endpoint(last), second * SECOND, Err(Failure::Timeout))
+    }
+
+    #[test]
+    fn single_provider_failure_is_degraded() {
+        let mut detector = detector(2);
+        for sample in [
+            up(1, 0),
+            up(2, 0),
+            down(1, 1),
+            up(2, 1),
+            down(1, 2),
+            up(2, 2),
+        ] {
+            assert!(detector.observe(sample).is_empty());
+        }
+        assert_eq!(
+            detector.observe(up(1, 3)),
+            vec![Event::Degraded {
+                endpoint: endpoint(1),
+                start: SECOND,
+                end: 3 * SECOND
+            }]
+        );
+    }
+
+    #[test]
+    fn quorum_of_failures_is_an_outage() {
+        let mut detector = detector(2);
+        assert!(detector.observe(down(1, 1)).is_empty());
+        assert_eq!(
+            detector.observe(down(2, 2)),
+            vec![Event::Started { start: SECOND }]
+        );
+        assert!(detector.observe(down(1, 3)).is_empty());
+        assert_eq!(
+            detector.observe(up(2, 4)),
+            vec![Event::Ended {
+                start: SECOND,
+                end: 4 * SECOND
+            }]
+        );
+        // the target still failing after the outage starts a new degraded run
+        assert!(detector.observe(down(1, 5)).is_empty());
+        assert_eq!(
+            detector.observe(up(1, 6)),
+            vec![Event::Degraded {
+                endpoint: endpoint(1),
+                start: 5 * SECOND,
+                end: 6 * SECOND
+            }]
+        );
+    }
+
+    #[test]
+    fn failures_outside_the_window_do_not_add_up() {
+        let mut detector = detector(2);
+        assert!(detector.observe(down(1, 1)).is_empty());
+        assert!(detector.observe(down(2, 10)).is_empty());
+    }
+
+    #[test]
+    fn outage_lasts_until_a_reply() {
+        let mut detector = detector(2);
+        detector.observe(down(1, 1));
+        detector.observe(down(2, 1));
+        assert!(detector.observe(down(1, 10)).is_empty());
+        assert_eq!(
+            detector.observe(up(2, 11)),
+            vec![Event::Ended {
+                start: SECOND,
+                end: 11 * SECOND
+            }]
+        );
+    }
+
+    #[test]
+    fn families_fail_separately() {
+        let v6 = |last: u16| -> Endpoint { IpAddr::from([0xfd00, 0, 0, 0, 0, 0, 0, last]).into() };
+        let targets = [endpoint(1), endpoint(2), endpoint(3), v6(1), v6(2)];
+        let mut stack = DualStack::for_targets(None, Duration::from_secs(3), targets).unwrap();
+        stack.observe(up(1, 0));
+        // a majority of the three IPv4 targets is two
+        assert!(stack.observe(down(2, 1)).is_empty());
+        stack.observe((v6(1), 0, Ok(20)));
+        stack.observe((v6(1), SECOND, Err(Failure::Timeout)));
+        assert_eq!(
+            stack.observe((v6(2), SECOND, Err(Failure::Timeout))),
+            vec![(Family::V6, Event::Started { start: SECOND })]
+        );
+        assert!(stack.is_down(Family::V6));
+        assert!(!stack.is_down(Family::V4));
+        stack.observe((v6(1), 4 * SECOND, Ok(20)));
+        stack.observe(up(1, 4));
+        assert_eq!(stack.availability(Family::V4), Some(1.0));
+        assert_eq!(stack.availability(Family::V6), Some(0.25));
+        let error = DualStack::for_targets(Some(3), Duration::from_secs(3), targets)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "quorum is 3 but there are only 2 IPv6 targets; it applies to each IP version on its own"
+        );
+    }
+
+    #[test]
+    fn long_degraded_provider_ends_where_the_outage_starts() {
+        let mut detector = detector(2);
+        for second in 0..10 {
+            detector.observe(down(1, second));
+        }
+        assert_eq!(
+            detector.observe(down(2, 10)),
+            vec![
+                Event::Degraded {
+                    endpoint: endpoint(1),
+                    start: 0,
+                    end: 10 * SECOND
+                },
+                Event::Started { start: 10 * SECOND }
+            ]
+        );
+    }
+
+    #[test]
+    fn stopping_ends_the_outage_and_leaves_out_the_gap() {
+        let targets = [endpoint(1), endpoint(2)];
+        let mut stack = DualStack::for_targets(Some(1), Duration::from_secs(3), targets).unwrap();
+        stack.observe(up(2, 0));
+        stack.observe(down(1, 2));
+        assert_eq!(
+            stack.stopped(4 * SECOND),
+            vec![(
+                Family::V4,
+                Event::Ended {
+                    start: 2 * SECOND,
+                    end: 4 * SECOND
+                }
+            )]
+        );
+        // down for 2 of the 4 seconds before the gap and none of the 4 after it
+        stack.observe(up(1, 100));
+        stack.observe(up(2, 104));
+        assert_eq!(stack.availability(Family::V4), Some(0.75));
+    }
+}
@@ -0,0 +1,272 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{io_failure, micros, Failure, ProbeKind, Prober};
+
+pub const DNS_PORT: u16 = 53;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NOERROR: u16 = 0;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_REFUSED: u16 = 5;
+const CLASS_IN: u16 = 1;
+
+/// The record a DNS probe asks for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+/// Times a recursive query to one resolver, so a DNS outage shows up even while ICMP still works.
+///
+/// An answer with no records still counts as a reply; NXDOMAIN, SERVFAIL and REFUSED are
+/// reported as failures of their own.
+pub struct DnsProber {
+    resolver: SocketAddr,
+    name: String,
+    record: RecordType,
+    id: u16,
+    timeout: Duration,
+}
+
+impl DnsProber {
+    pub fn new(resolver: SocketAddr, name: &str, record: RecordType, timeout: Duration) -> Self {
+        // vary the starting id between runs so replies to an earlier process are not accepted
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        Self {
+            resolver,
+            name: name.trim_end_matches('.').to_string(),
+            record,
+            id: (seed ^ std::process::id()) as u16,
+            timeout,
+        }
+    }
+
+    fn query(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(32 + self.name.len());
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+        packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question, no other records
+        for label in self.name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&self.record.code().to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    /// Returns the response code if `packet` is the response to the current query.
+    fn response_code(&self, packet: &[u8]) -> Option<u16> {
+        if packet.len() < 12 || packet[0..2] != self.id.to_be_bytes() {
+            return None;
+        }
+        let flags = u16::from_be_bytes([packet[2], packet[3]]);
+        if flags & FLAG_RESPONSE == 0 {
+            return None;
+        }
+        Some(flags & RCODE_MASK)
+    }
+}
+
+impl Prober for DnsProber {
+    fn address(&self) -> IpAddr {
+        self.resolver.ip()
+    }
+
+    fn kind(&self) -> ProbeKind {
+        ProbeKind::Dns
+    }
+
+    fn probe(&mut self) -> Result<u32, Failure> {
+        self.id = self.id.wrapping_add(1);
+        let local: SocketAddr = match self.resolver {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        // a fresh socket per query gets a new source port, like a real stub resolver
+        let socket = UdpSocket::bind(local).map_err(|err| io_failure(&err))?;
+        socket
+            .connect(self.resolver)
+            .map_err(|err| io_failure(&err))?;
+        let start = Instant::now();
+        let deadline = start + self.timeout;
+        socket.send(&self.query()).map_err(|err| io_failure(&err))?;
+        let mut buffer = [0u8; 512];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return Err(Failure::Timeout);
+            }
+            socket
+                .set_read_timeout(Some(remaining))
+                .map_err(|err| io_failure(&err))?;
+            let len = match socket.recv(&mut buffer) {
+                Ok(len) => len,
+                // refused is ICMP port unreachable: nothing is listening on the resolver address
+                Err(err) => return Err(io_failure(&err)),
+            };
+            let rtt = micros(start.elapsed());
+            match self.response_code(&buffer[..len]) {
+                None => continue, // a late reply to an earlier query
+                Some(RCODE_NOERROR) => return Ok(rtt),
+                Some(RCODE_NXDOMAIN) => return Err(Failure::NxDomain),
+                Some(RCODE_REFUSED) => return Err(Failure::Refused),
+                // FORMERR, NOTIMP and friends mean the resolver is not answering usefully either
+                Some(RCODE_SERVFAIL) | Some(_) => return Err(Failure::ServFail),
+            }
+        }
+    }
+}
+
+/// The resolvers the operating system is configured to use.
+#[cfg(unix)]
+pub fn system_resolvers() -> Vec<IpAddr> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    resolv_conf
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(address)) => address.parse().ok(),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The resolvers the operating system is configured to use.
+#[cfg(windows)]
+pub fn system_resolvers() -> Vec<IpAddr> {
+    use std::ffi::CStr;
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::iphlpapi::GetNetworkParams;
+    use winapi::um::iptypes::{FIXED_INFO, IP_ADDR_STRING};
+
+    let mut size: u32 = 0;
+    // This call always fails with ERROR_BUFFER_OVERFLOW, because we pass NULL to
+    // get the required buffer size.
+    unsafe { GetNetworkParams(std::ptr::null_mut(), &mut size) };
+    let mut buffer = super::aligned_buffer(size);
+    let info = buffer.as_mut_ptr() as *mut FIXED_INFO;
+    if unsafe { GetNetworkParams(info, &mut size) } != ERROR_SUCCESS {
+        return Vec::new();
+    }
+    let mut resolvers = Vec::new();
+    let mut entry: *const IP_ADDR_STRING = unsafe { &(*info).DnsServerList };
+    while !entry.is_null() {
+        let address = unsafe { CStr::from_ptr((*entry).IpAddress.String.as_ptr()) };
+        if let Ok(ip) = address.to_string_lossy().parse() {
+            resolvers.push(ip);
+        }
+        entry = unsafe { (*entry).Next };
+    }
+    resolvers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Answers each query on a local socket according to the first label of the name asked for.
+    fn spawn_stand_in_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, client)) = socket.recv_from(&mut buffer) {
+                let query = &buffer[..len];
+                let label = &query[13..13 + query[12] as usize];
+                let rcode = match label {
+                    b"nx" => RCODE_NXDOMAIN,
+                    b"fail" => RCODE_SERVFAIL,
+                    b"refused" => RCODE_REFUSED,
+                    b"silent" => continue,
+                    _ => RCODE_NOERROR,
+                };
+                let mut response = query.to_vec();
+                if label == b"stale" {
+                    // answer with the wrong id first, as a late reply to an earlier query would
+                    let mut stale = response.clone();
+                    stale[1] ^= 0xff;
+                    stale[3] |= 0x80;
+                    socket.send_to(&stale, client).unwrap();
+                }
+                let flags = FLAG_RESPONSE | FLAG_RECURSION_DESIRED | 0x0080 | rcode;
+                response[2..4].copy_from_slice(&flags.to_be_bytes());
+                socket.send_to(&response, client).unwrap();
+            }
+        });
+        address
+    }
+
+    fn probe(name: &str, record: RecordType) -> Result<u32, Failure> {
+        let timeout = Duration::from_millis(200);
+        DnsProber::new(spawn_stand_in_server(), name, record, timeout).probe()
+    }
+
+    #[test]
+    fn answered_queries_report_latency() {
+        assert!(probe("ok.example.com", RecordType::A).is_ok());
+        assert!(probe("ok.example.com.", RecordType::Aaaa).is_ok());
+    }
+
+    #[test]
+    fn response_codes_are_separate_failures() {
+        assert_eq!(
+            probe("nx.example.com", RecordType::A),
+            Err(Failure::NxDomain)
+        );
+        assert_eq!(
+            probe("fail.example.com", RecordType::A),
+            Err(Failure::ServFail)
+        );
+        assert_eq!(
+            probe("refused.example.com", RecordType::A),
+            Err(Failure::Refused)
+        );
+    }
+
+    #[test]
+    fn unanswered_query_times_out() {
+        assert_eq!(
+            probe("silent.example.com", RecordType::A),
+            Err(Failure::Timeout)
+        );
+    }
+
+    #[test]
+    fn replies_to_other_queries_are_ignored() {
+        assert!(probe("stale.example.com", RecordType::A).is_ok());
+    }
+
+    #[test]
+    fn query_encodes_name_and_record_type() {
+        let prober = DnsProber::new(
+            ([127, 0, 0, 1], 53).into(),
+            "www.example.com",
+            RecordType::Aaaa,
+            Duration::from_millis(200),
+        );
+        let query = prober.query();
+        assert_eq!(&query[12..29], b"\x03www\x07example\x03com\x00");
+        assert_eq!(&query[29..], &[0, 28, 0, 1]);
+    }
+}
@@ -0,0 +1,100 @@
+use std::net::IpAddr;
+
+/// The IPv4 default gateway, i.e. the router or modem every Internet probe goes through.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_route_table(&routes)
+}
+
+/// The IPv4 default gateway, i.e. the router or modem every Internet probe goes through.
+#[cfg(windows)]
+pub fn default_gateway() -> Option<IpAddr> {
+    use std::ffi::CStr;
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::iphlpapi::GetAdaptersInfo;
+    use winapi::um::iptypes::{IP_ADAPTER_INFO, IP_ADDR_STRING};
+
+    let mut size: u32 = 0;
+    // Like GetNetworkParams, this first call only reports the buffer size needed.
+    unsafe { GetAdaptersInfo(std::ptr::null_mut(), &mut size) };
+    let mut buffer = super::aligned_buffer(size);
+    let info = buffer.as_mut_ptr() as *mut IP_ADAPTER_INFO;
+    if size == 0 || unsafe { GetAdaptersInfo(info, &mut size) } != ERROR_SUCCESS {
+        return None;
+    }
+    let mut adapter: *const IP_ADAPTER_INFO = info;
+    while !adapter.is_null() {
+        let mut entry: *const IP_ADDR_STRING = unsafe { &(*adapter).GatewayList };
+        while !entry.is_null() {
+            let address = unsafe { CStr::from_ptr((*entry).IpAddress.String.as_ptr()) };
+            match address.to_string_lossy().parse::<IpAddr>() {
+                // adapters without a gateway list 0.0.0.0
+                Ok(ip) if !ip.is_unspecified() => return Some(ip),
+                _ => {}
+            }
+            entry = unsafe { (*entry).Next };
+        }
+        adapter = unsafe { (*adapter).Next };
+    }
+    None
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn default_gateway() -> Option<IpAddr> {
+    None
+}
+
+/// Picks the gateway of the lowest metric default route from the contents of /proc/net/route.
+#[cfg(target_os = "linux")]
+fn parse_route_table(routes: &str) -> Option<IpAddr> {
+    const RTF_UP: u32 = 0x0001;
+    const RTF_GATEWAY: u32 = 0x0002;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+                return None;
+            }
+            let flags = u32::from_str_radix(fields[3], 16).ok()?;
+            if flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+                return None;
+            }
+            // addresses are printed as a host order number of network order bytes
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            let metric: u32 = fields[6].parse().ok()?;
+            Some((metric, IpAddr::from(gateway.to_ne_bytes())))
+        })
+        .min_by_key(|(metric, _gateway)| *metric)
+        .map(|(_metric, gateway)| gateway)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    /// An address as the kernel prints it: its network order bytes read as a host order number.
+    fn printed(octets: [u8; 4]) -> String {
+        format!("{:08X}", u32::from_ne_bytes(octets))
+    }
+
+    #[test]
+    fn default_route_with_lowest_metric_wins() {
+        let routes = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            wlan0\t00000000\t{}\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+            eth0\t00000000\t{}\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+            eth0\t{}\t00000000\t0001\t0\t0\t100\t{}\t0\t0\t0\n",
+            printed([192, 168, 1, 1]),
+            printed([10, 0, 0, 254]),
+            printed([10, 0, 0, 0]),
+            printed([255, 255, 255, 0])
+        );
+        assert_eq!(parse_route_table(&routes), Some([10, 0, 0, 254].into()));
+        let without_default = "Iface\tDestination\tGateway\n\
+            eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n";
+        assert_eq!(parse_route_table(without_default), None);
+    }
+}
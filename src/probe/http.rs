@@ -0,0 +1,238 @@
+use anyhow::{bail, Context, Result};
+use native_tls::{HandshakeError, TlsConnector};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use super::{io_failure, micros, Failure, ProbeKind, Prober};
+
+/// More than any `generate_204` answer needs; a portal login page is cut off here.
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// Fetches a "generate_204" style URL and reports the time to the first byte of the response.
+///
+/// Anything other than an empty 204 means something between us and the Internet answered in the
+/// server's place. Redirects and pages served in place of the 204 are reported as a captive
+/// portal; other statuses, unexpected bodies and TLS certificate failures as interception.
+pub struct HttpProber {
+    dst: SocketAddr,
+    authority: String,
+    host: String,
+    path: String,
+    tls: Option<TlsConnector>,
+    timeout: Duration,
+}
+
+impl HttpProber {
+    /// Resolves the host in `url` once, so samples are always reported against the same address.
+    pub fn new(url: &str, timeout: Duration) -> Result<Self> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            bail!("'{}' is not an http:// or https:// URL", url);
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let default_port = if https { 443 } else { 80 };
+        let (host, port) = match authority.rfind(':') {
+            Some(index) if !authority[index..].contains(']') => (
+                &authority[..index],
+                authority[index + 1..]
+                    .parse::<u16>()
+                    .context(format!("bad port in '{}'", url))?,
+            ),
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let dst = (host, port)
+            .to_socket_addrs()
+            .context(format!("unable to resolve '{}'", host))?
+            .next()
+            .context(format!("no addresses for '{}'", host))?;
+        let tls = if https {
+            Some(TlsConnector::new().context("unable to set up TLS")?)
+        } else {
+            None
+        };
+        Ok(Self {
+            dst,
+            authority: authority.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+            tls,
+            timeout,
+        })
+    }
+
+    fn connect(&self, deadline: Instant) -> Result<Box<dyn Stream>, Failure> {
+        let stream =
+            TcpStream::connect_timeout(&self.dst, self.timeout).map_err(|err| io_failure(&err))?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_millis(0) {
+            return Err(Failure::Timeout);
+        }
+        stream
+            .set_read_timeout(Some(remaining))
+            .and_then(|_| stream.set_write_timeout(Some(remaining)))
+            .map_err(|err| io_failure(&err))?;
+        match &self.tls {
+            None => Ok(Box::new(stream)),
+            Some(connector) => match connector.connect(&self.host, stream) {
+                Ok(stream) => Ok(Box::new(stream)),
+                // a certificate that does not match the host is what a TLS intercepting proxy looks like
+                Err(HandshakeError::Failure(_)) => Err(Failure::Intercepted),
+                Err(HandshakeError::WouldBlock(_)) => Err(Failure::Timeout),
+            },
+        }
+    }
+}
+
+impl Prober for HttpProber {
+    fn address(&self) -> IpAddr {
+        self.dst.ip()
+    }
+
+    fn kind(&self) -> ProbeKind {
+        ProbeKind::Http
+    }
+
+    fn probe(&mut self) -> Result<u32, Failure> {
+        let start = Instant::now();
+        let deadline = start + self.timeout;
+        let mut stream = self.connect(deadline)?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: contrac\r\nConnection: close\r\n\r\n",
+            self.path, self.authority
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|err| io_failure(&err))?;
+
+        let mut response = Vec::new();
+        let mut first_byte = None;
+        let mut buffer = [0u8; 4096];
+        while response.len() < MAX_RESPONSE_BYTES {
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => {
+                    first_byte.get_or_insert_with(|| start.elapsed());
+                    response.extend_from_slice(&buffer[..len]);
+                }
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    if first_byte.is_none() {
+                        return Err(Failure::Timeout);
+                    }
+                    break; // judge what did arrive
+                }
+                Err(_err) => break,
+            }
+        }
+        // the connection was closed or reset without an answer
+        let ttfb = first_byte.ok_or(Failure::Unreachable(None))?;
+        classify(&response)?;
+        Ok(micros(ttfb))
+    }
+}
+
+/// Checks a raw HTTP response for the empty 204 the probe URL is expected to return.
+fn classify(response: &[u8]) -> Result<(), Failure> {
+    let header_end = find(response, b"\r\n\r\n").ok_or(Failure::Intercepted)?;
+    let headers = String::from_utf8_lossy(&response[..header_end]);
+    let body = &response[header_end + 4..];
+    let status = headers
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or(Failure::Intercepted)?;
+    match status {
+        204 if body.is_empty() => Ok(()),
+        300..=399 => Err(Failure::CaptivePortal),
+        200 if !body.is_empty() => Err(Failure::CaptivePortal),
+        _ => Err(Failure::Intercepted),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_millis(2000);
+
+    /// Serves `response` to a single request on a local port and returns a prober for it.
+    fn serve(response: &'static str) -> HttpProber {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/generate_204", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        HttpProber::new(&url, Duration::from_millis(500)).unwrap()
+    }
+
+    #[test]
+    fn empty_204_is_a_reply() {
+        let mut prober = serve("HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n");
+        assert!(prober.probe().is_ok());
+    }
+
+    #[test]
+    fn redirect_is_a_captive_portal() {
+        let mut prober = serve(
+            "HTTP/1.1 302 Found\r\nLocation: http://login.portal/\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(prober.probe(), Err(Failure::CaptivePortal));
+    }
+
+    #[test]
+    fn login_page_is_a_captive_portal() {
+        let mut prober = serve("HTTP/1.1 200 OK\r\n\r\n<html>Please sign in</html>");
+        assert_eq!(prober.probe(), Err(Failure::CaptivePortal));
+    }
+
+    #[test]
+    fn unexpected_response_is_interception() {
+        let mut prober = serve("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(prober.probe(), Err(Failure::Intercepted));
+        let mut prober = serve("SSH-2.0-OpenSSH\r\n");
+        assert_eq!(prober.probe(), Err(Failure::Intercepted));
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let mut prober = HttpProber::new(&url, Duration::from_millis(200)).unwrap();
+        assert_eq!(prober.probe(), Err(Failure::Timeout));
+    }
+
+    #[test]
+    fn url_parts() {
+        let prober = HttpProber::new("http://127.0.0.1:8080", TIMEOUT).unwrap();
+        assert_eq!(prober.dst, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(prober.path, "/");
+        let prober = HttpProber::new("http://[::1]/generate_204", TIMEOUT).unwrap();
+        assert_eq!(prober.dst, "[::1]:80".parse().unwrap());
+        assert_eq!(prober.host, "::1");
+        assert!(HttpProber::new("ftp://127.0.0.1/", TIMEOUT).is_err());
+    }
+}
@@ -0,0 +1,491 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::mem::{self, MaybeUninit};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+use super::{io_failure, micros, Failure, Icmp, ProbeKind, Prober, RECENT_PROBES};
+
+/// The payload Windows `ping` sends, so captures look the same on both platforms.
+const ECHO_PAYLOAD: &[u8] = b"abcdefghijklmnopqrstuvwabcdefghi";
+
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const ICMPV4_UNREACHABLE: u8 = 3;
+const ICMPV4_TIME_EXCEEDED: u8 = 11;
+const ICMPV6_UNREACHABLE: u8 = 1;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+/// Fixed IPv6 header in front of the request quoted by an ICMPv6 error.
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Counts the probers made so far, so each raw socket prober, traces included, sends with an
+/// identifier of its own and only takes the replies to its own requests.
+static PROBERS: AtomicU16 = AtomicU16::new(0);
+
+/// What an ICMP packet read from the socket says about the current request.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Echo,
+    TimeExceeded(Icmp),
+    Unreachable(Icmp),
+    /// This machine's own network stack gave up on the request, e.g. when the next hop did not
+    /// answer ARP.
+    Local,
+}
+
+/// ICMP echo over a socket.
+///
+/// Prefers an unprivileged ICMP datagram socket (allowed by `net.ipv4.ping_group_range`) and
+/// falls back to a raw socket, which needs root or `CAP_NET_RAW`. The raw socket is left
+/// unconnected so that it also sees the errors routers along the path send back when tracing.
+pub struct IcmpSocketProber {
+    dst: IpAddr,
+    socket: Socket,
+    raw: bool,
+    identifier: u16,
+    sequence: u16,
+    /// When each of the last few requests went out, to time replies that come in after their timeout.
+    sent: VecDeque<(u16, Instant)>,
+    /// Replies to earlier requests seen since `stray_replies` was last called.
+    strays: Vec<(u16, u32)>,
+    timeout: Duration,
+}
+
+impl IcmpSocketProber {
+    pub fn new(dst: IpAddr, timeout: Duration) -> Result<Self> {
+        let (domain, protocol) = match dst {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(socket) => (socket, false),
+            Err(_) => (
+                Socket::new(domain, Type::RAW, Some(protocol)).context(
+                    "unable to open an ICMP socket (check net.ipv4.ping_group_range or grant CAP_NET_RAW)",
+                )?,
+                true,
+            ),
+        };
+        if !raw {
+            socket
+                .connect(&SockAddr::from(SocketAddr::new(dst, 0)))
+                .context(format!("unable to connect ICMP socket to {}", dst))?;
+            set_receive_errors(&socket, dst).context("unable to turn on ICMP error reporting")?;
+        }
+        Ok(Self {
+            dst,
+            socket,
+            raw,
+            identifier: (std::process::id() as u16)
+                .wrapping_add(PROBERS.fetch_add(1, Ordering::Relaxed)),
+            sequence: 0,
+            sent: VecDeque::with_capacity(RECENT_PROBES),
+            strays: Vec::new(),
+            timeout,
+        })
+    }
+
+    fn echo_request(&self) -> Vec<u8> {
+        let mut packet = vec![self.request_type(), 0, 0, 0];
+        packet.extend_from_slice(&self.identifier.to_be_bytes());
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(ECHO_PAYLOAD);
+        if self.dst.is_ipv4() {
+            // the kernel fills in the ICMPv6 checksum itself since it covers the IPv6 pseudo header
+            let checksum = internet_checksum(&packet);
+            packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        packet
+    }
+
+    /// Works out whether `packet`, which came from `source`, answers one of our requests, and
+    /// which one by sequence number.
+    fn classify(&self, source: IpAddr, packet: &[u8]) -> Option<(Reply, u16)> {
+        let icmp = match self.dst {
+            // raw IPv4 sockets deliver the IP header too
+            IpAddr::V4(_) if self.raw => packet.get(ip_header_len(packet)..)?,
+            _ => packet,
+        };
+        if icmp.len() < 8 {
+            return None;
+        }
+        let message = Icmp {
+            kind: icmp[0],
+            code: icmp[1],
+        };
+        let (reply, request) = match (self.dst, icmp[0]) {
+            (IpAddr::V4(_), ICMPV4_ECHO_REPLY) | (IpAddr::V6(_), ICMPV6_ECHO_REPLY)
+                if source == self.dst =>
+            {
+                (Reply::Echo, icmp)
+            }
+            // errors quote the header of the request they are about
+            (IpAddr::V4(_), ICMPV4_TIME_EXCEEDED) => {
+                (Reply::TimeExceeded(message), skip_ip_header(&icmp[8..])?)
+            }
+            (IpAddr::V4(_), ICMPV4_UNREACHABLE) => {
+                (Reply::Unreachable(message), skip_ip_header(&icmp[8..])?)
+            }
+            (IpAddr::V6(_), ICMPV6_TIME_EXCEEDED) => (
+                Reply::TimeExceeded(message),
+                icmp.get(8 + IPV6_HEADER_LEN..)?,
+            ),
+            (IpAddr::V6(_), ICMPV6_UNREACHABLE) => (
+                Reply::Unreachable(message),
+                icmp.get(8 + IPV6_HEADER_LEN..)?,
+            ),
+            _ => return None,
+        };
+        // an error about a request to another target, sent by another prober
+        if reply != Reply::Echo && quoted_destination(&icmp[8..])? != self.dst {
+            return None;
+        }
+        if request.len() < 8 || (reply != Reply::Echo && request[0] != self.request_type()) {
+            return None;
+        }
+        // datagram sockets have their identifier rewritten by the kernel, which also does the matching
+        let identifier = u16::from_be_bytes([request[4], request[5]]);
+        let sequence = u16::from_be_bytes([request[6], request[7]]);
+        if self.raw && identifier != self.identifier {
+            return None;
+        }
+        Some((reply, sequence))
+    }
+
+    fn request_type(&self) -> u8 {
+        match self.dst {
+            IpAddr::V4(_) => ICMPV4_ECHO_REQUEST,
+            IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+        }
+    }
+
+    /// Sends an echo request and waits for whoever answers it, the destination or a router
+    /// reporting that the request could not get there.
+    fn exchange(&mut self) -> Result<(IpAddr, Reply, u32), Failure> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let request = self.echo_request();
+        let start = Instant::now();
+        let deadline = start + self.timeout;
+        if self.sent.len() == RECENT_PROBES {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((self.sequence, start));
+        // an error that came in after an earlier request timed out would fail this send
+        if !self.raw {
+            let _ = self.socket.take_error();
+            self.read_error_queue();
+        }
+        let sent = if self.raw {
+            self.socket
+                .send_to(&request, &SockAddr::from(SocketAddr::new(self.dst, 0)))
+        } else {
+            self.socket.send(&request)
+        };
+        sent.map_err(|err| io_failure(&err))?;
+        let mut buffer = [MaybeUninit::<u8>::uninit(); 1500];
+        loop {
+            // a raw socket also sees other ICMP traffic, so keep reading until our reply or the deadline
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return Err(Failure::Timeout);
+            }
+            self.socket
+                .set_read_timeout(Some(remaining))
+                .map_err(|err| io_failure(&err))?;
+            let (len, source) = match self.socket.recv_from(&mut buffer) {
+                Ok((len, source)) => (len, source.as_socket().map(|source| source.ip())),
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    return Err(Failure::Timeout)
+                }
+                // an ICMP error for an earlier packet is reported on the next read of a connected
+                // socket, with the details in the error queue
+                Err(err) => match self.read_error_queue() {
+                    Some((source, reply)) => return Ok((source, reply, micros(start.elapsed()))),
+                    None => return Err(io_failure(&err)),
+                },
+            };
+            // recv_from initialized the first len bytes
+            let packet = unsafe { &*(&buffer[..len] as *const [MaybeUninit<u8>] as *const [u8]) };
+            match source.and_then(|source| self.classify(source, packet)) {
+                Some((reply, sequence)) if sequence == self.sequence => {
+                    return Ok((source.unwrap(), reply, micros(start.elapsed())));
+                }
+                Some((Reply::Echo, sequence)) => self.record_stray(sequence),
+                _ => {}
+            }
+        }
+    }
+
+    /// Remembers a reply to an earlier request, late or duplicated, so it is not just dropped.
+    fn record_stray(&mut self, sequence: u16) {
+        if let Some((_sequence, sent)) = self.sent.iter().find(|(sent, _)| *sent == sequence) {
+            let age = self.sequence.wrapping_sub(sequence);
+            self.strays.push((age, micros(sent.elapsed())));
+        }
+    }
+
+    /// Takes ICMP errors off the socket error queue until one is about the current request.
+    fn read_error_queue(&self) -> Option<(IpAddr, Reply)> {
+        loop {
+            if let Some(error) = self.next_error()? {
+                return Some(error);
+            }
+        }
+    }
+
+    /// Takes the next error off the socket error queue, returning `None` once it is empty and
+    /// `Some(None)` for an error about some other request.
+    fn next_error(&self) -> Option<Option<(IpAddr, Reply)>> {
+        let mut data = [0u8; 576];
+        // u64 elements keep the control buffer aligned for the cmsghdr structs
+        let mut control = [0u64; 64];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control) as _;
+        let len = unsafe {
+            libc::recvmsg(
+                self.socket.as_raw_fd(),
+                &mut message,
+                libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+            )
+        };
+        if len < 0 {
+            return None;
+        }
+        // the queued data is the request the error is about
+        if len < 8 || u16::from_be_bytes([data[6], data[7]]) != self.sequence {
+            return Some(None);
+        }
+        let mut header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+        while !header.is_null() {
+            let (level, kind) = unsafe { ((*header).cmsg_level, (*header).cmsg_type) };
+            if (level == libc::SOL_IP && kind == libc::IP_RECVERR)
+                || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR)
+            {
+                let error = unsafe { libc::CMSG_DATA(header) } as *const libc::sock_extended_err;
+                let (origin, kind, code) =
+                    unsafe { ((*error).ee_origin, (*error).ee_type, (*error).ee_code) };
+                let message = Icmp { kind, code };
+                let reply = match (origin, kind) {
+                    (libc::SO_EE_ORIGIN_ICMP, ICMPV4_TIME_EXCEEDED)
+                    | (libc::SO_EE_ORIGIN_ICMP6, ICMPV6_TIME_EXCEEDED) => {
+                        Reply::TimeExceeded(message)
+                    }
+                    (libc::SO_EE_ORIGIN_ICMP, _) | (libc::SO_EE_ORIGIN_ICMP6, _) => {
+                        Reply::Unreachable(message)
+                    }
+                    (libc::SO_EE_ORIGIN_LOCAL, _) => return Some(Some((self.dst, Reply::Local))),
+                    _ => return Some(None),
+                };
+                let offender = unsafe { libc::SO_EE_OFFENDER(error) };
+                return Some(socket_address(offender).map(|source| (source, reply)));
+            }
+            header = unsafe { libc::CMSG_NXTHDR(&message, header) };
+        }
+        Some(None)
+    }
+
+    fn set_hop_limit(&self, hops: u32) -> std::io::Result<()> {
+        match self.dst {
+            IpAddr::V4(_) => self.socket.set_ttl(hops),
+            IpAddr::V6(_) => self.socket.set_unicast_hops_v6(hops),
+        }
+    }
+
+    fn hop_limit(&self) -> std::io::Result<u32> {
+        match self.dst {
+            IpAddr::V4(_) => self.socket.ttl(),
+            IpAddr::V6(_) => self.socket.unicast_hops_v6(),
+        }
+    }
+}
+
+impl Prober for IcmpSocketProber {
+    fn address(&self) -> IpAddr {
+        self.dst
+    }
+
+    fn kind(&self) -> ProbeKind {
+        ProbeKind::Icmp
+    }
+
+    fn probe(&mut self) -> Result<u32, Failure> {
+        match self.exchange()? {
+            (_source, Reply::Echo, rtt) => Ok(rtt),
+            (_source, Reply::TimeExceeded(message), _rtt) => {
+                Err(Failure::TtlExpired(Some(message)))
+            }
+            (_source, Reply::Unreachable(message), _rtt) => {
+                Err(Failure::Unreachable(Some(message)))
+            }
+            (_source, Reply::Local, _rtt) => Err(Failure::NoRoute),
+        }
+    }
+
+    fn stray_replies(&mut self) -> Vec<(u16, u32)> {
+        mem::take(&mut self.strays)
+    }
+
+    fn probe_ttl(&mut self, ttl: u8) -> Result<(IpAddr, u32), Failure> {
+        let default = self.hop_limit().map_err(|err| io_failure(&err))?;
+        self.set_hop_limit(ttl as u32)
+            .map_err(|err| io_failure(&err))?;
+        let result = self.exchange();
+        self.set_hop_limit(default)
+            .map_err(|err| io_failure(&err))?;
+        match result? {
+            (source, Reply::Echo, rtt) | (source, Reply::TimeExceeded(_), rtt) => Ok((source, rtt)),
+            (_source, Reply::Unreachable(message), _rtt) => {
+                Err(Failure::Unreachable(Some(message)))
+            }
+            (_source, Reply::Local, _rtt) => Err(Failure::NoRoute),
+        }
+    }
+}
+
+/// Datagram sockets only pass on ICMP errors about the requests they sent through the socket
+/// error queue, once IP_RECVERR is on.
+fn set_receive_errors(socket: &Socket, dst: IpAddr) -> std::io::Result<()> {
+    let (level, option) = match dst {
+        IpAddr::V4(_) => (libc::SOL_IP, libc::IP_RECVERR),
+        IpAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_RECVERR),
+    };
+    let on: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ip_header_len(packet: &[u8]) -> usize {
+    packet.first().map_or(0, |b| (b & 0x0f) as usize * 4)
+}
+
+fn skip_ip_header(packet: &[u8]) -> Option<&[u8]> {
+    packet.get(ip_header_len(packet)..)
+}
+
+/// The destination in the IPv4 or IPv6 header an ICMP error quotes.
+fn quoted_destination(header: &[u8]) -> Option<IpAddr> {
+    match header.first()? >> 4 {
+        4 => {
+            let octets: [u8; 4] = header.get(16..20)?.try_into().ok()?;
+            Some(Ipv4Addr::from(octets).into())
+        }
+        6 => {
+            let octets: [u8; 16] = header.get(24..40)?.try_into().ok()?;
+            Some(Ipv6Addr::from(octets).into())
+        }
+        _ => None,
+    }
+}
+
+fn socket_address(address: *const libc::sockaddr) -> Option<IpAddr> {
+    match unsafe { (*address).sa_family } as libc::c_int {
+        libc::AF_INET => {
+            let address = unsafe { &*(address as *const libc::sockaddr_in) };
+            Some(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+            let address = unsafe { &*(address as *const libc::sockaddr_in6) };
+            Some(Ipv6Addr::from(address.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
+
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => u16::from_be_bytes([*high, *low]) as u32,
+            [high] => (*high as u32) << 8,
+            _ => 0,
+        })
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A raw IPv4 prober for `dst` that is never used to send, so any socket will do.
+    fn prober(dst: [u8; 4], identifier: u16) -> IcmpSocketProber {
+        IcmpSocketProber {
+            dst: dst.into(),
+            socket: Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap(),
+            raw: true,
+            identifier,
+            sequence: 7,
+            sent: VecDeque::new(),
+            strays: Vec::new(),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// An unreachable error from a router, as a raw socket reads it, quoting a request to `dst`.
+    fn unreachable(dst: [u8; 4], identifier: u16, sequence: u16) -> Vec<u8> {
+        let header = |protocol: u8, source: [u8; 4], destination: [u8; 4]| {
+            let mut header = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0];
+            header.extend_from_slice(&source);
+            header.extend_from_slice(&destination);
+            header
+        };
+        let mut packet = header(1, [10, 0, 0, 1], [192, 168, 1, 2]);
+        packet.extend_from_slice(&[ICMPV4_UNREACHABLE, 1, 0, 0, 0, 0, 0, 0]);
+        packet.extend(header(1, [192, 168, 1, 2], dst));
+        packet.extend_from_slice(&[ICMPV4_ECHO_REQUEST, 0, 0, 0]);
+        packet.extend_from_slice(&identifier.to_be_bytes());
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn errors_about_other_targets_are_ignored() {
+        let router = [10, 0, 0, 1].into();
+        let google = prober([8, 8, 8, 8], 100);
+        let icmp = Icmp { kind: 3, code: 1 };
+        assert_eq!(
+            google.classify(router, &unreachable([8, 8, 8, 8], 100, 7)),
+            Some((Reply::Unreachable(icmp), 7))
+        );
+        // the same identifier and sequence, but about a request to another target
+        assert_eq!(
+            google.classify(router, &unreachable([9, 9, 9, 9], 100, 7)),
+            None
+        );
+        // another prober's request to the same target, such as a trace's
+        assert_eq!(
+            google.classify(router, &unreachable([8, 8, 8, 8], 101, 7)),
+            None
+        );
+    }
+}
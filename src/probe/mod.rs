@@ -0,0 +1,462 @@
+use anyhow::{bail, Context, Error, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::ProbeKind;
+use crate::Sample;
+
+mod dns;
+mod gateway;
+mod http;
+#[cfg(target_os = "linux")]
+mod linux;
+mod schedule;
+mod tcp;
+mod trace;
+#[cfg(windows)]
+mod windows;
+
+pub use dns::{system_resolvers, RecordType};
+pub use gateway::default_gateway;
+pub use schedule::Slot;
+pub use trace::{spawn_trace, Trace};
+
+/// How far back replies to earlier probes are still matched to the probe they answer.
+const RECENT_PROBES: usize = 16;
+
+/// Why a probe did not get a reply.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Failure {
+    /// Nothing came back before the probe timeout.
+    Timeout,
+    /// The destination answered but refused the probe, e.g. a TCP reset.
+    Refused,
+    /// This machine could not send the probe, e.g. because it has no route or the adapter is down.
+    NoRoute,
+    /// A router or the destination reported that the destination cannot be reached, with the
+    /// ICMP message when the prober sees it.
+    Unreachable(Option<Icmp>),
+    /// The probe ran out of hops on the way, e.g. in a routing loop.
+    TtlExpired(Option<Icmp>),
+    /// The resolver answered that the name does not exist.
+    NxDomain,
+    /// The resolver answered but could not resolve the name.
+    ServFail,
+    /// A web request was redirected or answered with a page, as a captive portal does.
+    CaptivePortal,
+    /// A web request got an unexpected answer, e.g. from a transparent proxy.
+    Intercepted,
+}
+
+impl Failure {
+    /// Interception means something on the path answered, so the connection itself is up.
+    pub fn is_interception(self) -> bool {
+        matches!(self, Failure::CaptivePortal | Failure::Intercepted)
+    }
+
+    /// The failure happened on this machine, so it says nothing about the ISP.
+    pub fn is_local(self) -> bool {
+        self == Failure::NoRoute
+    }
+
+    /// A name for the kind of failure that stays the same across versions, for exports.
+    pub fn kind(self) -> &'static str {
+        match self {
+            Failure::Timeout => "timeout",
+            Failure::Refused => "refused",
+            Failure::NoRoute => "no_route",
+            Failure::Unreachable(_) => "unreachable",
+            Failure::TtlExpired(_) => "ttl_expired",
+            Failure::NxDomain => "nxdomain",
+            Failure::ServFail => "servfail",
+            Failure::CaptivePortal => "captive_portal",
+            Failure::Intercepted => "intercepted",
+        }
+    }
+
+    /// The ICMP type and code a router sent back, if it sent one.
+    pub fn icmp(self) -> Option<Icmp> {
+        match self {
+            Failure::Unreachable(icmp) | Failure::TtlExpired(icmp) => icmp,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Timeout => "timeout",
+            Failure::Refused => "refused",
+            Failure::NoRoute => "no route",
+            Failure::Unreachable(None) => "unreachable",
+            Failure::Unreachable(Some(icmp)) => return write!(f, "unreachable ({})", icmp),
+            Failure::TtlExpired(None) => "ttl expired",
+            Failure::TtlExpired(Some(icmp)) => return write!(f, "ttl expired ({})", icmp),
+            Failure::NxDomain => "nxdomain",
+            Failure::ServFail => "servfail",
+            Failure::CaptivePortal => "captive portal",
+            Failure::Intercepted => "intercepted",
+        })
+    }
+}
+
+impl FromStr for Failure {
+    type Err = Error;
+
+    /// Reads a failure back from how it is displayed, as it is in logs and the sample journal.
+    fn from_str(text: &str) -> Result<Self> {
+        let (name, icmp) = match text
+            .strip_suffix(')')
+            .and_then(|text| text.split_once(" ("))
+        {
+            Some((name, icmp)) => (name, Some(icmp.parse()?)),
+            None => (text, None),
+        };
+        Ok(match (name, icmp) {
+            ("timeout", None) => Failure::Timeout,
+            ("refused", None) => Failure::Refused,
+            ("no route", None) => Failure::NoRoute,
+            ("unreachable", icmp) => Failure::Unreachable(icmp),
+            ("ttl expired", icmp) => Failure::TtlExpired(icmp),
+            ("nxdomain", None) => Failure::NxDomain,
+            ("servfail", None) => Failure::ServFail,
+            ("captive portal", None) => Failure::CaptivePortal,
+            ("intercepted", None) => Failure::Intercepted,
+            _ => bail!("'{}' is not a probe failure", text),
+        })
+    }
+}
+
+/// The type and code of an ICMP error message. Their meaning depends on the IP version.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Icmp {
+    pub kind: u8,
+    pub code: u8,
+}
+
+impl fmt::Display for Icmp {
+    /// Without commas, since failures are written to comma separated logs.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ICMP type {} code {}", self.kind, self.code)
+    }
+}
+
+impl FromStr for Icmp {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text.split(' ').collect::<Vec<_>>()[..] {
+            ["ICMP", "type", kind, "code", code] => Ok(Icmp {
+                kind: kind.parse()?,
+                code: code.parse()?,
+            }),
+            _ => bail!("'{}' is not an ICMP type and code", text),
+        }
+    }
+}
+
+/// The IP version of an address. IPv4 and IPv6 are routed separately and can fail on their own.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(address: IpAddr) -> Family {
+        match address {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        })
+    }
+}
+
+impl FromStr for Family {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Ok(match text {
+            "IPv4" => Family::V4,
+            "IPv6" => Family::V6,
+            _ => bail!("'{}' is not an IP version", text),
+        })
+    }
+}
+
+/// A target as its samples name it: the address probed and how. A resolver can be an ICMP
+/// target and a DNS target at once, and those are told apart by the kind.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Endpoint {
+    pub address: IpAddr,
+    pub kind: ProbeKind,
+}
+
+impl Endpoint {
+    pub fn family(self) -> Family {
+        Family::of(self.address)
+    }
+}
+
+impl From<IpAddr> for Endpoint {
+    /// An ICMP target, which is what every target was before there were other kinds.
+    fn from(address: IpAddr) -> Self {
+        Endpoint {
+            address,
+            kind: ProbeKind::Icmp,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    /// The address, followed by the kind for anything but ICMP, e.g. `8.8.8.8 dns`, so logs
+    /// written before there were other kinds read the same.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ProbeKind::Icmp => write!(f, "{}", self.address),
+            kind => write!(f, "{} {}", self.address, kind),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (address, kind) = match text.split_once(' ') {
+            Some((address, kind)) => (address, kind.parse()?),
+            None => (text, ProbeKind::Icmp),
+        };
+        match address.parse() {
+            Ok(address) => Ok(Endpoint { address, kind }),
+            Err(_) => bail!("'{}' is not an address", text),
+        }
+    }
+}
+
+/// A reply to an earlier probe that came in after that probe's sample was sent, either late or
+/// a duplicate. `timestamp` is the timestamp of that sample.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stray {
+    pub endpoint: Endpoint,
+    pub timestamp: u128,
+    pub rtt: u32,
+}
+
+/// Sends probes to a single destination.
+///
+/// The loop in `spawn` drives a prober on its schedule and turns each result into a `Sample`, so
+/// every backend produces the same samples regardless of how it talks to the network.
+pub trait Prober: Send {
+    /// The address probed.
+    fn address(&self) -> IpAddr;
+
+    /// How the address is probed.
+    fn kind(&self) -> ProbeKind;
+
+    /// The target as the samples from this prober name it.
+    fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            address: self.address(),
+            kind: self.kind(),
+        }
+    }
+
+    /// Sends one probe and waits for the reply. Returns the round trip time in microseconds.
+    fn probe(&mut self) -> Result<u32, Failure>;
+
+    /// Replies to earlier probes seen while waiting for the latest one, each as how many probes
+    /// before the latest it answers and its round trip time. Backends that match replies to
+    /// requests themselves never see any.
+    fn stray_replies(&mut self) -> Vec<(u16, u32)> {
+        Vec::new()
+    }
+
+    /// Sends one probe that may cross at most `ttl` routers. Returns the address that answered,
+    /// which is the router where the probe ran out of hops if it did not get through.
+    fn probe_ttl(&mut self, _ttl: u8) -> Result<(IpAddr, u32), Failure> {
+        Err(Failure::Unreachable(None))
+    }
+}
+
+/// Creates the ICMP echo prober for the current platform. `address` is an IP address or host name.
+pub fn icmp(address: &str, timeout: Duration) -> Result<Box<dyn Prober>> {
+    let dst = resolve(address, 0)?.ip();
+    new_icmp_prober(dst, timeout)
+}
+
+#[cfg(windows)]
+fn new_icmp_prober(dst: IpAddr, timeout: Duration) -> Result<Box<dyn Prober>> {
+    Ok(Box::new(windows::WinPingProber::new(dst, timeout)?))
+}
+
+#[cfg(target_os = "linux")]
+fn new_icmp_prober(dst: IpAddr, timeout: Duration) -> Result<Box<dyn Prober>> {
+    Ok(Box::new(linux::IcmpSocketProber::new(dst, timeout)?))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn new_icmp_prober(_dst: IpAddr, _timeout: Duration) -> Result<Box<dyn Prober>> {
+    anyhow::bail!("ICMP probes are not supported on this platform")
+}
+
+/// Creates a prober that times the TCP handshake to `address`, given as `host:port`.
+pub fn tcp(address: &str, timeout: Duration) -> Result<Box<dyn Prober>> {
+    let dst = address
+        .to_socket_addrs()
+        .context(format!(
+            "'{}' is not a host:port address that resolves",
+            address
+        ))?
+        .next()
+        .context(format!("no addresses for '{}'", address))?;
+    Ok(Box::new(tcp::TcpProber::new(dst, timeout)))
+}
+
+/// Creates a prober that times a query for `name` to `resolver`, given as `host` or `host:port`.
+pub fn dns(
+    resolver: &str,
+    name: &str,
+    record: RecordType,
+    timeout: Duration,
+) -> Result<Box<dyn Prober>> {
+    let resolver = match resolver.to_socket_addrs() {
+        Ok(mut addresses) => addresses
+            .next()
+            .context(format!("no addresses for '{}'", resolver))?,
+        Err(_) => resolve(resolver, dns::DNS_PORT)?,
+    };
+    Ok(Box::new(dns::DnsProber::new(
+        resolver, name, record, timeout,
+    )))
+}
+
+/// Creates a prober that fetches `url`, which should answer with an empty 204.
+pub fn http(url: &str, timeout: Duration) -> Result<Box<dyn Prober>> {
+    Ok(Box::new(http::HttpProber::new(url, timeout)?))
+}
+
+/// The failure an I/O error from a socket stands for.
+fn io_failure(err: &io::Error) -> Failure {
+    match err.kind() {
+        ErrorKind::ConnectionRefused => Failure::Refused,
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => Failure::Timeout,
+        // this machine's own network stack had nowhere to send the probe
+        ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown | ErrorKind::AddrNotAvailable => {
+            Failure::NoRoute
+        }
+        _ => Failure::Unreachable(None),
+    }
+}
+
+/// A round trip time in microseconds. Probers time with `Instant`, so changes to the wall clock
+/// cannot skew it.
+fn micros(elapsed: Duration) -> u32 {
+    elapsed.as_micros().min(u32::MAX as u128) as u32
+}
+
+/// A zeroed buffer of at least `size` bytes for the IP Helper functions to fill in. It is made
+/// of u64s so the pointers inside the structures they write are aligned.
+#[cfg(windows)]
+fn aligned_buffer(size: u32) -> Vec<u64> {
+    vec![0; size as usize / std::mem::size_of::<u64>() + 1]
+}
+
+/// Looks up a host name or parses an IP address, using the first address found.
+fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (host, port)
+        .to_socket_addrs()
+        .context(format!("unable to resolve '{}'", host))?
+        .next()
+        .context(format!("no addresses for '{}'", host))
+}
+
+/// Whether this machine has a route to `address`. Connecting a UDP socket looks the route up
+/// without sending anything.
+pub fn has_route(address: IpAddr) -> bool {
+    let unspecified: IpAddr = match address {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    UdpSocket::bind((unspecified, 0))
+        .and_then(|socket| socket.connect((address, dns::DNS_PORT)))
+        .is_ok()
+}
+
+/// Runs `prober` on its own thread, sending a sample whenever `slot` says a probe is due until
+/// the receiver is dropped. Late and duplicate replies to earlier probes go to `strays`.
+pub fn spawn(
+    mut prober: Box<dyn Prober>,
+    sender: Sender<Sample>,
+    strays: Sender<Stray>,
+    mut slot: Slot,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut recent = VecDeque::with_capacity(RECENT_PROBES);
+        loop {
+            slot.wait();
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let ping_response = prober.probe();
+            slot.finished(ping_response.is_ok());
+            if recent.len() == RECENT_PROBES {
+                recent.pop_front();
+            }
+            recent.push_back(timestamp);
+            if let Err(_err) = sender.send((prober.endpoint(), timestamp, ping_response)) {
+                break; // stop the loop if there is an error.
+            }
+            for (age, rtt) in prober.stray_replies() {
+                let index = recent.len().checked_sub(1 + age as usize);
+                if let Some(timestamp) = index.and_then(|index| recent.get(index)) {
+                    let stray = Stray {
+                        endpoint: prober.endpoint(),
+                        timestamp: *timestamp,
+                        rtt,
+                    };
+                    let _ = strays.send(stray);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_read_back_as_displayed() {
+        let icmp = Some(Icmp { kind: 3, code: 1 });
+        for failure in [
+            Failure::Timeout,
+            Failure::NoRoute,
+            Failure::Unreachable(None),
+            Failure::Unreachable(icmp),
+            Failure::TtlExpired(icmp),
+            Failure::CaptivePortal,
+        ] {
+            assert_eq!(failure.to_string().parse::<Failure>().unwrap(), failure);
+        }
+        assert!("timeout (ICMP type 3 code 1)".parse::<Failure>().is_err());
+        assert!("lost".parse::<Failure>().is_err());
+    }
+}
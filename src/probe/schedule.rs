@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// No provider is probed more often than once per second, however the schedule changes.
+const MIN_SPACING: Duration = Duration::from_millis(1000);
+/// Failing targets are probed as often as allowed this long, then half as often each time this
+/// passes again.
+const BACKOFF_AFTER: Duration = Duration::from_secs(60);
+/// Slowest a failing target is probed, so the end of a long outage is still seen within seconds.
+const MAX_BACKOFF_INTERVAL: Duration = Duration::from_secs(10);
+
+struct TargetState {
+    address: IpAddr,
+    interval: Duration,
+    failing_since: Option<Instant>,
+}
+
+struct State {
+    targets: Vec<TargetState>,
+    /// When each provider is next due to be probed, by any of its targets.
+    reserved: HashMap<IpAddr, Instant>,
+}
+
+impl State {
+    /// The first time at or after `wanted` that keeps probes to the target's provider a second
+    /// apart, which is then held for it.
+    fn reserve(&mut self, index: usize, wanted: Instant) -> Instant {
+        let address = self.targets[index].address;
+        let due = match self.reserved.get(&address) {
+            Some(last) => wanted.max(*last + MIN_SPACING),
+            None => wanted,
+        };
+        self.reserved.insert(address, due);
+        due
+    }
+
+    /// Records whether the target's latest probe got through, returning how long to wait from
+    /// that probe to the next.
+    fn record(&mut self, index: usize, succeeded: bool, now: Instant) -> Duration {
+        let target = &mut self.targets[index];
+        target.failing_since = match (succeeded, target.failing_since) {
+            (true, _) => None,
+            (false, None) => Some(now),
+            (false, since) => since,
+        };
+        let interval = target.interval;
+        if let Some(since) = target.failing_since {
+            // a long outage: halve the rate for each further `BACKOFF_AFTER` it lasts
+            let periods = (now - since).as_secs() / BACKOFF_AFTER.as_secs();
+            if periods == 0 {
+                return MIN_SPACING;
+            }
+            let backoff = interval * 2u32.saturating_pow(periods.min(16) as u32);
+            return backoff.min(MAX_BACKOFF_INTERVAL).max(interval);
+        }
+        // another target failing: probe the path that still works as often as allowed to tell
+        // whether the connection is going down, and when it is back
+        if self
+            .targets
+            .iter()
+            .any(|other| other.failing_since.is_some())
+        {
+            return MIN_SPACING;
+        }
+        interval
+    }
+}
+
+/// One target's place in the shared probe schedule, used by its prober thread.
+pub struct Slot {
+    state: Arc<Mutex<State>>,
+    index: usize,
+    next: Instant,
+    started: Instant,
+}
+
+impl Slot {
+    /// Creates a slot for each target, given as its address and interval. The first probes are
+    /// spread evenly across each target's interval so the targets take turns.
+    pub fn stagger(targets: &[(IpAddr, Duration)]) -> Vec<Slot> {
+        let state = Arc::new(Mutex::new(State {
+            targets: targets
+                .iter()
+                .map(|(address, interval)| TargetState {
+                    address: *address,
+                    interval: *interval,
+                    failing_since: None,
+                })
+                .collect(),
+            reserved: HashMap::new(),
+        }));
+        let now = Instant::now();
+        let count = targets.len() as u32;
+        targets
+            .iter()
+            .enumerate()
+            .map(|(index, (_address, interval))| {
+                let offset = *interval * index as u32 / count;
+                Slot {
+                    state: state.clone(),
+                    index,
+                    next: now + offset,
+                    started: now,
+                }
+            })
+            .collect()
+    }
+
+    /// Sleeps until this target's next probe is due.
+    pub fn wait(&mut self) {
+        let due = self.state.lock().unwrap().reserve(self.index, self.next);
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        self.started = due;
+    }
+
+    /// Schedules the next probe from the start of the one that just finished, so a slow or timed
+    /// out probe does not push the following ones back.
+    pub fn finished(&mut self, succeeded: bool) {
+        let now = Instant::now();
+        let delay = self
+            .state
+            .lock()
+            .unwrap()
+            .record(self.index, succeeded, now);
+        self.next = (self.started + delay).max(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(4);
+
+    fn address(last: u8) -> IpAddr {
+        [10, 0, 0, last].into()
+    }
+
+    fn state(addresses: &[u8]) -> State {
+        State {
+            targets: addresses
+                .iter()
+                .map(|last| TargetState {
+                    address: address(*last),
+                    interval: INTERVAL,
+                    failing_since: None,
+                })
+                .collect(),
+            reserved: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn first_probes_are_spread_across_the_interval() {
+        let targets: Vec<_> = (1..=4).map(|last| (address(last), INTERVAL)).collect();
+        let slots = Slot::stagger(&targets);
+        let offsets: Vec<_> = slots.iter().map(|slot| slot.next - slot.started).collect();
+        assert_eq!(offsets, [0, 1, 2, 3].map(Duration::from_secs).to_vec());
+    }
+
+    #[test]
+    fn a_provider_is_probed_at_most_once_a_second() {
+        let mut state = state(&[1, 1]);
+        let now = Instant::now();
+        assert_eq!(state.reserve(0, now), now);
+        assert_eq!(state.reserve(1, now), now + MIN_SPACING);
+        let later = now + Duration::from_secs(5);
+        assert_eq!(state.reserve(0, later), later);
+    }
+
+    #[test]
+    fn surviving_targets_speed_up_after_a_failure() {
+        let mut state = state(&[1, 2]);
+        let now = Instant::now();
+        assert_eq!(state.record(1, true, now), INTERVAL);
+        assert_eq!(state.record(0, false, now), MIN_SPACING);
+        assert_eq!(state.record(1, true, now), MIN_SPACING);
+        assert_eq!(state.record(0, true, now), INTERVAL);
+        assert_eq!(state.record(1, true, now), INTERVAL);
+    }
+
+    #[test]
+    fn long_failures_back_off() {
+        let mut state = state(&[1]);
+        let now = Instant::now();
+        state.record(0, false, now);
+        let minutes = |count| now + BACKOFF_AFTER * count;
+        assert_eq!(state.record(0, false, minutes(1)), INTERVAL * 2);
+        assert_eq!(state.record(0, false, minutes(2)), MAX_BACKOFF_INTERVAL);
+        assert_eq!(state.record(0, false, minutes(100)), MAX_BACKOFF_INTERVAL);
+    }
+}
@@ -0,0 +1,81 @@
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use super::{io_failure, micros, Failure, ProbeKind, Prober};
+
+/// Times the TCP three-way handshake, for networks that drop or deprioritise ICMP.
+///
+/// The connection is closed as soon as it is established so no application data is sent.
+pub struct TcpProber {
+    dst: SocketAddr,
+    timeout: Duration,
+}
+
+impl TcpProber {
+    pub fn new(dst: SocketAddr, timeout: Duration) -> Self {
+        Self { dst, timeout }
+    }
+}
+
+impl Prober for TcpProber {
+    fn address(&self) -> IpAddr {
+        self.dst.ip()
+    }
+
+    fn kind(&self) -> ProbeKind {
+        ProbeKind::Tcp
+    }
+
+    fn probe(&mut self) -> Result<u32, Failure> {
+        let start = Instant::now();
+        match TcpStream::connect_timeout(&self.dst, self.timeout) {
+            Ok(_stream) => Ok(micros(start.elapsed())),
+            Err(err) => Err(io_failure(&err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_millis(2000);
+
+    #[test]
+    fn connects_to_local_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut prober = TcpProber::new(listener.local_addr().unwrap(), TIMEOUT);
+        assert!(prober.probe().is_ok());
+        assert_eq!(prober.address(), "127.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn closed_port_is_refused() {
+        let dst = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut prober = TcpProber::new(dst, TIMEOUT);
+        assert_eq!(prober.probe(), Err(Failure::Refused));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn full_accept_queue_times_out() {
+        use socket2::{Domain, Socket, Type};
+
+        // Linux drops SYNs once a listener's accept queue is full, like a filtered port.
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let dst = listener.local_addr().unwrap().as_socket().unwrap();
+        let mut prober = TcpProber::new(dst, Duration::from_millis(200));
+        let _queued: Vec<_> = (0..2)
+            .map(|_| TcpStream::connect_timeout(&dst, Duration::from_millis(200)))
+            .collect();
+        assert_eq!(prober.probe(), Err(Failure::Timeout));
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::Result;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Failure;
+use crate::utils;
+
+/// Most routes across the Internet are well under this many hops.
+const MAX_HOPS: u8 = 30;
+/// Every hop past a break in the path is silent, so a few in a row are enough to show where it is.
+const MAX_SILENT_HOPS: u8 = 3;
+const HOP_TIMEOUT_MILLIS: u64 = 1000;
+
+/// One step of a trace: the router or destination that answered a probe limited to `ttl` hops.
+#[derive(Clone, Debug)]
+pub struct Hop {
+    pub ttl: u8,
+    pub reply: Result<(IpAddr, u32), Failure>,
+}
+
+/// The route toward a target as far as it could be followed.
+#[derive(Clone, Debug)]
+pub struct Trace {
+    pub target: IpAddr,
+    /// When the trace started, in nanoseconds like sample timestamps.
+    pub taken: u128,
+    pub hops: Vec<Hop>,
+}
+
+impl fmt::Display for Trace {
+    /// Keeps to one line without commas so it fits in a timeouts log record.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trace to {}:", self.target)?;
+        for (index, hop) in self.hops.iter().enumerate() {
+            let separator = if index == 0 { "" } else { ";" };
+            match hop.reply {
+                Ok((address, rtt)) => write!(
+                    f,
+                    "{} {} {} {:.2}ms",
+                    separator,
+                    hop.ttl,
+                    address,
+                    utils::millis(rtt)
+                )?,
+                Err(Failure::Timeout) => write!(f, "{} {} *", separator, hop.ttl)?,
+                Err(failure) => write!(f, "{} {} {}", separator, hop.ttl, failure)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Follows the route to `target` one hop at a time with ICMP echo requests.
+pub fn trace(target: IpAddr) -> Result<Trace> {
+    let timeout = Duration::from_millis(HOP_TIMEOUT_MILLIS);
+    let mut prober = super::new_icmp_prober(target, timeout)?;
+    let taken = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let mut hops = Vec::new();
+    let mut silent = 0;
+    for ttl in 1..=MAX_HOPS {
+        let reply = prober.probe_ttl(ttl);
+        let done = match reply {
+            Ok((address, _rtt)) => address == target,
+            Err(Failure::Timeout) => {
+                silent += 1;
+                silent == MAX_SILENT_HOPS
+            }
+            // a router said the target cannot be reached, or the probe could not be sent at all
+            Err(_) => true,
+        };
+        if reply.is_ok() {
+            silent = 0;
+        }
+        hops.push(Hop { ttl, reply });
+        if done {
+            break;
+        }
+    }
+    Ok(Trace {
+        target,
+        taken,
+        hops,
+    })
+}
+
+/// Traces the route to `target` on its own thread, since that takes up to a second per hop.
+pub fn spawn_trace(target: IpAddr, sender: Sender<Result<Trace>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let _ = sender.send(trace(target));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_formats_on_one_line() {
+        let trace = Trace {
+            target: [8, 8, 8, 8].into(),
+            taken: 0,
+            hops: vec![
+                Hop {
+                    ttl: 1,
+                    reply: Ok(([192, 168, 1, 1].into(), 2_345)),
+                },
+                Hop {
+                    ttl: 2,
+                    reply: Err(Failure::Timeout),
+                },
+                Hop {
+                    ttl: 3,
+                    reply: Err(Failure::Unreachable(None)),
+                },
+            ],
+        };
+        assert_eq!(
+            trace.to_string(),
+            "trace to 8.8.8.8: 1 192.168.1.1 2.35ms; 2 *; 3 unreachable"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn loopback_is_one_hop_away() {
+        let trace = trace([127, 0, 0, 1].into()).unwrap();
+        assert_eq!(trace.hops.len(), 1);
+        assert_eq!(
+            trace.hops[0].reply.map(|(address, _rtt)| address),
+            Ok([127, 0, 0, 1].into())
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{bail, Result};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use winping::{Buffer, CreateError, Pinger};
+
+use super::{micros, Failure, Icmp, ProbeKind, Prober};
+
+/// What `Pinger` starts out with.
+const DEFAULT_TTL: u8 = 255;
+
+/// ICMP echo through the Windows ICMP helper API (`IcmpSendEcho`).
+pub struct WinPingProber {
+    dst: IpAddr,
+    pinger: Pinger,
+    buffer: Buffer,
+}
+
+// The pinger only holds ICMP handles from IcmpCreateFile, which are not tied to the creating thread.
+unsafe impl Send for WinPingProber {}
+
+impl WinPingProber {
+    pub fn new(dst: IpAddr, timeout: Duration) -> Result<Self> {
+        let mut pinger = match Pinger::new() {
+            Ok(pinger) => pinger,
+            // still usable for the address family that did initialize
+            Err(CreateError::NoV4(pinger)) | Err(CreateError::NoV6(pinger)) => pinger,
+            Err(err) => bail!("{}", err),
+        };
+        pinger.set_timeout(timeout.as_millis() as u32);
+        Ok(Self {
+            dst,
+            pinger,
+            buffer: Buffer::new(),
+        })
+    }
+
+    /// The failure a ping error stands for. The helper API names the ICMP message that came back
+    /// rather than passing it on, so its type and code are filled back in here.
+    fn failure(&self, err: winping::Error) -> Failure {
+        let icmp = |v4: (u8, u8), v6: (u8, u8)| {
+            let (kind, code) = if self.dst.is_ipv4() { v4 } else { v6 };
+            Some(Icmp { kind, code })
+        };
+        match err {
+            winping::Error::Timeout => Failure::Timeout,
+            winping::Error::NetUnreachable => Failure::Unreachable(icmp((3, 0), (1, 0))),
+            winping::Error::HostUnreachable => Failure::Unreachable(icmp((3, 1), (1, 3))),
+            winping::Error::ProtocolUnreachable => Failure::Unreachable(icmp((3, 2), (4, 1))),
+            winping::Error::NeedsFragmented => Failure::Unreachable(icmp((3, 4), (2, 0))),
+            winping::Error::TtlExpired => Failure::TtlExpired(icmp((11, 0), (3, 0))),
+            winping::Error::ReassemblyExpired => Failure::TtlExpired(icmp((11, 1), (3, 1))),
+            // e.g. IP_GENERAL_FAILURE, which is what a disconnected adapter gives
+            winping::Error::Other(_) => Failure::NoRoute,
+        }
+    }
+}
+
+impl Prober for WinPingProber {
+    fn address(&self) -> IpAddr {
+        self.dst
+    }
+
+    fn kind(&self) -> ProbeKind {
+        ProbeKind::Icmp
+    }
+
+    /// Times the call itself, since the round trip time ICMP.dll reports is in whole milliseconds.
+    fn probe(&mut self) -> Result<u32, Failure> {
+        let start = Instant::now();
+        match self.pinger.send(self.dst, &mut self.buffer) {
+            Ok(_rtt) => Ok(micros(start.elapsed())),
+            Err(err) => Err(self.failure(err)),
+        }
+    }
+
+    fn probe_ttl(&mut self, ttl: u8) -> Result<(IpAddr, u32), Failure> {
+        self.pinger.set_ttl(ttl);
+        let start = Instant::now();
+        let result = self.pinger.send(self.dst, &mut self.buffer);
+        let rtt = micros(start.elapsed());
+        self.pinger.set_ttl(DEFAULT_TTL);
+        match result {
+            Ok(_rtt) => Ok((self.dst, rtt)),
+            // the reply still records which router sent the TTL expired message
+            Err(winping::Error::TtlExpired) => match self.buffer.responding_ip() {
+                Some(router) => Ok((router, rtt)),
+                None => Err(self.failure(winping::Error::TtlExpired)),
+            },
+            Err(err) => Err(self.failure(err)),
+        }
+    }
+}
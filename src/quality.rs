@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::probe::{Endpoint, Failure, Stray};
+use crate::Sample;
+
+/// Loss and jitter are worked out over the last minute of samples.
+const WINDOW_NANOS: u128 = 60 * 1_000_000_000;
+/// The gain RFC 3550 uses for its jitter estimate.
+const JITTER_GAIN: f32 = 1.0 / 16.0;
+
+/// What became of one probe.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Outcome {
+    Answered(u32),
+    /// Answered, but only after the probe had timed out.
+    Late(u32),
+    Lost,
+    /// Something answered, but not with a round trip time, e.g. a DNS error or a captive portal.
+    Refused,
+}
+
+struct Probe {
+    timestamp: u128,
+    outcome: Outcome,
+    /// A reply to a later probe came in first.
+    reordered: bool,
+}
+
+#[derive(Default)]
+struct TargetWindow {
+    probes: VecDeque<Probe>,
+    duplicates: VecDeque<u128>,
+}
+
+impl TargetWindow {
+    fn prune(&mut self, now: u128) {
+        let oldest = now.saturating_sub(WINDOW_NANOS);
+        while self
+            .probes
+            .front()
+            .is_some_and(|probe| probe.timestamp < oldest)
+        {
+            self.probes.pop_front();
+        }
+        while self.duplicates.front().is_some_and(|time| *time < oldest) {
+            self.duplicates.pop_front();
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        let mut summary = Summary {
+            sent: self.probes.len() as u32,
+            duplicates: self.duplicates.len() as u32,
+            ..Default::default()
+        };
+        let mut previous = None;
+        for probe in &self.probes {
+            match probe.outcome {
+                Outcome::Answered(rtt) => {
+                    // late replies would show up as a jump in the transit time, so leave them out
+                    if let Some(previous) = previous {
+                        let difference = (rtt as f32 - previous as f32).abs() / 1_000.0;
+                        summary.jitter += (difference - summary.jitter) * JITTER_GAIN;
+                    }
+                    previous = Some(rtt);
+                }
+                Outcome::Late(_rtt) => summary.late += 1,
+                Outcome::Lost => summary.lost += 1,
+                Outcome::Refused => {}
+            }
+            if probe.reordered {
+                summary.reordered += 1;
+            }
+        }
+        summary
+    }
+}
+
+/// Probe counts and jitter over the window, for one target or for all of them.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub sent: u32,
+    /// Probes that never got a reply. Late replies are not counted as lost.
+    pub lost: u32,
+    pub late: u32,
+    pub duplicates: u32,
+    /// Late replies that came in after the reply to a later probe.
+    pub reordered: u32,
+    /// RFC 3550 interarrival jitter of the round trip times, in milliseconds.
+    pub jitter: f32,
+}
+
+impl Summary {
+    pub fn loss_percent(&self) -> f32 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.lost as f32 * 100.0 / self.sent as f32
+    }
+}
+
+/// What a stray reply turned out to be.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StrayKind {
+    Late,
+    Duplicate,
+}
+
+/// Packet loss and jitter per target over a rolling window.
+#[derive(Default)]
+pub struct Quality {
+    targets: HashMap<Endpoint, TargetWindow>,
+}
+
+impl Quality {
+    pub fn record(&mut self, sample: Sample) {
+        let (endpoint, timestamp, result) = sample;
+        let outcome = match result {
+            Ok(rtt) => Outcome::Answered(rtt),
+            Err(Failure::Timeout)
+            | Err(Failure::NoRoute)
+            | Err(Failure::Unreachable(_))
+            | Err(Failure::TtlExpired(_)) => Outcome::Lost,
+            Err(_) => Outcome::Refused,
+        };
+        let window = self.targets.entry(endpoint).or_default();
+        window.probes.push_back(Probe {
+            timestamp,
+            outcome,
+            reordered: false,
+        });
+        window.prune(timestamp);
+    }
+
+    /// Matches a reply up with the earlier probe it answers. A reply to a probe that timed out
+    /// means the probe was late rather than lost; any other is a duplicate.
+    pub fn record_stray(&mut self, stray: Stray) -> Option<StrayKind> {
+        let window = self.targets.get_mut(&stray.endpoint)?;
+        let index = window
+            .probes
+            .iter()
+            .position(|probe| probe.timestamp == stray.timestamp)?;
+        let answered_since = window
+            .probes
+            .iter()
+            .skip(index + 1)
+            .any(|probe| matches!(probe.outcome, Outcome::Answered(_)));
+        let probe = &mut window.probes[index];
+        if probe.outcome == Outcome::Lost {
+            probe.outcome = Outcome::Late(stray.rtt);
+            probe.reordered = answered_since;
+            return Some(StrayKind::Late);
+        }
+        window.duplicates.push_back(stray.timestamp);
+        Some(StrayKind::Duplicate)
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))] // only the GUI's samples log breaks it down
+    pub fn summary(&self, endpoint: Endpoint) -> Summary {
+        self.targets
+            .get(&endpoint)
+            .map(TargetWindow::summary)
+            .unwrap_or_default()
+    }
+
+    /// Counts for all targets together, with the jitter averaged over the targets that have one.
+    pub fn overall(&self) -> Summary {
+        let mut overall = Summary::default();
+        let mut with_jitter = 0;
+        for summary in self.targets.values().map(TargetWindow::summary) {
+            overall.sent += summary.sent;
+            overall.lost += summary.lost;
+            overall.late += summary.late;
+            overall.duplicates += summary.duplicates;
+            overall.reordered += summary.reordered;
+            if summary.jitter > 0.0 {
+                overall.jitter += summary.jitter;
+                with_jitter += 1;
+            }
+        }
+        if with_jitter > 0 {
+            overall.jitter /= with_jitter as f32;
+        }
+        overall
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u128 = 1_000_000_000;
+
+    fn endpoint() -> Endpoint {
+        std::net::IpAddr::from([10, 0, 0, 1]).into()
+    }
+
+    fn sample(second: u128, result: Result<u32, Failure>) -> Sample {
+        (endpoint(), second * SECOND, result)
+    }
+
+    fn stray(second: u128, rtt: u32) -> Stray {
+        Stray {
+            endpoint: endpoint(),
+            timestamp: second * SECOND,
+            rtt,
+        }
+    }
+
+    #[test]
+    fn late_replies_are_not_lost() {
+        let mut quality = Quality::default();
+        quality.record(sample(0, Err(Failure::Timeout)));
+        quality.record(sample(1, Err(Failure::Timeout)));
+        quality.record(sample(2, Ok(20)));
+        assert_eq!(
+            quality.record_stray(stray(0, 2_500_000)),
+            Some(StrayKind::Late)
+        );
+        let summary = quality.summary(endpoint());
+        assert_eq!((summary.sent, summary.lost, summary.late), (3, 1, 1));
+        assert_eq!(summary.reordered, 1);
+        assert!((summary.loss_percent() - 100.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn second_reply_is_a_duplicate() {
+        let mut quality = Quality::default();
+        quality.record(sample(0, Ok(20)));
+        assert_eq!(
+            quality.record_stray(stray(0, 21)),
+            Some(StrayKind::Duplicate)
+        );
+        assert_eq!(quality.summary(endpoint()).duplicates, 1);
+        assert_eq!(quality.record_stray(stray(5, 21)), None);
+    }
+
+    #[test]
+    fn jitter_follows_rfc_3550() {
+        let mut quality = Quality::default();
+        for (second, rtt) in [10_000, 20_000, 20_000].iter().enumerate() {
+            quality.record(sample(second as u128, Ok(*rtt)));
+        }
+        // J = 0 + (10 ms - 0) / 16, then J + (0 - J) / 16
+        let jitter = quality.summary(endpoint()).jitter;
+        assert!((jitter - 0.625 * 15.0 / 16.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn old_samples_leave_the_window() {
+        let mut quality = Quality::default();
+        quality.record(sample(0, Err(Failure::Timeout)));
+        quality.record(sample(61, Ok(20)));
+        let summary = quality.summary(endpoint());
+        assert_eq!((summary.sent, summary.lost), (1, 0));
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Error, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::history::{self, Bucket};
+use crate::stats::Stats;
+
+const MINUTE: u128 = 60 * 1_000_000_000;
+/// Each window is kept in this many buckets, so it reaches back at most a 60th further than its
+/// length and moves on in steps of a 60th.
+const BUCKETS: u128 = 60;
+
+/// How far back the latency stats in the status bar go.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Window {
+    Minute,
+    #[default]
+    QuarterHour,
+    Hour,
+    Day,
+}
+
+impl Window {
+    pub const ALL: [Window; 4] = [
+        Window::Minute,
+        Window::QuarterHour,
+        Window::Hour,
+        Window::Day,
+    ];
+
+    /// The length of the window in nanoseconds.
+    pub fn length(self) -> u128 {
+        match self {
+            Window::Minute => MINUTE,
+            Window::QuarterHour => 15 * MINUTE,
+            Window::Hour => 60 * MINUTE,
+            Window::Day => 24 * 60 * MINUTE,
+        }
+    }
+
+    fn width(self) -> u128 {
+        self.length() / BUCKETS
+    }
+
+    fn index(self) -> usize {
+        Window::ALL
+            .iter()
+            .position(|window| *window == self)
+            .unwrap()
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Window::Minute => "1m",
+            Window::QuarterHour => "15m",
+            Window::Hour => "1h",
+            Window::Day => "24h",
+        })
+    }
+}
+
+impl FromStr for Window {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match Window::ALL.iter().find(|window| window.to_string() == text) {
+            Some(window) => Ok(*window),
+            None => bail!("'{}' is not a window, use 1m, 15m, 1h or 24h", text),
+        }
+    }
+}
+
+/// Round trip times over every `Window` up to the latest sample, all kept at once so the status
+/// bar can switch between them. Old buckets are dropped as new ones start, so memory use and the
+/// work to sum a window stay the same however long contrac runs.
+#[derive(Default)]
+pub struct Rolling {
+    buckets: [VecDeque<Bucket>; Window::ALL.len()],
+    latest: u128,
+}
+
+impl Rolling {
+    pub fn record(&mut self, timestamp: u128, rtt: Option<u64>) {
+        self.latest = self.latest.max(timestamp);
+        for (window, buckets) in Window::ALL.iter().zip(self.buckets.iter_mut()) {
+            history::add(buckets, window.width(), timestamp, rtt);
+            let oldest = self.latest.saturating_sub(window.length());
+            while buckets
+                .front()
+                .is_some_and(|bucket| bucket.start + window.width() <= oldest)
+            {
+                buckets.pop_front();
+            }
+        }
+    }
+
+    /// The stats of the samples in `window`.
+    pub fn stats(&self, window: Window) -> Stats<u64> {
+        let mut stats = Stats::default();
+        for bucket in &self.buckets[window.index()] {
+            stats.merge(&bucket.stats);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u128 = 1_000_000_000;
+
+    #[test]
+    fn windows_forget_older_samples() {
+        let mut rolling = Rolling::default();
+        rolling.record(0, Some(500));
+        rolling.record(10 * MINUTE, Some(100));
+        rolling.record(30 * MINUTE, Some(20));
+        rolling.record(30 * MINUTE + 30 * SECOND, Some(10));
+        rolling.record(30 * MINUTE + 30 * SECOND, None);
+        let summary = |rolling: &Rolling, window| {
+            let stats = rolling.stats(window);
+            (stats.count, stats.min, stats.max, stats.failures)
+        };
+        assert_eq!(summary(&rolling, Window::Minute), (2, 10, 20, 1));
+        assert_eq!(summary(&rolling, Window::QuarterHour), (2, 10, 20, 1));
+        assert_eq!(summary(&rolling, Window::Hour), (4, 10, 500, 1));
+        assert_eq!(summary(&rolling, Window::Day), (4, 10, 500, 1));
+        // a day on, only the latest sample is left in any window
+        rolling.record(25 * 60 * MINUTE, Some(30));
+        for window in Window::ALL {
+            assert_eq!(summary(&rolling, window), (1, 30, 30, 0), "{}", window);
+            assert!(rolling.buckets[window.index()].len() <= BUCKETS as usize + 1);
+        }
+    }
+
+    #[test]
+    fn windows_are_named_as_in_the_configuration() {
+        for window in Window::ALL {
+            assert_eq!(window.to_string().parse::<Window>().unwrap(), window);
+        }
+        assert!("2h".parse::<Window>().is_err());
+    }
+}
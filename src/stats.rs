@@ -1,34 +1,114 @@
 
-#[derive(Copy, Clone)]
+/// Values are kept to 7 significant bits, so each bucket is at most 1/64th as wide as the
+/// values in it.
+const SIGNIFICANT_BITS: u32 = 7;
+const HALF_BUCKETS: u64 = 1 << (SIGNIFICANT_BITS - 1);
+
+/// Counts values in buckets whose width grows with the values, as in an HDR histogram, so a
+/// percentile is within 1% of the true value whether the values are microseconds or seconds.
+/// Only the buckets in use are kept, so a histogram per second of samples stays small.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    /// Bucket indexes and counts, in order.
+    counts: Vec<(u16, u64)>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        self.add(index(value), 1);
+    }
+
+    fn add(&mut self, index: u16, count: u64) {
+        match self
+            .counts
+            .binary_search_by_key(&index, |(index, _count)| *index)
+        {
+            Ok(found) => self.counts[found].1 += count,
+            Err(position) => self.counts.insert(position, (index, count)),
+        }
+        self.total += count;
+    }
+
+    /// Adds in the values counted by another histogram.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (index, count) in &other.counts {
+            self.add(*index, *count);
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// The value that `percent` of the values are at or below, e.g. 99.9 for the 99.9th
+    /// percentile, to within the width of its bucket.
+    pub fn percentile(&self, percent: f64) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((percent / 100.0 * self.total as f64).ceil() as u64).clamp(1, self.total);
+        let mut seen = 0;
+        self.counts
+            .iter()
+            .find(|(_index, count)| {
+                seen += count;
+                seen >= rank
+            })
+            .map(|(index, _count)| middle(*index))
+    }
+}
+
+/// The bucket `value` goes in: values below `2 * HALF_BUCKETS` have one each, and each
+/// doubling after that is split into `HALF_BUCKETS`.
+fn index(value: u64) -> u16 {
+    let shift = (64 - value.leading_zeros()).saturating_sub(SIGNIFICANT_BITS);
+    (HALF_BUCKETS * shift as u64 + (value >> shift)) as u16
+}
+
+/// The value in the middle of bucket `index`.
+fn middle(index: u16) -> u64 {
+    let index = index as u64;
+    let shift = (index / HALF_BUCKETS).saturating_sub(1);
+    let lowest = (index - HALF_BUCKETS * shift) << shift;
+    lowest + (1 << shift) / 2
+}
+
+#[derive(Clone)]
 pub struct Stats<T> {
     pub total: T,
     pub min: T,
     pub max: T,
     pub count: T,
+    /// Samples without a round trip time.
+    pub failures: T,
     pub timeout: bool,
+    /// The values, for percentiles.
+    pub distribution: Histogram,
 }
 
 impl<T: num::Integer + num::Bounded + Copy> Default for Stats<T> {
     fn default() -> Self {
         let zero = T::zero();
         let max = T::max_value();
-        let result = Stats::<T> {
+        Stats::<T> {
             total: zero,
             min: max,
             max: zero,
             count: zero,
+            failures: zero,
             timeout: false,
-        };
-        result
+            distribution: Histogram::default(),
+        }
     }
 }
 
 impl<T> Stats<T>
 where
-    T: num::Integer + num::Bounded + std::ops::AddAssign + Copy, // + std::ops::Add<Output = T>
+    T: num::Integer + num::Bounded + std::ops::AddAssign + Copy + Into<u64>, // + std::ops::Add<Output = T>
 {
     pub fn average(&self) -> Option<T> {
-        if self.count == T::zero() { return None; } else {return Some(self.total / self.count)}
+        if self.count == T::zero() { None } else { Some(self.total / self.count) }
     }
 
     pub fn update(&mut self, value: Option<T>) {
@@ -39,19 +119,94 @@ where
             if value > self.max {
                 self.max = value
             }
-            self.count = self.count + T::one();
+            self.count += T::one();
             self.total += value;
+            self.distribution.record(value.into());
         } else {
+            self.failures += T::one();
             self.timeout = true;
         }
     }
 
+    /// Adds in stats kept over another stretch of samples.
+    pub fn merge(&mut self, other: &Stats<T>) {
+        if other.min < self.min {
+            self.min = other.min;
+        }
+        if other.max > self.max {
+            self.max = other.max;
+        }
+        self.count += other.count;
+        self.total += other.total;
+        self.failures += other.failures;
+        self.timeout |= other.timeout;
+        self.distribution.merge(&other.distribution);
+    }
+
     pub fn _clear(&mut self) {
         let zero = T::zero();
         self.total = zero;
         self.min = T::max_value();
         self.max = zero;
         self.count = zero;
+        self.failures = zero;
         self.timeout = false;
+        self.distribution = Histogram::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_every_value() {
+        let mut previous = 0;
+        for value in (0..100_000).chain([u32::MAX as u64, u64::MAX]) {
+            let index = index(value);
+            assert!(index >= previous);
+            previous = index;
+            let middle = middle(index);
+            assert!(
+                middle.abs_diff(value) <= value / 64,
+                "{} in {}",
+                value,
+                middle
+            );
+        }
+    }
+
+    #[test]
+    fn percentiles_ignore_the_odd_spike() {
+        let mut stats = Stats::<u64>::default();
+        for rtt in 1..=999 {
+            stats.update(Some(10_000 + rtt));
+        }
+        stats.update(Some(1_500_000));
+        let histogram = &stats.distribution;
+        let near = |percent, expected: u64| {
+            let value = histogram.percentile(percent).unwrap();
+            assert!(
+                value.abs_diff(expected) <= expected / 100,
+                "{}: {}",
+                percent,
+                value
+            );
+        };
+        near(50.0, 10_500);
+        near(90.0, 10_900);
+        near(99.0, 10_990);
+        near(100.0, 1_500_000);
+        // merging is the same as having counted everything in one
+        let mut merged = Histogram::default();
+        let mut other = Histogram::default();
+        for value in 0..1000 {
+            merged.record(value);
+            other.record(value + 1000);
+        }
+        merged.merge(&other);
+        assert_eq!(merged.count(), 2000);
+        assert!(merged.percentile(50.0).unwrap().abs_diff(1000) <= 10);
+        assert_eq!(Histogram::default().percentile(50.0), None);
     }
 }
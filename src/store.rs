@@ -0,0 +1,774 @@
+use anyhow::{bail, Context, Error, Result};
+use chrono::{DateTime, TimeZone};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::import::TimeoutLine;
+use crate::probe::{Endpoint, Failure};
+use crate::{utils, LoggedEvent, Sample};
+
+/// Bumped, with a migration, whenever the tables change.
+const SCHEMA_VERSION: i64 = 2;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    host TEXT NOT NULL,
+    started INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS targets (
+    id INTEGER PRIMARY KEY,
+    session INTEGER NOT NULL REFERENCES sessions(id),
+    name TEXT NOT NULL,
+    address TEXT NOT NULL,
+    gateway INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS samples (
+    target INTEGER NOT NULL REFERENCES targets(id),
+    timestamp INTEGER NOT NULL,
+    rtt INTEGER,
+    outcome TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS samples_by_time ON samples(timestamp);
+CREATE TABLE IF NOT EXISTS outages (
+    id INTEGER PRIMARY KEY,
+    session INTEGER NOT NULL REFERENCES sessions(id),
+    kind TEXT NOT NULL,
+    family TEXT,
+    address TEXT,
+    started INTEGER NOT NULL,
+    ended INTEGER NOT NULL,
+    fault TEXT,
+    failures TEXT NOT NULL,
+    evidence TEXT
+);
+CREATE INDEX IF NOT EXISTS outages_by_time ON outages(started);
+";
+
+/// What `samples.outcome` holds for a probe that got its reply; failures are stored as they
+/// are displayed.
+const ANSWERED: &str = "ok";
+
+/// Samples and outages kept in a SQLite database, from every run of contrac on every machine
+/// that writes to it, so they can be looked up by time, host, target and outcome.
+///
+/// Times are stored as nanoseconds since the epoch and round trip times in microseconds. Each
+/// run is a session, with the host it ran on and its targets.
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: &Path) -> Result<Store> {
+        let connection =
+            Connection::open(path).context(format!("unable to open '{}'", path.display()))?;
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            bail!(
+                "'{}' was written by a newer contrac (schema {})",
+                path.display(),
+                version
+            );
+        }
+        // samples arrive a few a second; syncing at checkpoints rather than on every one is
+        // plenty, and the journal has them anyway
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA).context(format!(
+            "unable to create the tables in '{}'",
+            path.display()
+        ))?;
+        if version == 1 {
+            // outages gained the evidence for their fault
+            connection.execute_batch("ALTER TABLE outages ADD COLUMN evidence TEXT")?;
+        }
+        connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(Store { connection })
+    }
+
+    /// Starts recording a run of contrac on `host`, begun at `started`, probing `targets`,
+    /// given as name, address and kind of probe, and whether it is the gateway.
+    pub fn start_session(
+        self,
+        host: &str,
+        started: u128,
+        targets: &[(String, Endpoint, bool)],
+    ) -> Result<Session> {
+        self.connection.execute(
+            "INSERT INTO sessions (host, started) VALUES (?1, ?2)",
+            params![host, started as i64],
+        )?;
+        let mut session = Session {
+            id: self.connection.last_insert_rowid(),
+            store: self,
+            targets: HashMap::new(),
+        };
+        for (name, endpoint, gateway) in targets {
+            session.add_target(name, *endpoint, *gateway)?;
+        }
+        Ok(session)
+    }
+
+    /// The stored session of the run on `host` that started at `started`, or a new one if
+    /// there is none, for logs of that run to be imported into.
+    pub fn import_session(self, host: &str, started: u128) -> Result<Session> {
+        let id: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT id FROM sessions WHERE host = ?1 AND started = ?2",
+                params![host, started as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let id = match id {
+            Some(id) => id,
+            None => return self.start_session(host, started, &[]),
+        };
+        let targets = {
+            let mut statement = self
+                .connection
+                .prepare("SELECT address, id FROM targets WHERE session = ?1")?;
+            let rows = statement.query_map([id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?;
+            let mut targets = HashMap::new();
+            for row in rows {
+                let (endpoint, target) = row?;
+                targets.insert(endpoint.parse().context("bad target address")?, target);
+            }
+            targets
+        };
+        Ok(Session {
+            store: self,
+            id,
+            targets,
+        })
+    }
+
+    /// The samples that match `query`, oldest first. `longer_than` does not apply to samples.
+    pub fn samples(&self, query: &Query) -> Result<Vec<StoredSample>> {
+        let mut sql = String::from(
+            "SELECT sessions.host, targets.name, targets.address, samples.timestamp, samples.rtt,
+                samples.outcome
+            FROM samples
+            JOIN targets ON samples.target = targets.id
+            JOIN sessions ON targets.session = sessions.id
+            WHERE 1",
+        );
+        let mut values = Vec::new();
+        let mut condition = |clause: &str, value: Value| {
+            sql.push_str(" AND ");
+            sql.push_str(clause);
+            values.push(value);
+        };
+        if let Some(from) = query.from {
+            condition("samples.timestamp >= ?", Value::Integer(from as i64));
+        }
+        if let Some(to) = query.to {
+            condition("samples.timestamp < ?", Value::Integer(to as i64));
+        }
+        if let Some(host) = &query.host {
+            condition("sessions.host = ?", Value::Text(host.clone()));
+        }
+        if let Some(target) = &query.target {
+            condition(
+                "? IN (targets.name, targets.address)",
+                Value::Text(target.clone()),
+            );
+        }
+        match query.outcome {
+            None => {}
+            Some(Outcome::Answered) => {
+                condition("samples.outcome = ?", Value::Text(ANSWERED.to_string()))
+            }
+            Some(Outcome::Failed) => {
+                condition("samples.outcome != ?", Value::Text(ANSWERED.to_string()))
+            }
+            Some(Outcome::Failure(failure)) => {
+                condition("samples.outcome = ?", Value::Text(failure.to_string()))
+            }
+        }
+        sql.push_str(" ORDER BY samples.timestamp");
+        let mut statement = self.connection.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| Ok(sample_row(row)))?;
+        rows.map(|row| row?).collect()
+    }
+
+    /// The outages, degraded providers and interceptions that match `query`, by when they
+    /// started. The time range takes in those that overlap it; `target` picks the degraded
+    /// providers and interceptions of that target, and `outcome` does not apply.
+    pub fn outages(&self, query: &Query) -> Result<Vec<StoredOutage>> {
+        let mut sql = String::from(
+            "SELECT sessions.host, outages.kind, outages.family, outages.address,
+                outages.started, outages.ended, outages.fault, outages.failures,
+                outages.evidence
+            FROM outages
+            JOIN sessions ON outages.session = sessions.id
+            WHERE 1",
+        );
+        let mut values = Vec::new();
+        let mut condition = |clause: &str, value: Value| {
+            sql.push_str(" AND ");
+            sql.push_str(clause);
+            values.push(value);
+        };
+        if let Some(from) = query.from {
+            condition("outages.ended >= ?", Value::Integer(from as i64));
+        }
+        if let Some(to) = query.to {
+            condition("outages.started < ?", Value::Integer(to as i64));
+        }
+        if let Some(host) = &query.host {
+            condition("sessions.host = ?", Value::Text(host.clone()));
+        }
+        if let Some(target) = &query.target {
+            condition(
+                "outages.address IN (SELECT address FROM targets WHERE ? IN (name, address))",
+                Value::Text(target.clone()),
+            );
+        }
+        if let Some(longer_than) = query.longer_than {
+            condition(
+                "outages.ended - outages.started > ?",
+                Value::Integer(longer_than.as_nanos() as i64),
+            );
+        }
+        sql.push_str(" ORDER BY outages.started");
+        let mut statement = self.connection.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| {
+            Ok(StoredOutage {
+                host: row.get(0)?,
+                kind: row.get(1)?,
+                family: row.get(2)?,
+                address: row.get(3)?,
+                start: row.get::<_, i64>(4)? as u128,
+                end: row.get::<_, i64>(5)? as u128,
+                fault: row.get(6)?,
+                failures: row.get(7)?,
+                evidence: row.get(8)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+fn sample_row(row: &Row) -> Result<StoredSample> {
+    let endpoint: String = row.get(2)?;
+    let rtt: Option<i64> = row.get(4)?;
+    let outcome: String = row.get(5)?;
+    let result = match rtt {
+        Some(rtt) => Ok(rtt as u32),
+        None => Err(outcome.parse()?),
+    };
+    Ok(StoredSample {
+        host: row.get(0)?,
+        target: row.get(1)?,
+        sample: (
+            endpoint.parse().context("bad target address")?,
+            row.get::<_, i64>(3)? as u128,
+            result,
+        ),
+    })
+}
+
+/// One run of contrac being recorded.
+pub struct Session {
+    store: Store,
+    id: i64,
+    targets: HashMap<Endpoint, i64>,
+}
+
+impl Session {
+    /// Stops recording, to start another session in the same database.
+    pub fn close(self) -> Store {
+        self.store
+    }
+
+    /// Whether any samples are stored for this session.
+    pub fn has_samples(&self) -> Result<bool> {
+        Ok(self.store.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM samples JOIN targets ON samples.target = targets.id
+            WHERE targets.session = ?1)",
+            [self.id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Whether any outages, degraded providers or interceptions are stored for this session.
+    pub fn has_outages(&self) -> Result<bool> {
+        Ok(self.store.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM outages WHERE session = ?1)",
+            [self.id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Runs `write` as one transaction, which is much quicker when storing many records.
+    pub fn batch(&mut self, write: impl FnOnce(&mut Session) -> Result<()>) -> Result<()> {
+        self.store.connection.execute_batch("BEGIN")?;
+        let result = write(self);
+        let end = if result.is_ok() { "COMMIT" } else { "ROLLBACK" };
+        self.store.connection.execute_batch(end)?;
+        result
+    }
+
+    /// Targets' addresses are stored as samples name them, followed by the kind of probe unless
+    /// it is ICMP.
+    fn add_target(&mut self, name: &str, endpoint: Endpoint, gateway: bool) -> Result<i64> {
+        self.store.connection.execute(
+            "INSERT INTO targets (session, name, address, gateway) VALUES (?1, ?2, ?3, ?4)",
+            params![self.id, name, endpoint.to_string(), gateway],
+        )?;
+        let id = self.store.connection.last_insert_rowid();
+        self.targets.insert(endpoint, id);
+        Ok(id)
+    }
+
+    pub fn record_sample(&mut self, sample: Sample) -> Result<()> {
+        let (endpoint, timestamp, result) = sample;
+        let target = match self.targets.get(&endpoint) {
+            Some(target) => *target,
+            None => self.add_target(&endpoint.to_string(), endpoint, false)?,
+        };
+        let (rtt, outcome) = match result {
+            Ok(rtt) => (Some(rtt), ANSWERED.to_string()),
+            Err(failure) => (None, failure.to_string()),
+        };
+        self.store.connection.execute(
+            "INSERT INTO samples (target, timestamp, rtt, outcome) VALUES (?1, ?2, ?3, ?4)",
+            params![target, timestamp as i64, rtt, outcome],
+        )?;
+        Ok(())
+    }
+
+    /// Stores an outage, brownout, degraded provider or interception. Times contrac was not
+    /// running are left out, since the sessions already show them.
+    pub fn record_event(&mut self, event: &LoggedEvent) -> Result<()> {
+        let outage = match event {
+            LoggedEvent::Outage {
+                family,
+                start,
+                end,
+                fault,
+                failures,
+                evidence,
+            } => {
+                let failures: Vec<_> = failures.iter().map(Failure::to_string).collect();
+                StoredOutage {
+                    kind: OUTAGE.to_string(),
+                    family: Some(family.to_string()),
+                    fault: Some(fault.to_string()),
+                    failures: failures.join("; "),
+                    evidence: Some(evidence.to_string()),
+                    ..StoredOutage::new(start, end)
+                }
+            }
+            LoggedEvent::Degraded {
+                endpoint,
+                start,
+                end,
+            } => StoredOutage {
+                kind: DEGRADED.to_string(),
+                address: Some(endpoint.to_string()),
+                ..StoredOutage::new(start, end)
+            },
+            LoggedEvent::Brownout {
+                family,
+                start,
+                end,
+                peak,
+            } => StoredOutage {
+                kind: BROWNOUT.to_string(),
+                family: Some(family.to_string()),
+                fault: Some(peak.severity().to_string()),
+                failures: peak.to_string(),
+                ..StoredOutage::new(start, end)
+            },
+            LoggedEvent::Interception {
+                endpoint,
+                start,
+                end,
+                failure,
+            } => StoredOutage {
+                kind: INTERCEPTION.to_string(),
+                address: Some(endpoint.to_string()),
+                failures: failure.to_string(),
+                ..StoredOutage::new(start, end)
+            },
+            LoggedEvent::NotRunning { .. } => return Ok(()),
+        };
+        self.record_outage(&outage)
+    }
+
+    /// Stores a line of a timeouts log. Outages from logs that leave out the fault, IP version
+    /// or evidence are stored without them.
+    pub fn record_line(&mut self, line: &TimeoutLine) -> Result<()> {
+        let (start, end) = line.span();
+        let outage = match line {
+            TimeoutLine::Outage {
+                fault,
+                family,
+                failures,
+                evidence,
+                ..
+            } => {
+                let failures: Vec<_> = failures.iter().map(Failure::to_string).collect();
+                StoredOutage {
+                    kind: OUTAGE.to_string(),
+                    family: family.map(|family| family.to_string()),
+                    fault: fault.map(|fault| fault.to_string()),
+                    failures: failures.join("; "),
+                    evidence: evidence.clone(),
+                    ..StoredOutage::new(&start, &end)
+                }
+            }
+            TimeoutLine::Degraded { endpoint, .. } => StoredOutage {
+                kind: DEGRADED.to_string(),
+                address: Some(endpoint.to_string()),
+                ..StoredOutage::new(&start, &end)
+            },
+            TimeoutLine::Brownout { family, peak, .. } => StoredOutage {
+                kind: BROWNOUT.to_string(),
+                family: Some(family.to_string()),
+                fault: Some(peak.severity().to_string()),
+                failures: peak.to_string(),
+                ..StoredOutage::new(&start, &end)
+            },
+            TimeoutLine::Interception {
+                failure, endpoint, ..
+            } => StoredOutage {
+                kind: INTERCEPTION.to_string(),
+                address: endpoint.map(|endpoint| endpoint.to_string()),
+                failures: failure.to_string(),
+                ..StoredOutage::new(&start, &end)
+            },
+            TimeoutLine::NotRunning { .. } => return Ok(()),
+        };
+        self.record_outage(&outage)
+    }
+
+    /// Stores an outage as part of this session, whichever host `outage` names.
+    fn record_outage(&mut self, outage: &StoredOutage) -> Result<()> {
+        self.store.connection.execute(
+            "INSERT INTO outages
+                (session, kind, family, address, started, ended, fault, failures, evidence)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                self.id,
+                outage.kind,
+                outage.family,
+                outage.address,
+                outage.start as i64,
+                outage.end as i64,
+                outage.fault,
+                outage.failures,
+                outage.evidence
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// What `outages.kind` holds for each kind of event.
+const OUTAGE: &str = "outage";
+const DEGRADED: &str = "degraded";
+const BROWNOUT: &str = "brownout";
+const INTERCEPTION: &str = "interception";
+
+/// Which samples and outages to look up. Everything is matched when nothing is set.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// Sample timestamps from `from` up to `to`.
+    pub from: Option<u128>,
+    pub to: Option<u128>,
+    /// The host name contrac ran on.
+    pub host: Option<String>,
+    /// A target's name, or its address followed by the kind of probe unless it is ICMP, e.g.
+    /// `8.8.8.8 dns`.
+    pub target: Option<String>,
+    pub outcome: Option<Outcome>,
+    /// Outages that lasted longer than this.
+    pub longer_than: Option<Duration>,
+}
+
+/// Which samples to look up by how their probe went.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Answered,
+    Failed,
+    Failure(Failure),
+}
+
+impl FromStr for Outcome {
+    type Err = Error;
+
+    /// `ok`, `failed`, or one way of failing as it is displayed, e.g. `timeout`.
+    fn from_str(text: &str) -> Result<Self> {
+        Ok(match text {
+            ANSWERED => Outcome::Answered,
+            "failed" => Outcome::Failed,
+            _ => Outcome::Failure(text.parse()?),
+        })
+    }
+}
+
+pub struct StoredSample {
+    pub host: String,
+    pub target: String,
+    pub sample: Sample,
+}
+
+impl fmt::Display for StoredSample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (endpoint, timestamp, result) = self.sample;
+        write!(
+            f,
+            "{}, {}, {} ({}), ",
+            self.host,
+            utils::timestamp_to_datetime(timestamp),
+            self.target,
+            endpoint
+        )?;
+        match result {
+            Ok(rtt) => write!(f, "{:.3}", utils::millis(rtt)),
+            Err(failure) => write!(f, "{}", failure),
+        }
+    }
+}
+
+/// An outage, brownout, degraded provider or interception as stored.
+pub struct StoredOutage {
+    pub host: String,
+    /// `outage`, `brownout`, `degraded` or `interception`.
+    pub kind: String,
+    /// The IP version that was down or browned out.
+    pub family: Option<String>,
+    /// The target that was degraded or intercepted.
+    pub address: Option<String>,
+    pub start: u128,
+    pub end: u128,
+    /// Where an outage was, or how severe a brownout was.
+    pub fault: Option<String>,
+    /// How the probes failed, separated by `; `, or a brownout's highest latency and loss.
+    pub failures: String,
+    /// How the gateway and each target answered during an outage.
+    pub evidence: Option<String>,
+}
+
+impl StoredOutage {
+    /// An outage of no particular kind from `start` to `end`, for the kind and details to be
+    /// filled in.
+    pub fn new<T: TimeZone>(start: &DateTime<T>, end: &DateTime<T>) -> StoredOutage {
+        StoredOutage {
+            host: String::new(),
+            kind: String::new(),
+            family: None,
+            address: None,
+            start: utils::datetime_to_timestamp(start),
+            end: utils::datetime_to_timestamp(end),
+            fault: None,
+            failures: String::new(),
+            evidence: None,
+        }
+    }
+}
+
+impl fmt::Display for StoredOutage {
+    /// The host followed by the line the timeouts log has for it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}, {}, ",
+            self.host,
+            utils::timestamp_to_datetime(self.start),
+            utils::timestamp_to_datetime(self.end),
+            ((self.end - self.start) / 1_000_000) as f32 / 1_000.0
+        )?;
+        let fault = self.fault.as_deref().unwrap_or_default();
+        let family = self.family.as_deref().unwrap_or_default();
+        let address = self.address.as_deref().unwrap_or_default();
+        match self.kind.as_str() {
+            OUTAGE => {
+                write!(f, "{}, {}, {}", fault, family, self.failures)?;
+                match &self.evidence {
+                    Some(evidence) => write!(f, ", evidence: {}", evidence),
+                    None => Ok(()),
+                }
+            }
+            DEGRADED => write!(f, "provider degraded, {}", address),
+            BROWNOUT => write!(f, "brownout, {}, {}, {}", family, fault, self.failures),
+            _ => write!(f, "{}, {}", self.failures, address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brownout::Peak;
+    use crate::fault::{Evidence, Fault, Tally, TargetTally};
+    use crate::probe::Family;
+
+    const SECOND: u128 = 1_000_000_000;
+
+    /// One resolver, probed both with ICMP and as a DNS server.
+    fn one() -> Endpoint {
+        "10.0.0.1".parse().unwrap()
+    }
+
+    fn two() -> Endpoint {
+        "10.0.0.1 dns".parse().unwrap()
+    }
+
+    fn session(store: Store, host: &str) -> Session {
+        let targets = [
+            ("One".to_string(), one(), false),
+            ("Two".to_string(), two(), false),
+        ];
+        store.start_session(host, 0, &targets).unwrap()
+    }
+
+    #[test]
+    fn samples_are_found_by_time_target_and_outcome() {
+        let mut session = session(Store::open(Path::new(":memory:")).unwrap(), "desk");
+        for second in 0..4 {
+            session
+                .record_sample((one(), second * SECOND, Ok(1500)))
+                .unwrap();
+            session
+                .record_sample((two(), second * SECOND, Err(Failure::Timeout)))
+                .unwrap();
+        }
+        let store = &session.store;
+        let query = Query {
+            from: Some(SECOND),
+            to: Some(3 * SECOND),
+            target: Some("One".to_string()),
+            ..Default::default()
+        };
+        let found = store.samples(&query).unwrap();
+        assert_eq!(
+            found.iter().map(|stored| stored.sample).collect::<Vec<_>>(),
+            vec![(one(), SECOND, Ok(1500)), (one(), 2 * SECOND, Ok(1500))]
+        );
+        let failed = Query {
+            outcome: Some("timeout".parse().unwrap()),
+            ..Default::default()
+        };
+        let found = store.samples(&failed).unwrap();
+        assert_eq!(found.len(), 4);
+        assert!(found.iter().all(|stored| stored.target == "Two"));
+        assert_eq!(
+            found[0].to_string(),
+            format!(
+                "desk, {}, Two (10.0.0.1 dns), timeout",
+                utils::timestamp_to_datetime(0)
+            )
+        );
+    }
+
+    #[test]
+    fn outages_are_found_by_length_and_host() {
+        let mut session = session(Store::open(Path::new(":memory:")).unwrap(), "desk");
+        let time = |second| utils::timestamp_to_datetime(second * SECOND);
+        for (start, end) in [(10, 20), (100, 160)] {
+            let event = LoggedEvent::Outage {
+                family: Family::V4,
+                start: time(start),
+                end: time(end),
+                fault: Fault::UpstreamIsp,
+                failures: vec![Failure::Timeout, Failure::NoRoute],
+                evidence: Evidence {
+                    gateway: Tally {
+                        answered: 3,
+                        sent: 3,
+                    },
+                    targets: vec![TargetTally {
+                        endpoint: one(),
+                        tally: Tally {
+                            answered: 0,
+                            sent: 9,
+                        },
+                    }],
+                },
+            };
+            session.record_event(&event).unwrap();
+        }
+        let event = LoggedEvent::Degraded {
+            endpoint: two(),
+            start: time(30),
+            end: time(40),
+        };
+        session.record_event(&event).unwrap();
+        let event = LoggedEvent::Brownout {
+            family: Family::V4,
+            start: time(50),
+            end: time(55),
+            peak: Peak {
+                latency_ratio: 2.5,
+                loss: 0.01,
+            },
+        };
+        session.record_event(&event).unwrap();
+        let store = session.store;
+        let long = Query {
+            longer_than: Some(Duration::from_secs(30)),
+            host: Some("desk".to_string()),
+            ..Default::default()
+        };
+        let found = store.outages(&long).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].to_string(),
+            format!(
+                "desk, {}, {}, 60, upstream ISP, IPv4, timeout; no route, \
+                 evidence: gateway 3/3; 10.0.0.1 0/9",
+                time(100),
+                time(160)
+            )
+        );
+        let degraded = Query {
+            target: Some("Two".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(store.outages(&degraded).unwrap()[0].kind, DEGRADED);
+        let elsewhere = Query {
+            host: Some("laptop".to_string()),
+            ..Default::default()
+        };
+        assert!(store.outages(&elsewhere).unwrap().is_empty());
+        let all = store.outages(&Query::default()).unwrap();
+        let brownout = all.iter().find(|outage| outage.kind == BROWNOUT).unwrap();
+        assert!(brownout
+            .to_string()
+            .ends_with(", 5, brownout, IPv4, mild, latency 2.5x, loss 1.0%"));
+    }
+
+    #[test]
+    fn outages_stored_before_evidence_still_read() {
+        let path = std::env::temp_dir().join(format!("contrac-store-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let old = Connection::open(&path).unwrap();
+        old.execute_batch(&SCHEMA.replace(",\n    evidence TEXT", ""))
+            .unwrap();
+        old.execute_batch(
+            "INSERT INTO sessions (host, started) VALUES ('desk', 0);
+            INSERT INTO outages (session, kind, started, ended, fault, failures)
+            VALUES (1, 'outage', 0, 1000000000, 'upstream ISP', 'timeout');
+            PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        drop(old);
+        let store = Store::open(&path).unwrap();
+        let found = store.outages(&Query::default()).unwrap();
+        assert_eq!(found[0].evidence, None);
+        assert!(found[0].to_string().ends_with(", upstream ISP, , timeout"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
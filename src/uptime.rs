@@ -0,0 +1,458 @@
+use chrono::{Datelike, Duration, NaiveDate};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+
+use crate::probe::Endpoint;
+use crate::stats::Histogram;
+use crate::{utils, Sample};
+
+/// A stretch of time between two timestamps in nanoseconds since the epoch.
+pub type Span = (u128, u128);
+
+const SECOND: u128 = 1_000_000_000;
+const MINUTE: u128 = 60 * SECOND;
+const HOUR: u128 = 60 * MINUTE;
+const DAY: u128 = 24 * HOUR;
+/// Outages are counted by length: under a minute, up to a quarter of an hour, up to an hour and
+/// longer.
+const SEVERITY_LIMITS: [u128; 3] = [MINUTE, 15 * MINUTE, HOUR];
+const SEVERITY_LABELS: [&str; 4] = ["<1m", "1-15m", "15-60m", ">1h"];
+/// Local days start on a quarter of an hour in every time zone, so histograms this wide add up
+/// to any day, week or month.
+const LATENCY_WIDTH: u128 = 15 * MINUTE;
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// The terms of a service level agreement, with availabilities and shares as fractions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sla {
+    /// The availability promised for each month.
+    pub availability: f64,
+    pub monthly_price: f64,
+    /// The share of the monthly price credited when the month's availability is below each
+    /// level. Without any, the credit is the price of the downtime beyond what the agreement
+    /// allows.
+    pub credits: Vec<(f64, f64)>,
+}
+
+impl Sla {
+    /// The credit owed for a month with `availability`, or nothing if the agreement was met.
+    pub fn credit(&self, availability: f64) -> Option<f64> {
+        if availability >= self.availability {
+            return None;
+        }
+        let share = if self.credits.is_empty() {
+            self.availability - availability
+        } else {
+            self.credits
+                .iter()
+                .filter(|(below, _share)| availability < *below)
+                .map(|(_below, share)| *share)
+                .fold(0.0, f64::max)
+        };
+        Some(self.monthly_price * share)
+    }
+}
+
+/// The outages over the time the journal covers, and the times within it that contrac was not
+/// running.
+pub struct Record {
+    /// In order, with overlapping outages, such as those of IPv4 and IPv6 together, merged.
+    outages: Vec<Span>,
+    absent: Vec<Span>,
+    covered: Span,
+}
+
+impl Record {
+    pub fn new(
+        outages: impl IntoIterator<Item = Span>,
+        absent: impl IntoIterator<Item = Span>,
+        covered: Span,
+    ) -> Self {
+        let mut sorted: Vec<_> = outages.into_iter().collect();
+        sorted.sort_unstable();
+        let mut merged: Vec<Span> = Vec::new();
+        for (start, end) in sorted {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Self {
+            outages: merged,
+            absent: absent.into_iter().collect(),
+            covered,
+        }
+    }
+
+    /// The figures for the part of `span` that the journal covers.
+    pub fn period(&self, label: String, span: Span) -> Period {
+        let window = (span.0.max(self.covered.0), span.1.min(self.covered.1));
+        let length = window.1.saturating_sub(window.0);
+        let mut outages = [0; 4];
+        for (start, end) in &self.outages {
+            if (span.0..span.1).contains(start) {
+                let severity = SEVERITY_LIMITS
+                    .iter()
+                    .take_while(|limit| end - start >= **limit)
+                    .count();
+                outages[severity] += 1;
+            }
+        }
+        Period {
+            label,
+            observed: length.saturating_sub(overlap(&self.absent, window)),
+            down: overlap(&self.outages, window),
+            outages,
+        }
+    }
+}
+
+/// How much of `window` the spans, which do not overlap, cover.
+fn overlap(spans: &[Span], window: Span) -> u128 {
+    spans
+        .iter()
+        .map(|(start, end)| (*end).min(window.1).saturating_sub((*start).max(window.0)))
+        .sum()
+}
+
+/// The figures for one day, week or month.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Period {
+    pub label: String,
+    /// How long contrac was watching, in nanoseconds.
+    pub observed: u128,
+    pub down: u128,
+    /// How many outages began in the period, by severity.
+    pub outages: [usize; 4],
+}
+
+impl Period {
+    pub fn availability(&self) -> Option<f64> {
+        (self.observed > 0).then(|| 1.0 - self.down as f64 / self.observed as f64)
+    }
+
+    /// The number of nines in the availability, e.g. 3 for 99.9%. There is none to give
+    /// without downtime.
+    pub fn nines(&self) -> Option<f64> {
+        self.availability()
+            .filter(|availability| *availability < 1.0)
+            .map(|availability| -(1.0 - availability).log10())
+    }
+
+    pub fn count(&self) -> usize {
+        self.outages.iter().sum()
+    }
+
+    /// The mean time between failures: how long the connection stayed up, on average.
+    pub fn mtbf(&self) -> Option<u128> {
+        let count = self.count() as u128;
+        (count > 0).then(|| (self.observed - self.down.min(self.observed)) / count)
+    }
+
+    /// The mean time to repair: how long an outage lasted, on average.
+    pub fn mttr(&self) -> Option<u128> {
+        let count = self.count() as u128;
+        (count > 0).then(|| self.down / count)
+    }
+}
+
+/// The round trip times of each target, in a histogram for each `LATENCY_WIDTH`.
+#[derive(Default)]
+pub struct Latency {
+    histograms: HashMap<Endpoint, BTreeMap<u128, Histogram>>,
+}
+
+impl Latency {
+    pub fn record(&mut self, sample: Sample) {
+        if let (endpoint, timestamp, Ok(rtt)) = sample {
+            self.histograms
+                .entry(endpoint)
+                .or_default()
+                .entry(timestamp - timestamp % LATENCY_WIDTH)
+                .or_default()
+                .record(rtt.into());
+        }
+    }
+
+    /// Each target's round trip times within `span`.
+    pub fn during(&self, span: Span) -> BTreeMap<Endpoint, Histogram> {
+        let mut during = BTreeMap::new();
+        for (endpoint, histograms) in &self.histograms {
+            let mut merged = Histogram::default();
+            for histogram in histograms
+                .range(span.0..span.1)
+                .map(|(_start, histogram)| histogram)
+            {
+                merged.merge(histogram);
+            }
+            if merged.count() > 0 {
+                during.insert(*endpoint, merged);
+            }
+        }
+        during
+    }
+}
+
+/// Splits the days from `from` to `to`, inclusive, into days, ISO weeks or calendar months, each
+/// cut short at the ends. Labels and spans follow local time.
+fn periods(
+    from: NaiveDate,
+    to: NaiveDate,
+    next: impl Fn(NaiveDate) -> NaiveDate,
+    label: impl Fn(NaiveDate) -> String,
+) -> Vec<(String, Span)> {
+    let end = to.succ_opt().unwrap_or(to);
+    let mut periods = Vec::new();
+    let mut start = from;
+    while start < end {
+        let following = next(start).min(end);
+        periods.push((
+            label(start),
+            (utils::start_of_day(start), utils::start_of_day(following)),
+        ));
+        start = following;
+    }
+    periods
+}
+
+pub fn days(from: NaiveDate, to: NaiveDate) -> Vec<(String, Span)> {
+    periods(
+        from,
+        to,
+        |date| date + Duration::days(1),
+        |date| date.format("%Y-%m-%d").to_string(),
+    )
+}
+
+pub fn weeks(from: NaiveDate, to: NaiveDate) -> Vec<(String, Span)> {
+    periods(
+        from,
+        to,
+        |date| date + Duration::days(7 - date.weekday().num_days_from_monday() as i64),
+        |date| format!("{}-W{:02}", date.iso_week().year(), date.iso_week().week()),
+    )
+}
+
+pub fn months(from: NaiveDate, to: NaiveDate) -> Vec<(String, Span)> {
+    periods(
+        from,
+        to,
+        |date| match date.month() {
+            12 => NaiveDate::from_ymd_opt(date.year() + 1, 1, 1).unwrap(),
+            month => NaiveDate::from_ymd_opt(date.year(), month + 1, 1).unwrap(),
+        },
+        |date| date.format("%Y-%m").to_string(),
+    )
+}
+
+/// A length of time in the unit that suits it.
+fn length(nanoseconds: u128) -> String {
+    let (value, unit) = match nanoseconds {
+        n if n >= DAY => (n as f64 / DAY as f64, "d"),
+        n if n >= HOUR => (n as f64 / HOUR as f64, "h"),
+        n if n >= MINUTE => (n as f64 / MINUTE as f64, "min"),
+        n => (n as f64 / SECOND as f64, "s"),
+    };
+    format!("{:.1} {}", value, unit)
+}
+
+fn or_dash(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "-".to_string())
+}
+
+/// Writes a table of `periods`, with whether the agreement was met for each if there is one.
+fn write_table(
+    f: &mut impl Write,
+    title: &str,
+    periods: &[Period],
+    sla: Option<&Sla>,
+) -> fmt::Result {
+    writeln!(
+        f,
+        "{:<10} {:>9} {:>10} {:>5} {:>7} {:>4} {:>5} {:>6} {:>4} {:>9} {:>9}{}",
+        title,
+        "Observed",
+        "Available",
+        "Nines",
+        "Outages",
+        SEVERITY_LABELS[0],
+        SEVERITY_LABELS[1],
+        SEVERITY_LABELS[2],
+        SEVERITY_LABELS[3],
+        "MTBF",
+        "MTTR",
+        if sla.is_some() { "  SLA" } else { "" }
+    )?;
+    for period in periods {
+        let availability = period.availability();
+        let verdict = match (sla, availability) {
+            (Some(sla), Some(availability)) => match sla.credit(availability) {
+                Some(credit) => format!("  breached, credit {:.2}", credit),
+                None => "  met".to_string(),
+            },
+            _ => String::new(),
+        };
+        writeln!(
+            f,
+            "{:<10} {:>9} {:>10} {:>5} {:>7} {:>4} {:>5} {:>6} {:>4} {:>9} {:>9}{}",
+            period.label,
+            length(period.observed),
+            or_dash(availability.map(|availability| format!("{:.3}%", availability * 100.0))),
+            or_dash(period.nines().map(|nines| format!("{:.1}", nines))),
+            period.count(),
+            period.outages[0],
+            period.outages[1],
+            period.outages[2],
+            period.outages[3],
+            or_dash(period.mtbf().map(length)),
+            or_dash(period.mttr().map(length)),
+            verdict
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the report from `from` to `to`: monthly figures, judged against the agreement if
+/// there is one, then weekly and daily ones.
+pub fn write_report(
+    f: &mut impl Write,
+    record: &Record,
+    from: NaiveDate,
+    to: NaiveDate,
+    sla: Option<&Sla>,
+) -> fmt::Result {
+    let figures = |spans: Vec<(String, Span)>| -> Vec<Period> {
+        spans
+            .into_iter()
+            .map(|(label, span)| record.period(label, span))
+            .collect()
+    };
+    writeln!(f, "Uptime from {} to {}", from, to)?;
+    if let Some(sla) = sla {
+        writeln!(
+            f,
+            "SLA {}% at {:.2} a month",
+            sla.availability * 100.0,
+            sla.monthly_price
+        )?;
+    }
+    writeln!(f)?;
+    write_table(f, "Month", &figures(months(from, to)), sla)?;
+    writeln!(f)?;
+    write_table(f, "Week", &figures(weeks(from, to)), None)?;
+    writeln!(f)?;
+    write_table(f, "Day", &figures(days(from, to)), None)
+}
+
+/// Writes each target's latency percentiles, in milliseconds, for each month from `from` to
+/// `to`, naming the targets from `names`.
+pub fn write_latency(
+    f: &mut impl Write,
+    latency: &Latency,
+    names: &HashMap<Endpoint, String>,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> fmt::Result {
+    writeln!(f)?;
+    write!(f, "{:<10} {:<36} {:>8}", "Latency", "Target", "Samples")?;
+    for percent in PERCENTILES {
+        write!(f, " {:>8}", format!("p{} ms", percent))?;
+    }
+    writeln!(f)?;
+    for (label, span) in months(from, to) {
+        for (endpoint, histogram) in latency.during(span) {
+            let target = match names.get(&endpoint) {
+                Some(name) => format!("{} ({})", name, endpoint),
+                None => endpoint.to_string(),
+            };
+            write!(f, "{:<10} {:<36} {:>8}", label, target, histogram.count())?;
+            for percent in PERCENTILES {
+                let rtt = histogram.percentile(percent).unwrap_or(0);
+                write!(f, " {:>8.2}", rtt as f64 / 1_000.0)?;
+            }
+            writeln!(f)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outages_are_merged_and_measured_while_running() {
+        // a day watched, less an hour not running, with overlapping IPv4 and IPv6 outages
+        let record = Record::new(
+            [
+                (HOUR, HOUR + 30 * SECOND),
+                (HOUR + 10 * SECOND, HOUR + MINUTE),
+            ],
+            [(10 * HOUR, 11 * HOUR)],
+            (0, DAY),
+        );
+        let period = record.period("day".to_string(), (0, 2 * DAY));
+        assert_eq!(period.observed, 23 * HOUR);
+        assert_eq!(period.down, MINUTE);
+        assert_eq!(period.outages, [0, 1, 0, 0]);
+        assert_eq!(period.mttr(), Some(MINUTE));
+        assert_eq!(period.mtbf(), Some(23 * HOUR - MINUTE));
+        let availability = period.availability().unwrap();
+        assert!((availability - (1.0 - 1.0 / (23.0 * 60.0))).abs() < 1e-12);
+        assert!((period.nines().unwrap() - (23.0f64 * 60.0).log10()).abs() < 1e-9);
+        // an outage is counted where it began, but its downtime is split
+        let before = record.period("before".to_string(), (0, HOUR + 20 * SECOND));
+        assert_eq!((before.count(), before.down), (1, 20 * SECOND));
+        let after = record.period("after".to_string(), (HOUR + 20 * SECOND, DAY));
+        assert_eq!((after.count(), after.down), (0, 40 * SECOND));
+    }
+
+    #[test]
+    fn latency_adds_up_over_periods() {
+        let mut latency = Latency::default();
+        let target: Endpoint = "8.8.8.8".parse().unwrap();
+        for minute in 0..60 {
+            let rtt = if minute < 30 { 10_000 } else { 20_000 };
+            latency.record((target, minute * MINUTE, Ok(rtt)));
+        }
+        latency.record((target, 0, Err(crate::probe::Failure::Timeout)));
+        let hour = &latency.during((0, HOUR))[&target];
+        assert_eq!(hour.count(), 60);
+        assert!(hour.percentile(99.0).unwrap().abs_diff(20_000) <= 200);
+        let first = &latency.during((0, 30 * MINUTE))[&target];
+        assert!(first.percentile(99.0).unwrap().abs_diff(10_000) <= 100);
+        assert!(latency.during((HOUR, 2 * HOUR)).is_empty());
+    }
+
+    #[test]
+    fn credits_follow_the_agreement() {
+        let mut sla = Sla {
+            availability: 0.999,
+            monthly_price: 200.0,
+            credits: Vec::new(),
+        };
+        assert_eq!(sla.credit(0.9995), None);
+        // the price of the downtime beyond the 0.1% allowed
+        assert!((sla.credit(0.989).unwrap() - 2.0).abs() < 1e-9);
+        sla.credits = vec![(0.999, 0.1), (0.99, 0.25), (0.95, 0.5)];
+        assert_eq!(sla.credit(0.998), Some(20.0));
+        assert_eq!(sla.credit(0.98), Some(50.0));
+        assert_eq!(sla.credit(0.5), Some(100.0));
+    }
+
+    #[test]
+    fn periods_are_cut_at_the_ends() {
+        let from = NaiveDate::from_ymd_opt(2024, 1, 30).unwrap();
+        let to = NaiveDate::from_ymd_opt(2024, 2, 6).unwrap();
+        let labels = |periods: Vec<(String, Span)>| -> Vec<String> {
+            periods.into_iter().map(|(label, _span)| label).collect()
+        };
+        assert_eq!(labels(months(from, to)), ["2024-01", "2024-02"]);
+        // the 30th is a Tuesday and the 5th a Monday
+        assert_eq!(labels(weeks(from, to)), ["2024-W05", "2024-W06"]);
+        assert_eq!(days(from, to).len(), 8);
+        let weeks = weeks(from, to);
+        assert_eq!(weeks[0].1 .0, utils::start_of_day(from));
+        assert_eq!(weeks[1].1 .1, utils::start_of_day(to.succ_opt().unwrap()));
+    }
+}
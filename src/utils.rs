@@ -1,31 +1,46 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
-use std::mem;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
 
 use std::ffi::OsString;
 use std::io::Error;
+#[cfg(windows)]
+use std::mem;
 
+#[cfg(windows)]
 use winapi::shared::minwindef::{BOOL, LPARAM, LRESULT, UINT, WPARAM};
+#[cfg(windows)]
 use winapi::shared::windef::HWND;
+#[cfg(windows)]
 #[allow(unused_imports)]
 use winapi::um::winuser::{
     PostMessageW, SendMessageW, SetWindowPos, HWND_TOP, SWP_NOMOVE, SWP_NOOWNERZORDER, SWP_NOSIZE,
 };
 
 pub fn timestamp_to_datetime(timestamp_in_nanoseconds: u128) -> DateTime<Local> {
-    let date_time = NaiveDateTime::from_timestamp(
-        (timestamp_in_nanoseconds / 1_000_000_000) as i64,
-        (timestamp_in_nanoseconds % 1_000_000_000) as u32,
-    );
-    let date_time_local = DateTime::<Local>::from(DateTime::<Utc>::from_utc(date_time, Utc));
-    date_time_local
+    Local.timestamp_nanos(timestamp_in_nanoseconds as i64)
+}
+
+pub fn datetime_to_timestamp<T: TimeZone>(datetime: &DateTime<T>) -> u128 {
+    datetime.timestamp() as u128 * 1_000_000_000 + datetime.timestamp_subsec_nanos() as u128
+}
+
+/// The timestamp of local midnight at the start of `date`.
+pub fn start_of_day(date: NaiveDate) -> u128 {
+    let midnight = Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        // midnight can be skipped by a change to daylight saving time
+        .unwrap_or_else(|| Local.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()));
+    datetime_to_timestamp(&midnight)
 }
 
-pub fn _datetime_to_timestamp<T: TimeZone>(datetime: &DateTime<T>) -> u128 {
-    (datetime.timestamp() * 1_000_000_000 + datetime.timestamp_nanos()) as u128
+/// Round trip times are kept in microseconds but shown and logged in milliseconds.
+pub fn millis(rtt_in_microseconds: u32) -> f64 {
+    rtt_in_microseconds as f64 / 1_000.0
 }
 
+#[cfg(windows)]
 pub fn check_hwnd(handle: &nwg::ControlHandle) -> HWND {
     use winapi::um::winuser::IsWindow;
 
@@ -45,6 +60,7 @@ pub fn check_hwnd(handle: &nwg::ControlHandle) -> HWND {
     }
 }
 
+#[cfg(windows)]
 pub fn PostMessage(
     control_handle: &nwg::ControlHandle,
     Msg: UINT,
@@ -54,6 +70,7 @@ pub fn PostMessage(
     let handle = check_hwnd(control_handle);
     unsafe { PostMessageW(handle, Msg, wParam, lParam) }
 }
+#[cfg(windows)]
 pub fn SendMessage(
     control_handle: &nwg::ControlHandle,
     Msg: UINT,
@@ -64,11 +81,13 @@ pub fn SendMessage(
     unsafe { SendMessageW(handle, Msg, wParam, lParam) }
 }
 
+#[cfg(windows)]
 pub fn _MoveToTop(control_handle: &nwg::ControlHandle) -> BOOL {
     let handle = check_hwnd(control_handle);
     unsafe { SetWindowPos(handle, HWND_TOP, 0, 0, 0, 0, SWP_NOMOVE | SWP_NOSIZE) }
 }
 
+#[cfg(windows)]
 pub fn VScrollToBottom(control_handle: &nwg::ControlHandle) -> BOOL {
     #[allow(unused_imports)]
     use winapi::um::winuser::{
@@ -80,6 +99,7 @@ pub fn VScrollToBottom(control_handle: &nwg::ControlHandle) -> BOOL {
     PostMessage(control_handle, WM_VSCROLL, SB_BOTTOM as WPARAM, 0)
 }
 
+#[cfg(windows)]
 pub fn ScrollToBottom(control_handle: &nwg::ControlHandle) {
     #[allow(unused_imports)]
     use winapi::shared::{
@@ -110,6 +130,7 @@ pub fn ScrollToBottom(control_handle: &nwg::ControlHandle) {
     }
 }
 
+#[cfg(windows)]
 pub fn GetHostName() -> OsString {
     use std::os::windows::ffi::OsStringExt;
     use winapi::ctypes::{c_ulong, wchar_t};
@@ -150,3 +171,18 @@ Please report this issue to <https://github.com/lunaryorn/gethostname.rs/issues>
         .unwrap_or_else(|| buffer.len());
     OsString::from_wide(&buffer[0..end])
 }
+
+#[cfg(unix)]
+pub fn GetHostName() -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut buffer = vec![0u8; 256];
+    let returncode =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if returncode != 0 {
+        panic!("gethostname failed to read hostname: {}", Error::last_os_error());
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    buffer.truncate(end);
+    OsString::from_vec(buffer)
+}